@@ -1,18 +1,18 @@
 use anyhow::{bail, Context, Result};
 use serde_json::{Map, Value};
 
-const BENCODE_END: char = 'e';
+const BENCODE_END: u8 = b'e';
 
-const BENCODE_STRING_SPLIT_CHAR: char = ':';
+const BENCODE_STRING_SPLIT_CHAR: u8 = b':';
 
-const BENCODE_INT_PREFIX: char = 'i';
-const BENCODE_INT_SUFFIX: char = BENCODE_END;
+const BENCODE_INT_PREFIX: u8 = b'i';
+const BENCODE_INT_SUFFIX: u8 = BENCODE_END;
 
-const BENCODE_LIST_PREFIX: char = 'l';
-const BENCODE_LIST_SUFFIX: char = BENCODE_END;
+const BENCODE_LIST_PREFIX: u8 = b'l';
+const BENCODE_LIST_SUFFIX: u8 = BENCODE_END;
 
-const BENCODE_DICT_PREFIX: char = 'd';
-const BENCODE_DICT_SUFFIX: char = BENCODE_END;
+const BENCODE_DICT_PREFIX: u8 = b'd';
+const BENCODE_DICT_SUFFIX: u8 = BENCODE_END;
 
 pub(crate) struct ParsedValue {
     length: usize,
@@ -28,9 +28,9 @@ enum BencodeType {
 }
 
 impl BencodeType {
-    fn new(input: &char) -> BencodeType {
+    fn new(input: &u8) -> BencodeType {
         match input {
-            _ if input.is_numeric() => BencodeType::String,
+            _ if input.is_ascii_digit() => BencodeType::String,
             _ if *input == BENCODE_INT_PREFIX => BencodeType::Number,
             _ if *input == BENCODE_LIST_PREFIX => BencodeType::List,
             _ if *input == BENCODE_DICT_PREFIX => BencodeType::Dictionary,
@@ -39,37 +39,45 @@ impl BencodeType {
     }
 }
 
-pub(crate) fn decode(input: &str) -> Result<ParsedValue> {
+/// Decodes bencoded bytes. Byte strings which are not valid UTF-8 (e.g. `pieces`) are converted
+/// lossily, as JSON has no notion of raw bytes.
+///
+/// `lenient` accepts integers with a leading `+` or surrounding whitespace like `i +5 e`, which
+/// some broken torrent creators write and other clients accept.
+pub(crate) fn decode(input: &[u8], lenient: bool) -> Result<ParsedValue> {
     let bencode_type = BencodeType::new(
-        &input
-            .chars()
-            .next()
+        input
+            .first()
             .ok_or_else(|| anyhow::anyhow!("empty input"))?,
     );
     let res = match bencode_type {
         BencodeType::String => bdecode_string(input)?,
-        BencodeType::Number => bdecode_num(input)?,
-        BencodeType::List => bdecode_list(input)?,
-        BencodeType::Dictionary => bdecode_dict(input)?,
-        BencodeType::Invalid => bail!("dont know how to handle {input}"),
+        BencodeType::Number => bdecode_num(input, lenient)?,
+        BencodeType::List => bdecode_list(input, lenient)?,
+        BencodeType::Dictionary => bdecode_dict(input, lenient)?,
+        BencodeType::Invalid => bail!("dont know how to handle {}", lossy(input)),
     };
 
     Ok(res)
 }
 
-fn bdecode_dict(input: &str) -> Result<ParsedValue> {
+fn lossy(input: &[u8]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(input)
+}
+
+fn bdecode_dict(input: &[u8], lenient: bool) -> Result<ParsedValue> {
     // encoded like d3:foo3:bar5:helloi52ee -> {"hello": 52, "foo":"bar"}
     let mut map = Map::new();
     let mut dict_len = 2;
 
-    let mut char_iter = input.chars().enumerate().peekable();
+    let mut char_iter = input.iter().enumerate().peekable();
     loop {
         let (idx, peeked_char) = match char_iter.peek() {
             Some(x) => x,
             None => bail!("unexpected iterator end, expected peekable char (this means end token was skipped)"),
         };
 
-        match *peeked_char {
+        match **peeked_char {
             BENCODE_DICT_SUFFIX => break,
             BENCODE_DICT_PREFIX => {
                 // Make sure to consume identifying char.
@@ -85,24 +93,34 @@ fn bdecode_dict(input: &str) -> Result<ParsedValue> {
         };
 
         // First parse the key. It must be a string, so fail if it is not.
-        let key =
-            bdecode_string(rest).context("expected string to be present as key in dict {rest}")?;
+        let key = bdecode_string(rest).with_context(|| {
+            format!(
+                "expected string to be present as key in dict {}",
+                lossy(rest)
+            )
+        })?;
 
         // Consume up until step, so next peek is the char identifying the value.
         let step = key.length - 1;
         dict_len += key.length;
         match char_iter.nth(step) {
             Some(x) => x,
-            None => bail!("expected value to follow a key in dict iterator {rest}"),
+            None => bail!(
+                "expected value to follow a key in dict iterator {}",
+                lossy(rest)
+            ),
         };
 
         let (idx, peeked_char) = match char_iter.peek() {
             Some(x) => x,
-            None => bail!("unexpected iterator end, expected value {rest}"),
+            None => bail!("unexpected iterator end, expected value {}", lossy(rest)),
         };
 
-        match *peeked_char {
-            BENCODE_DICT_SUFFIX => bail!("unexpected end token in dict, expected value {rest}"),
+        match **peeked_char {
+            BENCODE_DICT_SUFFIX => bail!(
+                "unexpected end token in dict, expected value {}",
+                lossy(rest)
+            ),
             _ => {}
         }
 
@@ -111,7 +129,7 @@ fn bdecode_dict(input: &str) -> Result<ParsedValue> {
             None => bail!("unexpected end of input"),
         };
 
-        let value = decode(rest)?;
+        let value = decode(rest, lenient)?;
 
         // Interesting interface: Returns Option, None if new, Old value if update.
         // NOTE: Thats kinda shitty. We need to allocate and cast back and forth to prevent string
@@ -139,19 +157,19 @@ fn bdecode_dict(input: &str) -> Result<ParsedValue> {
     })
 }
 
-fn bdecode_list(input: &str) -> Result<ParsedValue> {
+fn bdecode_list(input: &[u8], lenient: bool) -> Result<ParsedValue> {
     // encoded like l5:helloi52ee
     let mut list: Vec<Value> = Vec::new();
     let mut list_len = 2;
 
-    let mut char_iter = input.chars().enumerate().peekable();
+    let mut char_iter = input.iter().enumerate().peekable();
     loop {
         let (idx, peeked_char) = match char_iter.peek() {
             Some(char) => char,
             None => break,
         };
 
-        match *peeked_char {
+        match **peeked_char {
             BENCODE_LIST_SUFFIX => break,
             BENCODE_LIST_PREFIX => {
                 // Make sure to consume identifying char.
@@ -166,7 +184,7 @@ fn bdecode_list(input: &str) -> Result<ParsedValue> {
             None => break,
         };
 
-        let parsed_value = decode(rest)?;
+        let parsed_value = decode(rest, lenient)?;
         list.push(parsed_value.value);
 
         // Consume iter up to step, as that part was already processed.
@@ -184,67 +202,415 @@ fn bdecode_list(input: &str) -> Result<ParsedValue> {
     })
 }
 
-fn bdecode_string(input: &str) -> Result<ParsedValue> {
+fn bdecode_string(input: &[u8]) -> Result<ParsedValue> {
     // encoded like <length:contents>
-    let mut split = input.splitn(2, BENCODE_STRING_SPLIT_CHAR);
+    let (content_start, length) = string_bounds(input)?;
+    let relevant_content = &input[content_start..content_start + length];
+
+    Ok(ParsedValue {
+        length: content_start + length,
+        value: serde_json::to_value(lossy(relevant_content))?,
+    })
+}
+
+/// Offset and length of the contents of the string at the start of `input`, checked to be in
+/// bounds.
+fn string_bounds(input: &[u8]) -> Result<(usize, usize)> {
+    let split_at = input
+        .iter()
+        .position(|b| *b == BENCODE_STRING_SPLIT_CHAR)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "content missing after {}: input: {}",
+                BENCODE_STRING_SPLIT_CHAR as char,
+                lossy(input)
+            )
+        })?;
+
+    let length_string = &input[..split_at];
+    if length_string.is_empty() || !length_string.iter().all(u8::is_ascii_digit) {
+        bail!(
+            "invalid length prefix '{}' in encoded string",
+            lossy(length_string)
+        );
+    }
 
-    let length_string = split.next().ok_or_else(|| {
-        anyhow::anyhow!("length missing before {BENCODE_STRING_SPLIT_CHAR}: input: {input}")
-    })?;
+    let remaining = input.len() - split_at - 1;
 
-    let length = length_string
+    // Fails for prefixes that overflow usize, which can't describe any input we hold anyway.
+    let length: usize = std::str::from_utf8(length_string)?
         .parse()
-        .context(format!("parsing length in encoded string {input}"))?;
+        .with_context(|| {
+            format!(
+                "string length {} exceeds the remaining {} bytes of input",
+                lossy(length_string),
+                remaining
+            )
+        })?;
+    if length > remaining {
+        bail!("string length {length} exceeds the remaining {remaining} bytes of input");
+    }
 
-    let contents = split.next().ok_or_else(|| {
-        anyhow::anyhow!("content missing after {BENCODE_STRING_SPLIT_CHAR}: input: {input}")
-    })?;
+    Ok((split_at + 1, length))
+}
 
-    let relevant_content = match contents.get(0..length) {
-        Some(slice) => slice,
-        None => bail!(
-            "incorrect length encoding! Expected {length} characters, but have these {contents}"
-        ),
-    };
+/// Decodes only the values of `keys` from the dict at the start of `input`. The values of all
+/// other keys are skipped without being decoded, which saves allocations when only a bit of
+/// metadata is needed.
+pub(crate) fn decode_keys(input: &[u8], keys: &[&str], lenient: bool) -> Result<ParsedValue> {
+    let (entries, length) = split_dict(input)?;
 
-    let len = relevant_content.len() + length_string.len() + 1;
+    let mut map = Map::new();
+    for (key, raw) in entries {
+        if let Some(k) = keys.iter().find(|k| k.as_bytes() == key) {
+            map.insert(k.to_string(), decode(raw, lenient)?.value);
+        }
+    }
 
     Ok(ParsedValue {
-        length: len,
-        value: serde_json::to_value(&relevant_content)?,
+        length,
+        value: Value::Object(map),
     })
 }
 
-fn bdecode_num(input: &str) -> Result<ParsedValue> {
+/// Keys of a dict with their encoded values.
+pub(crate) type DictEntries<'a> = Vec<(&'a [u8], &'a [u8])>;
+
+/// Splits the dict at the start of `input` into its keys and their still encoded values, and
+/// returns them with the length of the dict. Values are only checked to be well-formed.
+pub(crate) fn split_dict(input: &[u8]) -> Result<(DictEntries<'_>, usize)> {
+    if input.first() != Some(&BENCODE_DICT_PREFIX) {
+        bail!("expected a dict, got {}", lossy(input));
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = 1;
+    loop {
+        let rest = &input[pos..];
+        match rest.first() {
+            None => bail!("unexpected end of input, expected dict key or end token"),
+            Some(&BENCODE_DICT_SUFFIX) => break,
+            Some(_) => {}
+        }
+
+        let (key_start, key_len) = string_bounds(rest).with_context(|| {
+            format!(
+                "expected string to be present as key in dict {}",
+                lossy(rest)
+            )
+        })?;
+        let key = &rest[key_start..key_start + key_len];
+        pos += key_start + key_len;
+
+        let value_len = skip(&input[pos..])?;
+        entries.push((key, &input[pos..pos + value_len]));
+        pos += value_len;
+    }
+
+    Ok((entries, pos + 1))
+}
+
+/// Encodes a dict from keys and already encoded values. Keys are sorted, as bencode requires.
+pub(crate) fn encode_dict(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|(key, _)| *key);
+
+    let mut out = vec![BENCODE_DICT_PREFIX];
+    for (key, value) in entries {
+        out.extend_from_slice(&encode_string(key));
+        out.extend_from_slice(value);
+    }
+    out.push(BENCODE_DICT_SUFFIX);
+    out
+}
+
+pub(crate) fn encode_string(s: &[u8]) -> Vec<u8> {
+    let mut out = format!("{}{}", s.len(), BENCODE_STRING_SPLIT_CHAR as char).into_bytes();
+    out.extend_from_slice(s);
+    out
+}
+
+/// Fails with the first violation if `input` isn't a single value in canonical form: dict keys
+/// sorted and unique, no leading zeros in integers and string lengths, no `-0`. Only canonical
+/// input re-encodes to the same bytes, which the info hash depends on.
+pub(crate) fn check_canonical(input: &[u8]) -> Result<()> {
+    let length = canonical_value(input, 0)?;
+    if length != input.len() {
+        bail!("unexpected data after the value at offset {}", length);
+    }
+
+    Ok(())
+}
+
+/// Checks the value at `input[pos..]` and returns the offset after it.
+fn canonical_value(input: &[u8], pos: usize) -> Result<usize> {
+    let rest = &input[pos..];
+    let first = rest
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of input, expected value"))?;
+    match BencodeType::new(first) {
+        BencodeType::String => {
+            let (start, length) = string_bounds(rest)?;
+            if !is_minimal_number(&rest[..start - 1]) {
+                bail!("string length with leading zero at offset {}", pos);
+            }
+            Ok(pos + start + length)
+        }
+        BencodeType::Number => {
+            let length = skip(rest)?;
+            let digits = &rest[1..length - 1];
+            let digits = digits.strip_prefix(b"-").unwrap_or(digits);
+            if digits == b"0" && rest[1] == b'-' {
+                bail!("negative zero at offset {}", pos);
+            }
+            if !is_minimal_number(digits) {
+                bail!(
+                    "integer {} at offset {} is not minimal",
+                    lossy(&rest[..length]),
+                    pos
+                );
+            }
+            Ok(pos + length)
+        }
+        BencodeType::List => {
+            let mut pos = pos + 1;
+            while input.get(pos) != Some(&BENCODE_LIST_SUFFIX) {
+                pos = canonical_value(input, pos)?;
+            }
+            Ok(pos + 1)
+        }
+        BencodeType::Dictionary => {
+            let mut pos = pos + 1;
+            let mut previous: Option<&[u8]> = None;
+            while input.get(pos) != Some(&BENCODE_DICT_SUFFIX) {
+                let key_pos = pos;
+                let (start, length) = string_bounds(&input[pos..])
+                    .with_context(|| format!("expected dict key at offset {}", pos))?;
+                pos = canonical_value(input, pos)?;
+                let key = &input[key_pos + start..key_pos + start + length];
+                if previous.is_some_and(|previous| previous >= key) {
+                    bail!(
+                        "dict key {} at offset {} is not sorted",
+                        lossy(key),
+                        key_pos
+                    );
+                }
+                previous = Some(key);
+                pos = canonical_value(input, pos)?;
+            }
+            Ok(pos + 1)
+        }
+        BencodeType::Invalid => bail!("dont know how to handle {}", lossy(rest)),
+    }
+}
+
+/// Dumps the value at the start of `input` like `{:#?}` would, with byte strings written as
+/// `b"..."` and bytes outside of printable ASCII escaped as `\xNN`. Unlike `decode`, nothing is
+/// lost.
+pub(crate) fn debug_dump(input: &[u8], lenient: bool) -> Result<String> {
+    let mut out = String::new();
+    dump_value(input, lenient, 0, &mut out)?;
+    Ok(out)
+}
+
+/// Appends the value at the start of `input` to `out` and returns its length.
+fn dump_value(input: &[u8], lenient: bool, indent: usize, out: &mut String) -> Result<usize> {
+    let first = input
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of input, expected value"))?;
+    match BencodeType::new(first) {
+        BencodeType::String => {
+            let (start, length) = string_bounds(input)?;
+            out.push('b');
+            out.push_str(&escape(&input[start..start + length]));
+            Ok(start + length)
+        }
+        BencodeType::Number => {
+            let num = bdecode_num(input, lenient)?;
+            out.push_str(&num.value.to_string());
+            Ok(num.length)
+        }
+        BencodeType::List | BencodeType::Dictionary => {
+            let is_dict = *first == BENCODE_DICT_PREFIX;
+            let (open, close) = if is_dict { ('{', '}') } else { ('[', ']') };
+            out.push(open);
+            let mut pos = 1;
+            while input.get(pos) != Some(&BENCODE_END) {
+                if pos == 1 {
+                    out.push('\n');
+                }
+                out.push_str(&"    ".repeat(indent + 1));
+                if is_dict {
+                    let (start, length) = string_bounds(&input[pos..])
+                        .with_context(|| format!("expected dict key at offset {}", pos))?;
+                    out.push_str(&escape(&input[pos + start..pos + start + length]));
+                    out.push_str(": ");
+                    pos += start + length;
+                }
+                pos += dump_value(&input[pos..], lenient, indent + 1, out)?;
+                out.push_str(",\n");
+            }
+            if pos > 1 {
+                out.push_str(&"    ".repeat(indent));
+            }
+            out.push(close);
+            Ok(pos + 1)
+        }
+        BencodeType::Invalid => bail!("dont know how to handle {}", lossy(input)),
+    }
+}
+
+/// Checks the integers of the bencoded `input` like `decode` does. With `lenient`, those only it
+/// accepts are rewritten, e.g. `i+5e` becomes `i5e`. Everything else, strict integers like `i03e`
+/// included, is copied byte for byte, so non-canonical input keeps its info hash. `serde_bencode`,
+/// which takes whatever Rust parses as a number, reads the result the same way.
+pub(crate) fn normalize(input: &[u8], lenient: bool) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len());
+    let length = normalize_value(input, lenient, &mut out)?;
+    out.extend_from_slice(&input[length..]);
+    Ok(out)
+}
+
+/// Appends the value at the start of `input` to `out` and returns its length.
+fn normalize_value(input: &[u8], lenient: bool, out: &mut Vec<u8>) -> Result<usize> {
+    let first = input
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of input, expected value"))?;
+    match BencodeType::new(first) {
+        BencodeType::String => {
+            let (start, length) = string_bounds(input)?;
+            out.extend_from_slice(&input[..start + length]);
+            Ok(start + length)
+        }
+        BencodeType::Number => {
+            let strict = bdecode_num(input, false);
+            if let Ok(num) = &strict {
+                out.extend_from_slice(&input[..num.length]);
+                return Ok(num.length);
+            }
+            let num = if lenient {
+                bdecode_num(input, true)?
+            } else {
+                strict?
+            };
+            let digits = match num.value {
+                Value::String(digits) => digits,
+                other => other.to_string(),
+            };
+            out.push(BENCODE_INT_PREFIX);
+            out.extend_from_slice(digits.as_bytes());
+            out.push(BENCODE_INT_SUFFIX);
+            Ok(num.length)
+        }
+        BencodeType::List | BencodeType::Dictionary => {
+            out.push(*first);
+            let mut pos = 1;
+            loop {
+                match input.get(pos) {
+                    None => bail!("unexpected end of input, expected end token"),
+                    Some(&BENCODE_END) => {
+                        out.push(BENCODE_END);
+                        return Ok(pos + 1);
+                    }
+                    Some(_) => pos += normalize_value(&input[pos..], lenient, out)?,
+                }
+            }
+        }
+        BencodeType::Invalid => bail!("dont know how to handle {}", lossy(input)),
+    }
+}
+
+/// Quotes `bytes`, escaping them like a Rust byte string literal.
+fn escape(bytes: &[u8]) -> String {
+    let escaped: String = bytes
+        .iter()
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect();
+    format!("\"{}\"", escaped)
+}
+
+fn is_minimal_number(digits: &[u8]) -> bool {
+    !digits.is_empty()
+        && digits.iter().all(u8::is_ascii_digit)
+        && (digits == b"0" || digits[0] != b'0')
+}
+
+/// Length of the value at the start of `input`, without decoding it.
+fn skip(input: &[u8]) -> Result<usize> {
+    let first = input
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of input, expected value"))?;
+    match BencodeType::new(first) {
+        BencodeType::String => {
+            let (start, length) = string_bounds(input)?;
+            Ok(start + length)
+        }
+        BencodeType::Number => input
+            .iter()
+            .position(|b| *b == BENCODE_INT_SUFFIX)
+            .map(|end| end + 1)
+            .ok_or_else(|| anyhow::anyhow!("number without end token {}", lossy(input))),
+        BencodeType::List | BencodeType::Dictionary => {
+            let mut pos = 1;
+            loop {
+                match input.get(pos) {
+                    None => bail!("unexpected end of input, expected end token"),
+                    Some(&BENCODE_END) => return Ok(pos + 1),
+                    Some(_) => pos += skip(&input[pos..])?,
+                }
+            }
+        }
+        BencodeType::Invalid => bail!("dont know how to handle {}", lossy(input)),
+    }
+}
+
+fn bdecode_num(input: &[u8], lenient: bool) -> Result<ParsedValue> {
     // endcoded like i<number>e. Number can be negative.
 
-    let mut input_chars = input.chars();
+    let mut input_chars = input.iter().map(|b| *b as char);
     let mut num_string = String::new();
     let mut signed_seen = false;
+    // Whitespace after the digits, only allowed to be followed by more of it.
+    let mut trailing = false;
+    let mut len = 0;
 
     while let Some(ch) = input_chars.next() {
-        match ch {
+        len += 1;
+        match ch as u8 {
             BENCODE_INT_PREFIX => continue,
             BENCODE_INT_SUFFIX => break,
-            _ if ch.is_numeric() || (ch == '-' && !signed_seen) => {
+            _ if !trailing && (ch.is_ascii_digit() || (ch == '-' && !signed_seen)) => {
                 num_string.push(ch);
                 signed_seen = true;
                 continue;
             }
-            _ => bail!("unexpected char '{ch}' in number input '{input}'"),
+            _ if lenient && ch == '+' && !signed_seen => {
+                signed_seen = true;
+                continue;
+            }
+            _ if lenient && ch.is_ascii_whitespace() => {
+                trailing = !num_string.is_empty();
+                continue;
+            }
+            _ => bail!("unexpected char '{ch}' in number input '{}'", lossy(input)),
         }
     }
 
-    let num: i64 = num_string
-        .parse()
-        .context(format!("cannot parse {num_string} as i64"))?;
-
-    let len = num_string.len() + 2; // + prefix and suffix
+    // Bencode integers have no size limit. Beyond u64 the digits are kept as a string, which
+    // is lossless and good enough for display.
+    let digits = num_string.strip_prefix('-').unwrap_or(&num_string);
+    let value = if let Ok(num) = num_string.parse::<i64>() {
+        Value::from(num)
+    } else if let Ok(num) = num_string.parse::<u64>() {
+        Value::from(num)
+    } else if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        Value::String(num_string)
+    } else {
+        bail!("cannot parse '{num_string}' as a number");
+    };
 
-    Ok(ParsedValue {
-        length: len,
-        value: serde_json::to_value(&num)?,
-    })
+    Ok(ParsedValue { length: len, value })
 }
 
 #[cfg(test)]
@@ -282,7 +648,7 @@ mod tests {
         ];
 
         for test_case in test_cases {
-            let decoded = bdecode_list(&test_case.input)?;
+            let decoded = bdecode_list(test_case.input.as_bytes(), false)?;
             assert_eq!(test_case.expected, decoded.value);
         }
 
@@ -316,10 +682,178 @@ mod tests {
         ];
 
         for test_case in test_cases {
-            let decoded = bdecode_dict(&test_case.input)?;
+            let decoded = bdecode_dict(test_case.input.as_bytes(), false)?;
             assert_eq!(test_case.expected, decoded.value);
         }
 
         Ok(())
     }
+
+    #[test]
+    fn test_bdecode_string_length_out_of_bounds() {
+        for input in ["99999999999999999999:x", "5:abc", "l10:abce"] {
+            let Err(err) = decode(input.as_bytes(), false) else {
+                panic!("expected an error for {input}");
+            };
+            assert!(
+                format!("{:#}", err).contains("exceeds the remaining"),
+                "unexpected error for {input}: {err:#}"
+            );
+        }
+
+        assert!(decode(b"-1:x", false).is_err());
+    }
+
+    #[test]
+    fn test_lenient_numbers() -> Result<(), Box<dyn std::error::Error>> {
+        for input in ["i+5e", "i 5 e", "i+5 e"] {
+            let Err(err) = decode(input.as_bytes(), false) else {
+                panic!("expected {input} to be rejected");
+            };
+            assert!(format!("{:#}", err).contains("unexpected char"), "{err:#}");
+
+            let decoded = decode(input.as_bytes(), true)?;
+            assert_eq!(decoded.value, serde_json::json!(5));
+            assert_eq!(decoded.length, input.len());
+        }
+
+        let decoded = decode(b"d6:lengthi+92063e4:name1:ae", true)?;
+        assert_eq!(
+            decoded.value,
+            serde_json::json!({"length": 92063, "name": "a"})
+        );
+        // Still a single number with at most one sign.
+        for input in ["i5 5e", "i+-5e", "i++5e"] {
+            assert!(decode(input.as_bytes(), true).is_err(), "{input}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() -> Result<(), Box<dyn std::error::Error>> {
+        let input = b"d6:lengthi+92063e4:name5:i+5e 6:piecesl2:\xff\x00i -7 eee";
+        assert_eq!(
+            normalize(input, true)?,
+            b"d6:lengthi92063e4:name5:i+5e 6:piecesl2:\xff\x00i-7eee"
+        );
+        assert!(normalize(input, false).is_err());
+        // Already strict input is left alone, even integers Rust reads the same way shorter.
+        assert_eq!(normalize(b"li5ei-5e0:e", false)?, b"li5ei-5e0:e");
+        for lenient in [false, true] {
+            assert_eq!(normalize(b"li03ei-0ee", lenient)?, b"li03ei-0ee");
+        }
+        assert!(normalize(b"i5 5e", true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_numbers() -> Result<(), Box<dyn std::error::Error>> {
+        let decoded = decode(b"i5000000000e", false)?;
+        assert_eq!(decoded.value, serde_json::json!(5_000_000_000u64));
+        let decoded = decode(b"i18446744073709551615e", false)?;
+        assert_eq!(decoded.value, serde_json::json!(u64::MAX));
+        let decoded = decode(b"i-9223372036854775808e", false)?;
+        assert_eq!(decoded.value, serde_json::json!(i64::MIN));
+
+        for input in ["i18446744073709551616e", "i-9223372036854775809e"] {
+            let decoded = decode(input.as_bytes(), false)?;
+            assert_eq!(decoded.value, serde_json::json!(input[1..input.len() - 1]));
+            assert_eq!(decoded.length, input.len());
+        }
+        assert!(decode(b"ie", false).is_err());
+        assert!(decode(b"i-e", false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_dump_keeps_binary() -> Result<(), Box<dyn std::error::Error>> {
+        let input = b"d4:datal3:\x00\xffae5:emptyde3:numi-3e6:pieces2:\xff\x9fe";
+
+        // JSON can only hold text, so the binary bytes get replaced.
+        let json = decode(input, false)?.value;
+        assert_eq!(json["pieces"], "\u{fffd}\u{fffd}");
+
+        assert_eq!(
+            debug_dump(input, false)?,
+            r#"{
+    "data": [
+        b"\x00\xffa",
+    ],
+    "empty": {},
+    "num": -3,
+    "pieces": b"\xff\x9f",
+}"#
+        );
+        assert_eq!(debug_dump(b"le", false)?, "[]");
+        assert!(debug_dump(b"l1:a", false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let input = b"d8:announce55:http://bittorrent-test-tracker.codecrafters.io/announce10:created by13:mktorrent 1.14:infod6:lengthi92063e4:name10:sample.txt12:piece lengthi32768e6:pieces1:a5:filesld6:lengthi1e4:pathl1:aeeeee";
+
+        let decoded = decode_keys(input, &["announce"], false)?;
+        assert_eq!(decoded.length, input.len());
+        assert_eq!(
+            decoded.value,
+            serde_json::json!({"announce": "http://bittorrent-test-tracker.codecrafters.io/announce"})
+        );
+
+        let decoded = decode_keys(input, &["info", "missing"], false)?;
+        assert_eq!(decoded.value["info"]["length"], 92063);
+        assert!(decoded.value.get("announce").is_none());
+
+        // Skipped values are still checked to be well-formed.
+        assert!(decode_keys(b"d3:fooi1e3:bar99:xe", &["foo"], false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_canonical() -> Result<(), Box<dyn std::error::Error>> {
+        check_canonical(&std::fs::read("sample.torrent")?)?;
+        check_canonical(b"d1:ai0e1:bli-3e0:e1:cd1:xi10eee")?;
+
+        for (input, violation) in [
+            (&b"d1:bi1e1:ai2ee"[..], "not sorted"),
+            (b"d1:ai1e1:ai2ee", "not sorted"),
+            (b"li01ee", "not minimal"),
+            (b"li-0ee", "negative zero"),
+            (b"ie", "not minimal"),
+            (b"03:abc", "leading zero"),
+            (b"i1ei2e", "unexpected data"),
+            (b"l", "unexpected end"),
+            (b"d1:a", "unexpected end"),
+        ] {
+            let err = check_canonical(input).expect_err(&lossy(input));
+            assert!(
+                err.to_string().contains(violation),
+                "{}: {}",
+                lossy(input),
+                err
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_binary_torrent() -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read("sample.torrent")?;
+        let decoded = decode(&content, false)?;
+
+        assert_eq!(content.len(), decoded.length);
+        assert_eq!(
+            "http://bittorrent-test-tracker.codecrafters.io/announce",
+            decoded.value["announce"]
+        );
+        assert_eq!(92063, decoded.value["info"]["length"]);
+
+        Ok(())
+    }
 }
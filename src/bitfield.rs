@@ -0,0 +1,154 @@
+//! Which pieces of a torrent are present, laid out as in the peer protocol's Bitfield message.
+
+use anyhow::{bail, Result};
+
+/// One bit per piece, the high bit of the first byte is piece 0. Spare bits in the last byte
+/// are always zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// A bitfield of `len` pieces, none of them set.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Parses the payload of a Bitfield message for a torrent of `len` pieces. Fails on a wrong
+    /// size or set spare bits, peers sending those must be dropped.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            bail!(
+                "bitfield of {} pieces must be {} bytes, got {}",
+                len,
+                len.div_ceil(8),
+                bytes.len()
+            );
+        }
+        let spare = bytes.len() * 8 - len;
+        if let Some(last) = bytes.last() {
+            if spare > 0 && last & ((1 << spare) - 1) != 0 {
+                bail!("bitfield has spare bits set");
+            }
+        }
+
+        Ok(Self {
+            bytes: bytes.to_vec(),
+            len,
+        })
+    }
+
+    /// The payload of a Bitfield message, the inverse of `from_bytes`. Only mock peers send one
+    /// so far, seeding will need it.
+    #[allow(dead_code)]
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Number of pieces.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Panics if `idx` is out of range, like indexing a slice.
+    pub fn set(&mut self, idx: usize) {
+        assert!(idx < self.len, "piece {} out of range {}", idx, self.len);
+        self.bytes[idx / 8] |= 0x80 >> (idx % 8);
+    }
+
+    /// False for indices out of range.
+    pub fn has(&self, idx: usize) -> bool {
+        idx < self.len && self.bytes[idx / 8] & (0x80 >> (idx % 8)) != 0
+    }
+
+    /// Number of set pieces.
+    pub fn count(&self) -> usize {
+        self.ones().count()
+    }
+
+    /// Indices of the set pieces, ascending.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&idx| self.has(idx))
+    }
+
+    /// Indices of the pieces not set, ascending.
+    pub fn zeros(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&idx| !self.has(idx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_has() {
+        let mut bf = Bitfield::new(10);
+        assert_eq!(bf.count(), 0);
+        bf.set(0);
+        bf.set(9);
+        bf.set(9);
+        assert_eq!(
+            bf,
+            Bitfield::from_bytes(&[0b1000_0000, 0b0100_0000], 10).unwrap()
+        );
+        assert!(bf.has(0) && bf.has(9));
+        assert!(!bf.has(1));
+        assert!(!bf.has(10));
+        assert_eq!(bf.count(), 2);
+        assert_eq!(bf.ones().collect::<Vec<_>>(), vec![0, 9]);
+        assert_eq!(bf.zeros().collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_set_out_of_range() {
+        Bitfield::new(10).set(10);
+    }
+
+    #[test]
+    fn test_from_bytes() -> Result<()> {
+        let bf = Bitfield::from_bytes(&[0xff, 0b1100_0000], 10)?;
+        assert_eq!(bf.count(), 10);
+        assert_eq!(bf.zeros().count(), 0);
+        assert_eq!(bf.ones().collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+
+        // Whole bytes have no spare bits.
+        let bf = Bitfield::from_bytes(&[0xff], 8)?;
+        assert_eq!(bf.count(), 8);
+        assert_eq!(Bitfield::from_bytes(&[], 0)?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_bytes_round_trip() -> Result<()> {
+        // 13 pieces leave 3 spare bits in the last byte.
+        let mut bf = Bitfield::new(13);
+        assert_eq!(bf.to_bytes(), &[0, 0]);
+        for idx in [0, 5, 8, 12] {
+            bf.set(idx);
+        }
+        assert_eq!(bf.to_bytes(), &[0b1000_0100, 0b1000_1000]);
+        assert_eq!(Bitfield::from_bytes(bf.to_bytes(), 13)?, bf);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_invalid() {
+        // Piece 10 doesn't exist.
+        let err = Bitfield::from_bytes(&[0xff, 0b1110_0000], 10).unwrap_err();
+        assert!(err.to_string().contains("spare bits"), "{}", err);
+        let err = Bitfield::from_bytes(&[0xff, 0b0000_0001], 9).unwrap_err();
+        assert!(err.to_string().contains("spare bits"), "{}", err);
+
+        assert!(Bitfield::from_bytes(&[0xff], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff, 0, 0], 10).is_err());
+    }
+}
@@ -1,116 +1,600 @@
 use std::fs;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::path::PathBuf;
+use std::time::Duration;
 
-use anyhow::{anyhow, Result};
-use bencode::decode;
+use anyhow::{bail, Result};
+use bencode::{decode, decode_keys};
 use clap::Parser;
 use torrent::TorrentFile;
 
+use self::progress::Progress;
+use self::stats::TransferTotals;
 use self::torrent::Torrent;
 
 mod bencode;
+mod bitfield;
+mod cancel;
 mod peers;
+mod progress;
+mod scheduler;
+mod session;
+mod stats;
+#[cfg(test)]
+mod test_util;
 mod torrent;
 mod tracker;
+mod verify;
+mod webseed;
+
+const PEER_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const PEER_CHECK_CONCURRENCY: usize = 20;
+// Cells of the `--show-map` piece map.
+const PIECE_MAP_WIDTH: usize = 64;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// IP version used to reach the tracker: any, ipv4 or ipv6.
+    #[arg(long, global = true, default_value = "any", value_parser = clap::value_parser!(peers::AddressFamily))]
+    address_family: peers::AddressFamily,
+    /// User-Agent sent to the tracker, for trackers that only accept certain clients.
+    #[arg(long, global = true)]
+    user_agent: Option<String>,
+    /// Extra announce parameter as key=value, e.g. required by a private tracker. Repeatable.
+    #[arg(long = "announce-param", global = true, value_parser = peers::parse_query_param)]
+    announce_params: Vec<(String, String)>,
+    /// Drop a partial peer at the end of a tracker's compact peer list with a warning, instead
+    /// of rejecting the response.
+    #[arg(long, global = true)]
+    lenient_peers: bool,
+    /// Accept bencode integers with a leading `+` or whitespace, as written by some broken
+    /// tools, in torrent files and the decode input.
+    #[arg(long, global = true)]
+    lenient_bencode: bool,
+    /// Only log errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log more, repeat for even more output (-v info, -vv debug, -vvv trace).
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+impl Cli {
+    fn client_options(&self, dump_tracker_response: &Option<PathBuf>) -> peers::ClientOptions {
+        peers::ClientOptions {
+            address_family: self.address_family,
+            dump_response: dump_tracker_response.clone(),
+            user_agent: self.user_agent.clone(),
+            extra_params: self.announce_params.iter().cloned().collect(),
+            lenient_peers: self.lenient_peers,
+        }
+    }
+
+    fn log_level(&self) -> log::LevelFilter {
+        if self.quiet {
+            return log::LevelFilter::Error;
+        }
+        match self.verbose {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            2 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum DecodeFormat {
+    Json,
+    PrettyJson,
+    Debug,
 }
 
 #[derive(Parser)]
 enum Commands {
     Decode {
-        input: String,
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        input: Option<String>,
+        /// Read the bencoded input from this file instead.
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Only decode this key of the top-level dict, skipping everything else. Can be repeated.
+        #[arg(long = "key")]
+        keys: Vec<String>,
+        /// Output format. `debug` keeps binary strings like `pieces`, escaping their bytes.
+        #[arg(long, value_enum, default_value_t = DecodeFormat::Json)]
+        format: DecodeFormat,
     },
     Info {
+        /// Path or http(s) URL of the .torrent file.
+        torrent_path: PathBuf,
+    },
+    /// Write a torrent's info dict exactly as encoded in the file. Its SHA1 is the info hash only
+    /// if the dict is canonical bencode, see lint.
+    ExtractInfo {
+        torrent_path: PathBuf,
+        #[arg(short, long, required = true)]
+        output_path: PathBuf,
+    },
+    /// Check a torrent file for structural problems, e.g. piece hashes not matching its length.
+    Lint { torrent_path: PathBuf },
+    /// Create a torrent of a file or directory.
+    Create {
+        /// The file or directory to share.
+        source: PathBuf,
+        #[arg(short, long, required = true)]
+        output_path: PathBuf,
+        /// Announce URL of the tracker.
+        #[arg(long, required = true)]
+        tracker: url::Url,
+        /// Bytes per piece, a power of two. Chosen by the content's size if not given.
+        #[arg(long)]
+        piece_length: Option<u32>,
+    },
+    /// Print the SHA1 of every piece of a file as a torrent would hash it, one per line.
+    HashFile {
+        file_path: PathBuf,
+        /// Bytes per piece, the torrent's `piece length`.
+        piece_length: usize,
+    },
+    /// Point a torrent at another tracker, keeping its info hash.
+    Edit {
+        torrent_path: PathBuf,
+        #[arg(short, long, required = true)]
+        output_path: PathBuf,
+        /// New announce URL.
+        #[arg(long, required = true)]
+        tracker: url::Url,
+        /// Drop the torrent's announce-list, so only the new tracker is used.
+        #[arg(long)]
+        clear_announce_list: bool,
+    },
+    /// Check downloaded data against the torrent's piece hashes.
+    Verify {
+        /// Path or http(s) URL of the .torrent file.
         torrent_path: PathBuf,
+        /// The downloaded file, or the directory a multi-file torrent was downloaded to.
+        #[arg(short, long, required = true)]
+        output_path: PathBuf,
+        /// Pieces hashed in parallel, defaults to the number of CPUs. With 1 the data is read
+        /// once front to back, best for huge torrents on spinning disks.
+        #[arg(long)]
+        threads: Option<usize>,
+    },
+    /// Print how much of an interrupted download is present and verified.
+    Completeness {
+        /// Path or http(s) URL of the .torrent file.
+        torrent_path: PathBuf,
+        /// The downloaded file, or the directory a multi-file torrent was downloaded to.
+        #[arg(short, long, required = true)]
+        output_path: PathBuf,
+    },
+    /// Print a summary line for every .torrent file in a directory.
+    BatchInfo {
+        dir: PathBuf,
+        /// Only list torrents with this info hash, can be given multiple times.
+        #[arg(long, value_parser = torrent::InfoHash::from_hex)]
+        info_hash: Vec<torrent::InfoHash>,
     },
     Peers {
+        /// Path or http(s) URL of the .torrent file.
+        torrent_path: PathBuf,
+        /// Print the peers as a JSON array instead of one per line.
+        #[arg(long)]
+        json: bool,
+        /// Handshake with every peer and report whether it is reachable.
+        #[arg(long, conflicts_with = "json")]
+        check: bool,
+        /// Peers checked at once, lower it if checking a large swarm overwhelms the machine.
+        #[arg(
+            long,
+            default_value_t = PEER_CHECK_CONCURRENCY,
+            requires = "check",
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        check_concurrency: usize,
+        /// Seconds a peer has to answer the handshake before it counts as timed out.
+        #[arg(
+            long,
+            default_value_t = PEER_CHECK_TIMEOUT.as_secs(),
+            requires = "check",
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        check_timeout: u64,
+        /// Write the raw tracker response to this file, even if it can't be parsed.
+        #[arg(long)]
+        dump_tracker_response: Option<PathBuf>,
+        /// Announce to this tracker instead of the torrent's own ones.
+        #[arg(long)]
+        tracker: Option<url::Url>,
+    },
+    /// Time connecting and handshaking with every peer of the swarm, fastest first.
+    Probe {
+        /// Path or http(s) URL of the .torrent file.
         torrent_path: PathBuf,
+        /// Announce to this tracker instead of the torrent's own ones.
+        #[arg(long)]
+        tracker: Option<url::Url>,
     },
     Handshake {
+        /// Path or http(s) URL of the .torrent file.
         torrent_path: PathBuf,
         #[arg(value_parser = clap::value_parser!(peers::Peer))]
         peer: peers::Peer,
     },
+    /// Handshake like `handshake`, but with only the info hash of the torrent at hand.
+    HandshakeHash {
+        /// Hex encoded info hash.
+        #[arg(long, required = true, value_parser = torrent::InfoHash::from_hex)]
+        info_hash: torrent::InfoHash,
+        #[arg(value_parser = clap::value_parser!(peers::Peer))]
+        peer: peers::Peer,
+    },
     #[command(alias = "download_piece")]
     DownloadPiece {
         #[arg(short, long, required = true)]
         output_path: PathBuf,
+        /// Path or http(s) URL of the .torrent file.
         #[arg(required = true)]
         torrent_path: PathBuf,
         #[arg(required = true)]
         piece_index: usize,
+        /// Write the raw tracker response to this file, even if it can't be parsed.
+        #[arg(long)]
+        dump_tracker_response: Option<PathBuf>,
+        /// Announce to this tracker instead of the torrent's own ones.
+        #[arg(long)]
+        tracker: Option<url::Url>,
+        /// Skip the piece hash check. Only for benchmarking downloads from trusted peers.
+        #[arg(long)]
+        no_verify: bool,
     },
     #[command(alias = "download")]
     DownloadFile {
+        /// `-` streams a single-file torrent to stdout, in order. Such a download can't be
+        /// resumed, as stdout can't be read back.
         #[arg(short, long, required = true)]
         output_path: PathBuf,
+        /// Path or http(s) URL of the .torrent file.
         #[arg(required = true)]
         torrent_path: PathBuf,
+        /// Only download the file with this index of a multi-file torrent. Can be repeated.
+        #[arg(long = "file")]
+        files: Vec<usize>,
+        /// Only download these files of a multi-file torrent, given as comma separated indices
+        /// and glob patterns, e.g. `0,2,5` or `*.mkv`. The file list is printed first.
+        #[arg(long = "files", conflicts_with = "files")]
+        file_spec: Option<String>,
+        /// Block requests kept outstanding per peer.
+        #[arg(long, default_value_t = tracker::DEFAULT_PIPELINE_DEPTH)]
+        pipeline_depth: usize,
+        /// Write the raw tracker response to this file, even if it can't be parsed.
+        #[arg(long)]
+        dump_tracker_response: Option<PathBuf>,
+        /// Announce to this tracker instead of the torrent's own ones.
+        #[arg(long)]
+        tracker: Option<url::Url>,
+        /// Skip the piece hash checks. Only for benchmarking downloads from trusted peers.
+        #[arg(long)]
+        no_verify: bool,
+        /// Keep the uploaded and downloaded totals reported to the tracker in this file, so they
+        /// add up across runs.
+        #[arg(long)]
+        stats_file: Option<PathBuf>,
+        /// Remember the peers that delivered verified pieces in this file, also when the download
+        /// fails. They are tried right away on the next run instead of waiting for the tracker.
+        #[arg(long)]
+        peer_cache: Option<PathBuf>,
+        /// Refuse torrents larger than this many bytes, checked before connecting to anyone.
+        #[arg(long)]
+        max_file_size: Option<u64>,
+        /// Draw a map of the downloaded pieces on stderr, if it is a terminal.
+        #[arg(long)]
+        show_map: bool,
+        /// Bytes of pieces collected in memory before writing them, so neighbouring pieces are
+        /// written in one go. 0 writes every piece right away.
+        #[arg(long, default_value_t = 0)]
+        write_buffer_size: usize,
+        /// Count the bytes and messages of every peer connection, logged with the peer stats.
+        #[arg(long)]
+        connection_metrics: bool,
+        /// Fail once pieces had to be requested again this many times, e.g. because no peer
+        /// serves them. Retries as long as there are peers if not given.
+        #[arg(long)]
+        max_retries: Option<usize>,
+        /// Pieces downloaded at once, each holding a buffer of the piece length. Bounds memory
+        /// regardless of the peer count.
+        #[arg(long)]
+        max_active_pieces: Option<usize>,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    env_logger::init();
+    // RUST_LOG, if set, still takes precedence over the flags.
+    env_logger::Builder::new()
+        .filter_level(cli.log_level())
+        .parse_default_env()
+        .init();
 
     match &cli.command {
-        Some(Commands::Decode { input }) => {
-            let parsed_value = decode(input)?;
-            println!("{}", parsed_value.value)
+        Some(Commands::Decode {
+            input,
+            file,
+            keys,
+            format,
+        }) => {
+            let content = match (input, file) {
+                (_, Some(file)) => fs::read(file)?,
+                (Some(input), None) => input.as_bytes().to_vec(),
+                (None, None) => bail!("either an input or --file is required"),
+            };
+            if let DecodeFormat::Debug = format {
+                let content = if keys.is_empty() {
+                    content
+                } else {
+                    let (entries, _) = bencode::split_dict(&content)?;
+                    let selected: Vec<_> = entries
+                        .into_iter()
+                        .filter(|(key, _)| keys.iter().any(|k| k.as_bytes() == *key))
+                        .collect();
+                    bencode::encode_dict(&selected)
+                };
+                println!("{}", bencode::debug_dump(&content, cli.lenient_bencode)?);
+                return Ok(());
+            }
+
+            let parsed_value = if keys.is_empty() {
+                decode(&content, cli.lenient_bencode)?
+            } else {
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                decode_keys(&content, &keys, cli.lenient_bencode)?
+            };
+            match format {
+                DecodeFormat::PrettyJson => {
+                    println!("{}", serde_json::to_string_pretty(&parsed_value.value)?)
+                }
+                _ => println!("{}", parsed_value.value),
+            }
         }
         Some(Commands::Info { torrent_path }) => {
-            let torrent_file = TorrentFile::parse_from_file(torrent_path)?;
+            let torrent_file = TorrentFile::load(torrent_path, cli.lenient_bencode).await?;
             let torrent = Torrent::from_file_torrent(&torrent_file)?;
             println!("{}", torrent)
         }
-        Some(Commands::Peers { torrent_path }) => {
-            let torrent_file = TorrentFile::parse_from_file(torrent_path)?;
+        Some(Commands::ExtractInfo {
+            torrent_path,
+            output_path,
+        }) => {
+            let content = fs::read(torrent_path)?;
+            fs::write(output_path, torrent::raw_info(&content)?)?;
+        }
+        Some(Commands::Lint { torrent_path }) => {
+            let content = fs::read(torrent_path)?;
+            let checks = torrent::lint(&content);
+            let mut failed = 0;
+            for check in &checks {
+                match &check.result {
+                    Ok(()) => println!("ok\t{}", check.name),
+                    Err(e) => {
+                        failed += 1;
+                        println!("FAIL\t{}: {:#}", check.name, e);
+                    }
+                }
+            }
+            if failed > 0 {
+                bail!("{} of {} checks failed", failed, checks.len());
+            }
+        }
+        Some(Commands::Create {
+            source,
+            output_path,
+            tracker,
+            piece_length,
+        }) => {
+            let piece_length = match piece_length {
+                Some(piece_length) => *piece_length,
+                None => {
+                    let piece_length = torrent::auto_piece_length(content_size(source)?);
+                    eprintln!("Piece length: {}", piece_length);
+                    piece_length
+                }
+            };
+            let torrent_file = TorrentFile::create(source, tracker, piece_length)?;
+            fs::write(output_path, torrent_file.to_bytes()?)?;
+            let torrent = Torrent::from_file_torrent(&torrent_file)?;
+            println!("Info Hash: {}", torrent.info_hash().to_hex());
+        }
+        Some(Commands::HashFile {
+            file_path,
+            piece_length,
+        }) => {
+            for hash in torrent::hash_pieces(std::slice::from_ref(file_path), *piece_length)? {
+                println!("{}", hash.to_hex());
+            }
+        }
+        Some(Commands::Edit {
+            torrent_path,
+            output_path,
+            tracker,
+            clear_announce_list,
+        }) => {
+            let content = fs::read(torrent_path)?;
+            let edited = torrent::replace_tracker(&content, tracker, *clear_announce_list)?;
+            fs::write(output_path, edited)?;
+        }
+        Some(Commands::Verify {
+            torrent_path,
+            output_path,
+            threads,
+        }) => {
+            let torrent_file = TorrentFile::load(torrent_path, cli.lenient_bencode).await?;
             let torrent = Torrent::from_file_torrent(&torrent_file)?;
+            let download_req = torrent.to_download_request()?;
+            let threads = threads
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+            let total = download_req.pieces.len();
+            let (progress_tx, printer) = if std::io::stderr().is_terminal() {
+                let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+                let printer = tokio::spawn(async move {
+                    while let Some(checked) = progress_rx.recv().await {
+                        eprint!("\r{}/{} pieces checked", checked, total);
+                    }
+                    eprintln!();
+                });
+                (Some(progress_tx), Some(printer))
+            } else {
+                (None, None)
+            };
+
+            let matched = verify::verify(&download_req, output_path, threads, progress_tx).await?;
+            if let Some(printer) = printer {
+                printer.await?;
+            }
+            println!("{}/{} pieces ok", matched.count(), total);
+            if matched.count() < total {
+                for idx in matched.zeros() {
+                    println!("piece {} does not match", idx);
+                }
+                bail!("{} pieces do not match", total - matched.count());
+            }
+        }
+        Some(Commands::Completeness {
+            torrent_path,
+            output_path,
+        }) => {
+            let torrent_file = TorrentFile::load(torrent_path, cli.lenient_bencode).await?;
+            let torrent = Torrent::from_file_torrent(&torrent_file)?;
+            let report = verify::completeness(&torrent.to_download_request()?, output_path).await?;
+            println!("{}", report);
+        }
+        Some(Commands::BatchInfo { dir, info_hash }) => {
+            batch_info(dir, info_hash, cli.lenient_bencode)?
+        }
+        Some(Commands::Peers {
+            torrent_path,
+            json,
+            check,
+            check_concurrency,
+            check_timeout,
+            dump_tracker_response,
+            tracker,
+        }) => {
+            let torrent_file = TorrentFile::load(torrent_path, cli.lenient_bencode).await?;
+            let mut torrent = Torrent::from_file_torrent(&torrent_file)?;
+            if let Some(tracker) = tracker {
+                torrent = torrent.with_tracker(tracker.clone());
+            }
+            let id = peers::PeerID::new();
+            let client = peers::Client::new(id.clone(), cli.client_options(dump_tracker_response))?;
+            let response = client.find_peers(torrent.to_peer_request()).await?;
+            // On stderr, so the peer list stays parseable.
+            if let Some(warning) = &response.warning_message {
+                eprintln!("Tracker warning: {}", warning);
+            }
+            let peers = client.peers(response)?;
+            if let (Some(complete), Some(incomplete)) = (peers.complete(), peers.incomplete()) {
+                eprintln!("Swarm: {} seeders, {} leechers", complete, incomplete);
+            }
+            if *check {
+                let mut checks = tracker::check_peers(
+                    id,
+                    &peers,
+                    torrent.to_peer_request().info_hash,
+                    Duration::from_secs(*check_timeout),
+                    *check_concurrency,
+                );
+                while let Some((peer, check)) = checks.recv().await {
+                    println!("{}\t{}", peer, check)
+                }
+            } else if *json {
+                println!("{}", peers.to_json()?)
+            } else {
+                println!("{}", peers)
+            }
+        }
+        Some(Commands::Probe {
+            torrent_path,
+            tracker,
+        }) => {
+            let torrent_file = TorrentFile::load(torrent_path, cli.lenient_bencode).await?;
+            let mut torrent = Torrent::from_file_torrent(&torrent_file)?;
+            if let Some(tracker) = tracker {
+                torrent = torrent.with_tracker(tracker.clone());
+            }
             let id = peers::PeerID::new();
-            let client = peers::Client::new(id)?;
-            let peers = client.find_peers(torrent.to_peer_request()).await?;
-            println!("{}", peers)
+            let client = peers::Client::new(id.clone(), cli.client_options(&None))?;
+            let response = client.find_peers(torrent.to_peer_request()).await?;
+            let peers = client.peers(response)?;
+            let probes = tracker::probe_peers(
+                id,
+                &peers,
+                torrent.to_peer_request().info_hash,
+                PEER_CHECK_TIMEOUT,
+                PEER_CHECK_CONCURRENCY,
+            )
+            .await;
+            for (peer, latency) in probes {
+                match latency {
+                    Some(latency) => println!("{}\t{} ms", peer, latency.as_millis()),
+                    None => println!("{}\tunreachable", peer),
+                }
+            }
         }
         Some(Commands::Handshake { torrent_path, peer }) => {
-            let torrent_file = TorrentFile::parse_from_file(torrent_path)?;
+            let torrent_file = TorrentFile::load(torrent_path, cli.lenient_bencode).await?;
             let torrent = Torrent::from_file_torrent(&torrent_file)?;
             let id = peers::PeerID::new();
             let handshake =
                 tracker::perform_handshake(id, peer, &torrent.to_peer_request().info_hash).await?;
             println!("{}", handshake)
         }
+        Some(Commands::HandshakeHash { info_hash, peer }) => {
+            let id = peers::PeerID::new();
+            let handshake = tracker::perform_handshake(id, peer, info_hash).await?;
+            println!("{}", handshake)
+        }
         Some(Commands::DownloadPiece {
             torrent_path,
             output_path,
             piece_index,
+            dump_tracker_response,
+            tracker,
+            no_verify,
         }) => {
-            let torrent_file = TorrentFile::parse_from_file(torrent_path)?;
-            let torrent = Torrent::from_file_torrent(&torrent_file)?;
+            let torrent_file = TorrentFile::load(torrent_path, cli.lenient_bencode).await?;
+            let mut torrent = Torrent::from_file_torrent(&torrent_file)?;
+            if let Some(tracker) = tracker {
+                torrent = torrent.with_tracker(tracker.clone());
+            }
             let id = peers::PeerID::new();
 
-            let peer_client = peers::Client::new(id.clone())?;
+            let peer_client =
+                peers::Client::new(id.clone(), cli.client_options(dump_tracker_response))?;
 
-            let peers = peer_client.find_peers(torrent.to_peer_request()).await?;
-            let peer = peers
-                .iter()
-                .next()
-                .ok_or(anyhow!("no peers found in torrent file"))?;
+            let response = peer_client.find_peers(torrent.to_peer_request()).await?;
+            let peers = peer_client.peers(response)?;
+            if peers.iter().next().is_none() {
+                bail!("no peers found in torrent file");
+            }
 
-            let download_req = torrent.to_download_request();
+            let download_req = torrent.to_download_request()?;
             let piece_data = tracker::perform_download_piece(
                 id,
-                peer,
+                &peers,
                 download_req,
                 piece_index.clone().try_into()?,
+                !no_verify,
             )
             .await?;
+            if let Some(parent) = output_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
             let mut file = fs::OpenOptions::new()
                 .write(true)
                 .create(true)
@@ -120,19 +604,300 @@ async fn main() -> Result<()> {
         Some(Commands::DownloadFile {
             torrent_path,
             output_path,
+            files,
+            file_spec,
+            pipeline_depth,
+            dump_tracker_response,
+            tracker,
+            no_verify,
+            stats_file,
+            peer_cache,
+            max_file_size,
+            show_map,
+            write_buffer_size,
+            connection_metrics,
+            max_retries,
+            max_active_pieces,
         }) => {
-            let torrent_file = TorrentFile::parse_from_file(torrent_path)?;
-            let torrent = Torrent::from_file_torrent(&torrent_file)?;
-            let download_req = torrent.to_download_request();
+            let torrent_file = TorrentFile::load(torrent_path, cli.lenient_bencode).await?;
+            let mut torrent = Torrent::from_file_torrent(&torrent_file)?;
+            if let Some(tracker) = tracker {
+                torrent = torrent.with_tracker(tracker.clone());
+            }
+            if let Some(max_size) = max_file_size {
+                torrent.check_size(*max_size)?;
+            }
+            let selected_files = match file_spec {
+                Some(spec) => {
+                    let selected = torrent.select_files(spec)?;
+                    print_file_selection(&torrent, &selected)?;
+                    Some(selected)
+                }
+                None => (!files.is_empty()).then(|| files.clone()),
+            };
+            if let Some(selected) = &selected_files {
+                torrent = torrent.with_selected_files(selected)?;
+            }
+            let download_req = torrent.to_download_request()?;
             let id = peers::PeerID::new();
+            let totals = match stats_file {
+                Some(path) => TransferTotals::load(path, &download_req.info_hash),
+                None => TransferTotals::default(),
+            };
+            let cached = match peer_cache {
+                Some(path) => peers::Peers::load_cached(path, &download_req.info_hash),
+                None => peers::Peers::from(Vec::new()),
+            };
 
-            let peer_client = peers::Client::new(id.clone())?;
-            let peers = peer_client.find_peers(torrent.to_peer_request()).await?;
+            let peer_client =
+                peers::Client::new(id.clone(), cli.client_options(dump_tracker_response))?;
+            let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel();
+            let progress_rx = if *show_map && std::io::stderr().is_terminal() {
+                let (map_tx, map_rx) = tokio::sync::mpsc::unbounded_channel();
+                tokio::spawn(show_piece_map(
+                    download_req.pieces.len(),
+                    progress_rx,
+                    map_tx,
+                ));
+                map_rx
+            } else {
+                progress_rx
+            };
+            let (session, feed) =
+                session::Session::start(peer_client, torrent, totals, progress_rx, cached).await?;
 
-            tracker::download_file(id, peers, download_req, output_path.to_owned()).await?;
+            // Ctrl-C stops the download, but still flushes what was downloaded.
+            let cancel = session.cancel_token();
+            tokio::spawn({
+                let cancel = cancel.clone();
+                async move {
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        cancel.cancel();
+                    }
+                }
+            });
+            let config = tracker::DownloadConfig {
+                selected_files,
+                pipeline_depth: *pipeline_depth,
+                verify: !no_verify,
+                progress_tx: Some(progress_tx),
+                stats_file: stats_file.clone(),
+                peer_cache: peer_cache.clone(),
+                cancel: Some(cancel),
+                write_buffer_size: *write_buffer_size,
+                connection_metrics: *connection_metrics,
+                max_retries: *max_retries,
+                max_active_pieces: *max_active_pieces,
+                ..Default::default()
+            };
+            let result = if output_path.as_os_str() == "-" {
+                tracker::download_to_writer(id, feed, download_req, tokio::io::stdout(), config)
+                    .await
+            } else {
+                tracker::download_file(id, feed, download_req, output_path.to_owned(), config).await
+            };
+            // Leave the swarm whether the download succeeded or not.
+            if let Err(e) = session.close().await {
+                log::warn!("Could not tell the tracker we stopped: {:#}", e);
+            }
+            let stats = result?;
+            // On stderr, so it doesn't end up in a download written to stdout.
+            if !cli.quiet {
+                eprint!("Peers:\n{}", stats);
+            }
         }
         None => {}
     };
 
     Ok(())
 }
+
+/// Redraws the piece map on stderr for every event of `progress_rx`, then passes the event on to
+/// `forward_tx`.
+async fn show_piece_map(
+    pieces_total: usize,
+    mut progress_rx: tokio::sync::mpsc::UnboundedReceiver<Progress>,
+    forward_tx: tokio::sync::mpsc::UnboundedSender<Progress>,
+) {
+    let mut map = progress::PieceMap::new(pieces_total);
+    while let Some(progress) = progress_rx.recv().await {
+        map.set_done(progress.piece);
+        eprint!(
+            "\r[{}] {}/{} pieces",
+            map.render(PIECE_MAP_WIDTH),
+            progress.pieces_done,
+            progress.pieces_total
+        );
+        if progress.pieces_done == progress.pieces_total {
+            eprintln!();
+        }
+        // The re-announce task may already be gone.
+        let _ = forward_tx.send(progress);
+    }
+}
+
+/// Total size of the file or the files below the directory at `path`.
+fn content_size(path: &std::path::Path) -> Result<u64> {
+    if !path.is_dir() {
+        return Ok(fs::metadata(path)?.len());
+    }
+
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += content_size(&entry?.path())?;
+    }
+
+    Ok(total)
+}
+
+/// Lists the files of `torrent` on stderr, marking the `selected` ones with `*`.
+fn print_file_selection(torrent: &Torrent, selected: &[usize]) -> Result<()> {
+    for (idx, span) in torrent.files()?.iter().enumerate() {
+        let mark = if selected.contains(&idx) { '*' } else { ' ' };
+        eprintln!(
+            "{} {}: {} ({} bytes)",
+            mark,
+            idx,
+            span.path.display(),
+            span.length
+        );
+    }
+
+    Ok(())
+}
+
+/// Lists the torrents in `dir`, only those with one of `info_hashes` unless it is empty.
+/// `lenient` is passed on to `TorrentFile::parse_from_file`.
+fn batch_info(dir: &PathBuf, info_hashes: &[torrent::InfoHash], lenient: bool) -> Result<()> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    paths.retain(|p| p.is_file() && p.extension().is_some_and(|ext| ext == "torrent"));
+    paths.sort();
+
+    let mut failures = Vec::new();
+    let mut found = vec![false; info_hashes.len()];
+    for path in paths {
+        let parsed = TorrentFile::parse_from_file(&path, lenient)
+            .and_then(|tf| Torrent::from_file_torrent(&tf));
+        match parsed {
+            Ok(torrent) => {
+                let mut matching = info_hashes.is_empty();
+                for (hash, found) in info_hashes.iter().zip(&mut found) {
+                    if hash == torrent.info_hash() {
+                        *found = true;
+                        matching = true;
+                    }
+                }
+                if !matching {
+                    continue;
+                }
+                println!("{}\t{}", path.display(), torrent.summary())
+            }
+            Err(e) => failures.push((path, e)),
+        }
+    }
+
+    for (hash, _) in info_hashes.iter().zip(&found).filter(|(_, found)| !**found) {
+        eprintln!("No torrent with info hash {}", hash.to_hex());
+    }
+
+    if !failures.is_empty() {
+        for (path, e) in &failures {
+            eprintln!("{}: {:#}", path.display(), e);
+        }
+        bail!("{} torrent files could not be parsed", failures.len());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_info_reports_broken_files() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        fs::copy("sample.torrent", dir.path().join("sample.torrent"))?;
+        fs::write(dir.path().join("broken.torrent"), b"d8:announce")?;
+        // Not a torrent file, skipped.
+        fs::write(dir.path().join("notes.txt"), b"d8:announce")?;
+
+        let err = batch_info(&dir.path().to_path_buf(), &[], false).unwrap_err();
+        assert_eq!(err.to_string(), "1 torrent files could not be parsed");
+
+        fs::remove_file(dir.path().join("broken.torrent"))?;
+        batch_info(&dir.path().to_path_buf(), &[], false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_level_flags() {
+        let level = |args: &[&str]| Cli::parse_from(args).log_level();
+
+        assert_eq!(level(&["prog"]), log::LevelFilter::Warn);
+        assert_eq!(level(&["prog", "-q"]), log::LevelFilter::Error);
+        assert_eq!(level(&["prog", "-v"]), log::LevelFilter::Info);
+        assert_eq!(level(&["prog", "-vv"]), log::LevelFilter::Debug);
+        assert_eq!(level(&["prog", "-vvv"]), log::LevelFilter::Trace);
+        assert!(Cli::try_parse_from(["prog", "-q", "-v"]).is_err());
+    }
+
+    #[test]
+    fn test_batch_info_hash_filter() {
+        let hash = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+        let cli = Cli::parse_from(["prog", "batch-info", "dir", "--info-hash", hash]);
+        let Some(Commands::BatchInfo { info_hash, .. }) = cli.command else {
+            panic!("expected batch-info");
+        };
+        assert_eq!(info_hash.len(), 1);
+        assert_eq!(info_hash[0].to_hex(), hash);
+
+        assert!(Cli::try_parse_from(["prog", "batch-info", "dir", "--info-hash", "xyz"]).is_err());
+    }
+
+    #[test]
+    fn test_handshake_hash_flags() {
+        let hash = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+        let cli = Cli::parse_from(["prog", "handshake-hash", "--info-hash", hash, "[::1]:6881"]);
+        let Some(Commands::HandshakeHash { info_hash, peer }) = cli.command else {
+            panic!("expected handshake-hash");
+        };
+        assert_eq!(info_hash.to_hex(), hash);
+        assert_eq!(peer.to_string(), "[::1]:6881");
+
+        assert!(Cli::try_parse_from(["prog", "handshake-hash", "127.0.0.1:6881"]).is_err());
+    }
+
+    #[test]
+    fn test_file_selection_flags() {
+        let args = [
+            "prog",
+            "download",
+            "-o",
+            "out",
+            "t.torrent",
+            "--files",
+            "0,*.mkv",
+        ];
+        let Some(Commands::DownloadFile { file_spec, .. }) = Cli::parse_from(args).command else {
+            panic!("expected download");
+        };
+        assert_eq!(file_spec.as_deref(), Some("0,*.mkv"));
+
+        let both = [
+            "prog",
+            "download",
+            "-o",
+            "out",
+            "t.torrent",
+            "--files",
+            "0",
+            "--file",
+            "1",
+        ];
+        assert!(Cli::try_parse_from(both).is_err());
+    }
+}
@@ -1,18 +1,42 @@
 use core::fmt;
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use anyhow::{Context, Result};
+use log::warn;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, Bytes};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use url::Url;
 
 use crate::torrent;
 
 const PEER_BYTE_SIZE: usize = 6;
+const PEER6_BYTE_SIZE: usize = 18;
 const PORT: usize = 6881;
 const ID_SIZE: usize = 20;
+// How much of an unparseable tracker response is shown in errors.
+const BODY_SNIPPET_LEN: usize = 200;
+// Redirects followed per announce, e.g. for a tracker that moved to another domain.
+const MAX_TRACKER_REDIRECTS: usize = 5;
+/// Sent to trackers unless overridden, some reject requests without a User-Agent.
+pub const DEFAULT_USER_AGENT: &str = "rusty-bittorrent/0.1";
+// Parameters of every announce, see `QueryParams`. Extra parameters can't replace them.
+const STANDARD_PARAMS: [&str; 8] = [
+    "info_hash",
+    "peer_id",
+    "port",
+    "uploaded",
+    "downloaded",
+    "left",
+    "compact",
+    "event",
+];
 
 pub struct PeerID(String);
 
@@ -41,22 +65,49 @@ impl PeerID {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Peer {
-    ip: IpAddr,
+    #[serde(rename = "ip")]
+    addr: PeerAddr,
     port: u16,
 }
 
+/// Trackers hand out IPs, peers given by hand may also be hostnames.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+enum PeerAddr {
+    Ip(IpAddr),
+    Host(String),
+}
+
 impl std::str::FromStr for Peer {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Peer, String> {
-        s.parse::<SocketAddr>()
-            .map(|addr| Peer {
-                ip: addr.ip(),
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Peer {
+                addr: PeerAddr::Ip(addr.ip()),
                 port: addr.port(),
-            })
-            .map_err(|_| format!("Invalid Peer SocketAddr: {}", s))
+            });
+        }
+
+        match s.rsplit_once(':') {
+            Some((host, port))
+                if !host.is_empty()
+                    && host
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') =>
+            {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid Peer port: {}", s))?;
+                Ok(Peer {
+                    addr: PeerAddr::Host(host.to_string()),
+                    port,
+                })
+            }
+            _ => Err(format!("Invalid Peer SocketAddr: {}", s)),
+        }
     }
 }
 
@@ -81,156 +132,1354 @@ impl Peer {
         let ip = IpAddr::from(ip_bytes);
         let port = u16::from_be_bytes(port_bytes);
 
-        Ok(Peer { ip, port })
+        Ok(Peer {
+            addr: PeerAddr::Ip(ip),
+            port,
+        })
+    }
+
+    fn from_bytes6(b: &[u8]) -> Result<Peer> {
+        if b.len() != PEER6_BYTE_SIZE {
+            anyhow::bail!("expected 18 bytes to build an IPv6 Peer, have {}", b.len());
+        }
+
+        let ip_bytes: [u8; 16] = b[..16].try_into()?;
+        let ip = IpAddr::from(ip_bytes);
+        let port = u16::from_be_bytes([b[16], b[17]]);
+
+        Ok(Peer {
+            addr: PeerAddr::Ip(ip),
+            port,
+        })
     }
 
     pub fn to_string(&self) -> String {
-        return format!("{}:{}", self.ip, self.port);
+        match &self.addr {
+            // SocketAddr puts IPv6 addresses into brackets.
+            PeerAddr::Ip(ip) => SocketAddr::new(*ip, self.port).to_string(),
+            PeerAddr::Host(host) => format!("{}:{}", host, self.port),
+        }
+    }
+
+    /// Connects to the peer. Hostnames are resolved first, trying every address they resolve to
+    /// until one accepts the connection.
+    pub async fn connect(&self) -> Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = match &self.addr {
+            PeerAddr::Ip(ip) => vec![SocketAddr::new(*ip, self.port)],
+            PeerAddr::Host(host) => tokio::net::lookup_host((host.as_str(), self.port))
+                .await
+                .with_context(|| format!("could not resolve peer {}", host))?
+                .collect(),
+        };
+
+        let mut last_err = None;
+        for addr in addrs {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    log::debug!("Could not connect to {} at {}: {}", self, addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        match last_err {
+            Some(e) => Err(e.into()),
+            None => anyhow::bail!("peer {} resolved to no address", self),
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct ErrorResponse {
-    #[serde(rename = "failure reason")]
-    failure_reason: String,
+/// Tells the tracker about a change of the download, sent with the announce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// The first announce of a download.
+    Started,
+    /// The download is left, the tracker can drop us from the swarm.
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Stopped => "stopped",
+        }
+    }
 }
 
 struct QueryParams<'a> {
     info_hash: &'a str,
-    peer_id: &'a String,
+    peer_id: &'a str,
     port: usize,
-    uploaded: usize,
-    downloaded: usize,
-    left: usize,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
     compact: u8,
+    event: Option<AnnounceEvent>,
 }
 
-pub struct Peers(Vec<Peer>);
+pub struct Peers {
+    peers: Vec<Peer>,
+    // Seeders and leechers in the swarm, if the tracker told us.
+    complete: Option<u64>,
+    incomplete: Option<u64>,
+    // How long to wait before the next announce, and the least that must be waited.
+    interval: Option<Duration>,
+    min_interval: Option<Duration>,
+}
 
 impl Peers {
     pub fn iter(&self) -> std::slice::Iter<'_, Peer> {
-        self.0.iter()
+        self.peers.iter()
     }
 
-    fn from_peer_response(pr: PeerResponse) -> Result<Peers> {
-        let mut out = Vec::new();
-        let chunks = pr.peers.chunks(PEER_BYTE_SIZE);
-        for chunk in chunks {
+    /// Number of seeders in the swarm, as reported by the tracker.
+    pub fn complete(&self) -> Option<u64> {
+        self.complete
+    }
+
+    /// Number of leechers in the swarm, as reported by the tracker.
+    pub fn incomplete(&self) -> Option<u64> {
+        self.incomplete
+    }
+
+    /// How long the tracker asks to wait before announcing again.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// The tracker's `min interval`, announcing more often may get the client banned.
+    pub fn min_interval(&self) -> Option<Duration> {
+        self.min_interval
+    }
+
+    /// Fails on a peers or peers6 field with a partial peer at its end, unless `lenient`, which
+    /// drops that peer with a warning. Some trackers pad or truncate the list.
+    pub(crate) fn from_tracker_response(pr: TrackerResponse, lenient: bool) -> Result<Peers> {
+        pr.check()?;
+
+        let chunks = pr.peers.unwrap_or_default();
+        let chunks6 = pr.peers6.unwrap_or_default();
+        // A partial trailing peer would fail in `from_bytes` with a less telling error.
+        let chunks = whole_peers(&chunks, PEER_BYTE_SIZE, "peers", lenient)?;
+        let chunks6 = whole_peers(&chunks6, PEER6_BYTE_SIZE, "peers6", lenient)?;
+
+        let mut out =
+            Vec::with_capacity(chunks.len() / PEER_BYTE_SIZE + chunks6.len() / PEER6_BYTE_SIZE);
+        for chunk in chunks.chunks(PEER_BYTE_SIZE) {
             let p = Peer::from_bytes(chunk)?;
             out.push(p);
         }
+        for chunk in chunks6.chunks(PEER6_BYTE_SIZE) {
+            let p = Peer::from_bytes6(chunk)?;
+            out.push(p);
+        }
 
-        Ok(Peers(out))
+        Ok(Peers {
+            peers: out,
+            complete: pr.complete,
+            incomplete: pr.incomplete,
+            interval: pr.interval.map(Duration::from_secs),
+            min_interval: pr.min_interval.map(Duration::from_secs),
+        })
     }
 
     pub(crate) fn into_iter(self) -> std::vec::IntoIter<Peer> {
-        self.0.into_iter()
+        self.peers.into_iter()
     }
 
     pub(crate) fn len(&self) -> usize {
-        self.0.len()
+        self.peers.len()
+    }
+
+    /// JSON array of `{"ip": ..., "port": ...}` objects.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.peers)?)
+    }
+
+    /// Reads the peers of `info_hash` from the peer cache at `path`, to try them without waiting
+    /// for the tracker. A missing or corrupt cache counts as empty, invalid entries are skipped.
+    pub fn load_cached(path: &Path, info_hash: &torrent::InfoHash) -> Peers {
+        let peers = read_peer_cache(path)
+            .remove(&info_hash.to_hex())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|p| p.parse().ok())
+            .collect::<Vec<Peer>>();
+        Peers::from(peers)
+    }
+
+    /// Stores these peers as the cached ones of `info_hash` in the peer cache at `path`,
+    /// replacing the earlier ones and keeping those of other torrents.
+    pub fn save_cached(&self, path: &Path, info_hash: &torrent::InfoHash) -> Result<()> {
+        let mut cache = read_peer_cache(path);
+        cache.insert(
+            info_hash.to_hex(),
+            self.peers.iter().map(Peer::to_string).collect(),
+        );
+        std::fs::write(path, serde_json::to_vec_pretty(&cache)?)
+            .with_context(|| format!("could not write peer cache {}", path.display()))
+    }
+}
+
+// Peer cache content, peer addresses keyed by info hash hex.
+type PeerCache = BTreeMap<String, Vec<String>>;
+
+/// The whole peers of the compact peer list `chunks`, see `Peers::from_tracker_response`.
+fn whole_peers<'a>(chunks: &'a [u8], size: usize, field: &str, lenient: bool) -> Result<&'a [u8]> {
+    let partial = chunks.len() % size;
+    if partial == 0 {
+        return Ok(chunks);
+    }
+    if !lenient {
+        anyhow::bail!(
+            "{} field length {} is not a multiple of {}",
+            field,
+            chunks.len(),
+            size
+        );
+    }
+    warn!(
+        "Dropping {} trailing bytes of the {} field, {} bytes is not a multiple of {}",
+        partial,
+        field,
+        chunks.len(),
+        size
+    );
+    Ok(&chunks[..chunks.len() - partial])
+}
+
+fn read_peer_cache(path: &Path) -> PeerCache {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return PeerCache::new(),
+        Err(e) => {
+            warn!("Could not read peer cache {}: {}", path.display(), e);
+            return PeerCache::new();
+        }
+    };
+    serde_json::from_slice(&content).unwrap_or_else(|e| {
+        warn!("Ignoring corrupt peer cache {}: {}", path.display(), e);
+        PeerCache::new()
+    })
+}
+
+impl From<Vec<Peer>> for Peers {
+    fn from(peers: Vec<Peer>) -> Self {
+        Peers {
+            peers,
+            complete: None,
+            incomplete: None,
+            interval: None,
+            min_interval: None,
+        }
     }
 }
 
 impl fmt::Display for Peers {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for peer in self.iter() {
-            writeln!(f, "{}:{}", peer.ip, peer.port)?;
+            writeln!(f, "{}", peer)?;
         }
         Ok(())
     }
 }
 
+/// Everything a tracker may answer an announce with. Only `failure reason` is set if the
+/// announce failed, the other fields are optional as not every tracker sends them.
 #[serde_as]
-#[derive(Deserialize, Debug)]
-pub struct PeerResponse {
-    #[serde_as(as = "Bytes")]
-    pub peers: Vec<u8>,
+#[derive(Deserialize, Debug, Default)]
+pub struct TrackerResponse {
+    #[serde(rename = "failure reason", default)]
+    pub failure_reason: Option<String>,
+    // Compact IPv4 peers, trackers in IPv6-only swarms may only send peers6.
+    #[serde_as(as = "Option<Bytes>")]
+    #[serde(default)]
+    pub peers: Option<Vec<u8>>,
+    // Compact IPv6 peers (BEP 7), 16 bytes address and 2 bytes port each.
+    #[serde_as(as = "Option<Bytes>")]
+    #[serde(default)]
+    pub peers6: Option<Vec<u8>>,
+    // Seeders and leechers, optional in the spec.
+    #[serde(default)]
+    pub complete: Option<u64>,
+    #[serde(default)]
+    pub incomplete: Option<u64>,
+    // Unlike `failure reason`, the announce still succeeded.
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+    // Seconds until the next announce, required by the spec but missing from some responses.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<u64>,
+    // Meant to be sent back on later announces, may be binary.
+    #[serde_as(as = "Option<Bytes>")]
+    #[serde(rename = "tracker id", default)]
+    pub tracker_id: Option<Vec<u8>>,
 }
 
+impl TrackerResponse {
+    /// Joins the responses of several trackers, skipping peers listed more than once. The swarm
+    /// size is the largest one reported, as all trackers see the same swarm. The first warning
+    /// and tracker id are kept, the intervals are the longest asked for, so no tracker is
+    /// announced to too often. The peer lists must hold whole peers, see `whole_peers`.
+    fn merge(all: impl IntoIterator<Item = TrackerResponse>) -> TrackerResponse {
+        fn join(
+            merged: &mut Option<Vec<u8>>,
+            seen: &mut HashSet<Vec<u8>>,
+            list: Option<Vec<u8>>,
+            size: usize,
+        ) {
+            let Some(list) = list else {
+                return;
+            };
+            let merged = merged.get_or_insert_with(Vec::new);
+            for peer in list.chunks(size) {
+                if seen.insert(peer.to_vec()) {
+                    merged.extend_from_slice(peer);
+                }
+            }
+        }
+
+        let mut merged = TrackerResponse::default();
+        let mut seen = HashSet::new();
+        for response in all {
+            join(&mut merged.peers, &mut seen, response.peers, PEER_BYTE_SIZE);
+            join(
+                &mut merged.peers6,
+                &mut seen,
+                response.peers6,
+                PEER6_BYTE_SIZE,
+            );
+            merged.complete = merged.complete.max(response.complete);
+            merged.incomplete = merged.incomplete.max(response.incomplete);
+            merged.warning_message = merged.warning_message.or(response.warning_message);
+            merged.interval = merged.interval.max(response.interval);
+            merged.min_interval = merged.min_interval.max(response.min_interval);
+            merged.tracker_id = merged.tracker_id.or(response.tracker_id);
+        }
+        merged
+    }
+
+    /// Cuts a partial trailing peer off the peer lists, see `Peers::from_tracker_response`.
+    fn whole_peers(mut self, lenient: bool) -> Result<TrackerResponse> {
+        if let Some(peers) = &self.peers {
+            self.peers = Some(whole_peers(peers, PEER_BYTE_SIZE, "peers", lenient)?.to_vec());
+        }
+        if let Some(peers6) = &self.peers6 {
+            self.peers6 = Some(whole_peers(peers6, PEER6_BYTE_SIZE, "peers6", lenient)?.to_vec());
+        }
+
+        Ok(self)
+    }
+
+    /// Fails if the announce failed or the response has no peer list to use.
+    fn check(&self) -> Result<()> {
+        if let Some(reason) = &self.failure_reason {
+            anyhow::bail!("API Error: {}", reason);
+        }
+        if self.peers.is_none() && self.peers6.is_none() {
+            anyhow::bail!("tracker response contains neither peers nor peers6");
+        }
+
+        Ok(())
+    }
+}
+
+/// Which IP version is used to reach trackers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Connect over whatever the resolver returns for the tracker host, IPv4 or IPv6.
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl std::str::FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AddressFamily, String> {
+        match s {
+            "any" => Ok(AddressFamily::Any),
+            "ipv4" | "4" => Ok(AddressFamily::Ipv4),
+            "ipv6" | "6" => Ok(AddressFamily::Ipv6),
+            other => Err(format!(
+                "Invalid address family {}, expected any, ipv4 or ipv6",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ClientOptions {
+    pub address_family: AddressFamily,
+    /// Writes the raw tracker response body to this file before parsing it.
+    pub dump_response: Option<PathBuf>,
+    /// User-Agent sent to trackers, None sends `DEFAULT_USER_AGENT`.
+    pub user_agent: Option<String>,
+    /// Added to every announce, for trackers requiring more than the standard parameters, like
+    /// `supportcrypto`. Sorted by key, so announce URLs don't change between runs.
+    pub extra_params: BTreeMap<String, String>,
+    /// Drops a partial trailing peer of a compact peer list with a warning, where by default the
+    /// whole response is rejected.
+    pub lenient_peers: bool,
+}
+
+/// Parses an extra announce parameter given as `key=value`, see `ClientOptions::extra_params`.
+pub fn parse_query_param(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!(
+            "Invalid announce parameter {}, expected key=value",
+            s
+        )),
+    }
+}
+
+#[derive(Clone)]
 pub struct Client {
     // Unique, 20 char String.
     peer_id: PeerID,
     inner: reqwest::Client,
+    dump_response: Option<PathBuf>,
+    extra_params: BTreeMap<String, String>,
+    lenient_peers: bool,
 }
 
 impl Client {
-    pub fn new(id: PeerID) -> Result<Client> {
-        let client = reqwest::Client::builder()
+    pub fn new(id: PeerID, options: ClientOptions) -> Result<Client> {
+        if let Some(key) = options
+            .extra_params
+            .keys()
+            .find(|k| STANDARD_PARAMS.contains(&k.as_str()))
+        {
+            anyhow::bail!("announce parameter {} can't be replaced", key);
+        }
+        let user_agent = options.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+        let mut builder = reqwest::Client::builder()
             .timeout(Duration::from_secs(20))
-            .build()?;
+            .user_agent(user_agent)
+            // Followed in `announce`, which keeps the announce parameters.
+            .redirect(reqwest::redirect::Policy::none());
+        // Binding to the unspecified address of a family makes connecting over the other family
+        // fail, so only addresses of the preferred family are used.
+        builder = match options.address_family {
+            AddressFamily::Any => builder,
+            AddressFamily::Ipv4 => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            AddressFamily::Ipv6 => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+        let client = builder.build()?;
         Ok(Client {
             peer_id: id,
             inner: client,
+            dump_response: options.dump_response,
+            extra_params: options.extra_params,
+            lenient_peers: options.lenient_peers,
         })
     }
 
-    pub async fn find_peers(&self, req: torrent::PeerRequest<'_>) -> Result<Peers> {
+    fn announce_url(&self, req: &torrent::PeerRequest<'_>, tracker: &Url) -> String {
         let hash_url_encoded = urlencoding::encode_binary(req.info_hash.get_hash());
 
         let query_params = QueryParams {
             info_hash: &hash_url_encoded.into_owned(),
-            peer_id: &self.peer_id.to_string(),
+            peer_id: self.peer_id.to_string(),
             port: PORT,
-            uploaded: 0,
-            downloaded: 0,
-            left: req.length as usize,
+            uploaded: req.uploaded,
+            downloaded: req.downloaded,
+            left: req.left,
             compact: 1,
+            event: req.event,
         };
 
-        // Thats kinda shitty, but I did not find a way to encode info_hash, and skip double
-        // encoding by url::Url or .query (of reqwest).
-        let full_url = format!(
-            "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}",
-            req.url.to_string(),
-            query_params.info_hash,
-            query_params.peer_id,
-            query_params.port,
-            query_params.uploaded,
-            query_params.downloaded,
-            query_params.left,
-            query_params.compact
-        );
+        // Announce URLs of private trackers may already carry a query, e.g. a passkey, so the
+        // parameters are appended to it.
+        let mut url = tracker.clone();
+        url.query_pairs_mut()
+            .append_pair("peer_id", query_params.peer_id)
+            .append_pair("port", &query_params.port.to_string())
+            .append_pair("uploaded", &query_params.uploaded.to_string())
+            .append_pair("downloaded", &query_params.downloaded.to_string())
+            .append_pair("left", &query_params.left.to_string())
+            .append_pair("compact", &query_params.compact.to_string());
+        if let Some(event) = query_params.event {
+            url.query_pairs_mut().append_pair("event", event.as_str());
+        }
+        url.query_pairs_mut().extend_pairs(&self.extra_params);
 
-        let resp = self
-            .inner
-            .request(reqwest::Method::GET, full_url)
-            .send()
-            .await
-            .context("failed to sent GET request")?;
+        // The info_hash is already percent encoded binary, url::Url would encode it again. The
+        // tracker id may be binary as well.
+        let mut url = format!("{}&info_hash={}", url, query_params.info_hash);
+        if let Some(id) = &req.tracker_id {
+            url.push_str("&trackerid=");
+            url.push_str(&urlencoding::encode_binary(id));
+        }
+        url
+    }
+
+    /// Announces to every tracker of the request at once and merges their responses, see `peers`
+    /// for the peer list. Peers of earlier tiers (BEP 12) come first. Only fails if no tracker
+    /// answered.
+    pub async fn find_peers(&self, req: torrent::PeerRequest<'_>) -> Result<TrackerResponse> {
+        let trackers: Vec<Url> = req.tiers().into_iter().flatten().collect();
+        if let [tracker] = trackers.as_slice() {
+            return self.announce(self.announce_url(&req, tracker)).await;
+        }
+
+        let mut announces = JoinSet::new();
+        for (idx, tracker) in trackers.iter().enumerate() {
+            let client = self.clone();
+            let full_url = self.announce_url(&req, tracker);
+            announces.spawn(async move {
+                let res = client.announce(full_url).await;
+                (idx, res.and_then(|r| r.whole_peers(client.lenient_peers)))
+            });
+        }
+
+        let mut answers = Vec::new();
+        let mut last_err = None;
+        while let Some(joined) = announces.join_next().await {
+            let (idx, res) = joined?;
+            match res {
+                Ok(response) => answers.push((idx, response)),
+                Err(e) => {
+                    if e.is::<Unresolvable>() {
+                        warn!("{}, trying the other trackers", e);
+                    } else {
+                        warn!("Announce to {} failed: {:#}", trackers[idx], e);
+                    }
+                    last_err = Some(e);
+                }
+            }
+        }
+        if answers.is_empty() {
+            return Err(last_err
+                .context("no tracker to announce to")?
+                .context(format!("all {} trackers failed", trackers.len())));
+        }
+
+        answers.sort_by_key(|(idx, _)| *idx);
+        Ok(TrackerResponse::merge(
+            answers.into_iter().map(|(_, response)| response),
+        ))
+    }
+
+    /// The peers of a `find_peers` response, see `ClientOptions::lenient_peers`.
+    pub fn peers(&self, response: TrackerResponse) -> Result<Peers> {
+        Peers::from_tracker_response(response, self.lenient_peers)
+    }
+
+    async fn announce(&self, full_url: String) -> Result<TrackerResponse> {
+        let mut url = full_url;
+        let mut redirects = 0;
+        let resp = loop {
+            let resp = self
+                .inner
+                .request(reqwest::Method::GET, &url)
+                .send()
+                .await
+                .map_err(send_error)?;
+            if !resp.status().is_redirection() {
+                break resp;
+            }
+            if redirects == MAX_TRACKER_REDIRECTS {
+                anyhow::bail!(
+                    "tracker redirected more than {} times",
+                    MAX_TRACKER_REDIRECTS
+                );
+            }
+            redirects += 1;
+            let location = resp
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .context("tracker redirected without a Location")?;
+            url = redirect_url(&url, location)?;
+            log::info!("Tracker redirected announce to {}", url);
+        };
 
         let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
 
         let body = resp.bytes().await?;
 
+        if let Some(path) = &self.dump_response {
+            tokio::fs::write(path, &body).await.with_context(|| {
+                format!("could not dump tracker response to {}", path.display())
+            })?;
+        }
+
         if !status.is_success() {
             anyhow::bail!("Request failed with status: {}", status);
         }
 
-        if let Ok(error) = serde_bencode::from_bytes::<ErrorResponse>(&body) {
-            anyhow::bail!(format!("API Error: {}", error.failure_reason))
-        }
+        check_bencode_body(status, content_type.as_deref(), &body)?;
 
-        let parsed: PeerResponse = serde_bencode::from_bytes(&body)
+        let parsed: TrackerResponse = serde_bencode::from_bytes(&body)
             .with_context(|| format!("Failed to parse bencoded string: {:?}", body))?;
+        // Shown by the callers, which see the merged warning of all trackers.
+        if let Some(warning) = &parsed.warning_message {
+            log::info!("Tracker warning from {}: {}", url, warning);
+        }
+        if let Some(id) = &parsed.tracker_id {
+            log::debug!("Tracker id of {}: {}", url, String::from_utf8_lossy(id));
+        }
+        parsed.check()?;
 
-        Peers::from_peer_response(parsed)
+        Ok(parsed)
     }
 }
 
+/// The host of a tracker could not be resolved, e.g. due to a DNS outage.
+#[derive(Debug)]
+struct Unresolvable {
+    host: String,
+    reason: String,
+}
+
+impl fmt::Display for Unresolvable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "could not resolve tracker {}: {}",
+            self.host, self.reason
+        )
+    }
+}
+
+impl std::error::Error for Unresolvable {}
+
+/// Tells DNS failures apart from the tracker being down. Both fail the connect with an io error
+/// as the last source, see `is_lookup_error`.
+fn send_error(err: reqwest::Error) -> anyhow::Error {
+    let io_err = std::iter::successors(std::error::Error::source(&err), |e| e.source())
+        .find_map(|e| e.downcast_ref::<std::io::Error>());
+    if let Some(io_err) = io_err.filter(|e| err.is_connect() && is_lookup_error(e)) {
+        return Unresolvable {
+            host: err
+                .url()
+                .and_then(Url::host_str)
+                .unwrap_or_default()
+                .to_string(),
+            reason: io_err.to_string(),
+        }
+        .into();
+    }
+
+    anyhow::Error::new(err).context("failed to sent GET request")
+}
+
+/// Whether the io error of a failed connect comes from the host lookup. A socket that failed to
+/// connect carries its OS error code, std reports a failed lookup without one. hyper's connect
+/// timeout and unreachable network errors have none either, so their kinds are ruled out.
+fn is_lookup_error(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    err.raw_os_error().is_none()
+        && !matches!(
+            err.kind(),
+            ErrorKind::TimedOut | ErrorKind::NotConnected | ErrorKind::Interrupted
+        )
+}
+
+/// Resolves the `location` a tracker redirected the announce `url` to. Trackers that moved often
+/// redirect to their new announce URL only, the announce parameters are added again then.
+fn redirect_url(url: &str, location: &str) -> Result<String> {
+    let current = Url::parse(url)?;
+    let target = current
+        .join(location)
+        .with_context(|| format!("invalid redirect location {}", location))?;
+    if target.query_pairs().any(|(key, _)| key == "info_hash") {
+        return Ok(target.to_string());
+    }
+
+    // The query is taken as is, the info_hash in it is already percent encoded.
+    let separator = if target.query().is_some() { '&' } else { '?' };
+    Ok(format!(
+        "{}{}{}",
+        target,
+        separator,
+        current.query().unwrap_or_default()
+    ))
+}
+
+/// Trackers behind proxies sometimes answer with an HTML page and status 200 when rate-limiting,
+/// catch those before the bencode parser produces an unreadable error.
+fn check_bencode_body(
+    status: reqwest::StatusCode,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<()> {
+    let is_html = content_type.is_some_and(|ct| ct.to_ascii_lowercase().contains("text/html"));
+    let is_bencode = matches!(body.first(), Some(b'd' | b'l' | b'i' | b'0'..=b'9'));
+    if is_html || !is_bencode {
+        let snippet = String::from_utf8_lossy(&body[..body.len().min(BODY_SNIPPET_LEN)]);
+        anyhow::bail!(
+            "tracker returned non-bencode response (possibly rate-limited, blocked or an error page), status: {}, content-type: {}, body: {:?}",
+            status,
+            content_type.unwrap_or("none"),
+            snippet
+        );
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::MockTracker;
+
+    #[tokio::test]
+    async fn test_peer_hostname() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        // localhost may resolve to ::1 first, where nobody listens.
+        let peer: Peer = format!("localhost:{}", port).parse()?;
+        assert_eq!(peer.to_string(), format!("localhost:{}", port));
+        peer.connect().await?;
+        listener.accept().await?;
+
+        let peer: Peer = "127.0.0.1:6881".parse()?;
+        assert!(matches!(peer.addr, PeerAddr::Ip(_)));
+
+        // .invalid never resolves (RFC 6761).
+        let peer: Peer = "peer.invalid:6881".parse()?;
+        let err = peer.connect().await.unwrap_err();
+        assert!(err.to_string().contains("could not resolve"), "{:#}", err);
+        assert!("peer.example".parse::<Peer>().is_err());
+        assert!("peer example:1".parse::<Peer>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_address_family() {
+        assert_eq!("any".parse(), Ok(AddressFamily::Any));
+        assert_eq!("ipv6".parse(), Ok(AddressFamily::Ipv6));
+        assert_eq!("4".parse(), Ok(AddressFamily::Ipv4));
+        assert!("ipv5".parse::<AddressFamily>().is_err());
+    }
 
     #[test]
     fn test_decode_peers() -> Result<(), Box<dyn std::error::Error>> {
         let bencoded = b"d8:completei4e10:incompletei1e8:intervali60e12:min intervali60e5:peers18:\xa5\xe8)I\xc9d\xa5\xe8&\xa4\xc9L\xa5\xe8#r\xc8\xede";
 
-        let response: PeerResponse = serde_bencode::from_bytes(bencoded)?;
+        let response: TrackerResponse = serde_bencode::from_bytes(bencoded)?;
+        assert_eq!(
+            (response.interval, response.min_interval),
+            (Some(60), Some(60))
+        );
 
-        let has_data = response.peers.len() > 0;
+        let has_data = response.peers.unwrap().len() > 0;
         assert_eq!(true, has_data);
 
         Ok(())
     }
+
+    #[test]
+    fn test_tracker_response() -> Result<(), Box<dyn std::error::Error>> {
+        let bencoded = b"d8:completei4e10:incompletei1e8:intervali900e12:min intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id3:\xffid15:warning message4:slowe";
+        let response: TrackerResponse = serde_bencode::from_bytes(bencoded)?;
+        assert_eq!(response.failure_reason, None);
+        assert_eq!(response.tracker_id.as_deref(), Some(&b"\xffid"[..]));
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+
+        let peers = Peers::from_tracker_response(response, false)?;
+        assert_eq!(peers.to_string(), "127.0.0.1:6881\n");
+        assert_eq!((peers.complete(), peers.incomplete()), (Some(4), Some(1)));
+        assert_eq!(peers.interval(), Some(Duration::from_secs(900)));
+        assert_eq!(peers.min_interval(), Some(Duration::from_secs(60)));
+
+        // A failure wins, even if peers were sent along.
+        let bencoded = b"d14:failure reason17:torrent not found5:peers0:e";
+        let response: TrackerResponse = serde_bencode::from_bytes(bencoded)?;
+        assert_eq!(
+            response.failure_reason.as_deref(),
+            Some("torrent not found")
+        );
+        let Err(err) = Peers::from_tracker_response(response, false) else {
+            panic!("expected the failure reason as error");
+        };
+        assert_eq!(err.to_string(), "API Error: torrent not found");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_peers_length() -> Result<(), Box<dyn std::error::Error>> {
+        let mut bencoded = b"d8:intervali60e5:peers6000:".to_vec();
+        for i in 0..1000u16 {
+            bencoded.extend_from_slice(&[10, 0, (i >> 8) as u8, i as u8, 0x1a, 0xe1]);
+        }
+        bencoded.push(b'e');
+        let peers = Peers::from_tracker_response(serde_bencode::from_bytes(&bencoded)?, false)?;
+        assert_eq!(peers.len(), 1000);
+        assert_eq!(peers.peers.capacity(), 1000);
+
+        let partial = b"d8:intervali60e5:peers8:\x7f\x00\x00\x01\x1a\xe1\x7f\x00e";
+        let Err(err) = Peers::from_tracker_response(serde_bencode::from_bytes(partial)?, false)
+        else {
+            panic!("expected the partial peer to be rejected");
+        };
+        assert_eq!(
+            err.to_string(),
+            "peers field length 8 is not a multiple of 6"
+        );
+        // Lenient parsing keeps the whole peers.
+        let peers = Peers::from_tracker_response(serde_bencode::from_bytes(partial)?, true)?;
+        assert_eq!(peers.to_string(), "127.0.0.1:6881\n");
+
+        let partial6 = b"d8:intervali60e6:peers65:\x20\x01\x0d\xb8\x00e";
+        let Err(err) = Peers::from_tracker_response(serde_bencode::from_bytes(partial6)?, false)
+        else {
+            panic!("expected the partial peer to be rejected");
+        };
+        assert_eq!(
+            err.to_string(),
+            "peers6 field length 5 is not a multiple of 18"
+        );
+        let peers = Peers::from_tracker_response(serde_bencode::from_bytes(partial6)?, true)?;
+        assert_eq!(peers.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_swarm_size() -> Result<(), Box<dyn std::error::Error>> {
+        let bencoded =
+            b"d8:completei4e10:incompletei1e8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+        let peers = Peers::from_tracker_response(serde_bencode::from_bytes(bencoded)?, false)?;
+        assert_eq!(peers.complete(), Some(4));
+        assert_eq!(peers.incomplete(), Some(1));
+
+        let bencoded = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+        let peers = Peers::from_tracker_response(serde_bencode::from_bytes(bencoded)?, false)?;
+        assert_eq!(peers.complete(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_warning_message() -> Result<(), Box<dyn std::error::Error>> {
+        let bencoded =
+            b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe115:warning message14:client too olde";
+        let response: TrackerResponse = serde_bencode::from_bytes(bencoded)?;
+        assert_eq!(response.warning_message.as_deref(), Some("client too old"));
+        // Not fatal, the peers are used as usual.
+        let peers = Peers::from_tracker_response(response, false)?;
+        assert_eq!(peers.to_string(), "127.0.0.1:6881\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peer_cache_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("peers.json");
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        let other = torrent::Hash::new([0xcd; 20]);
+        assert_eq!(Peers::load_cached(&path, &info_hash).len(), 0);
+
+        let peers: Vec<Peer> = vec!["127.0.0.1:6881".parse()?, "[2001:db8::1]:6882".parse()?];
+        Peers::from(peers).save_cached(&path, &info_hash)?;
+        Peers::from(vec!["10.0.0.1:1".parse()?]).save_cached(&path, &other)?;
+
+        let cached = Peers::load_cached(&path, &info_hash);
+        assert_eq!(cached.to_string(), "127.0.0.1:6881\n[2001:db8::1]:6882\n");
+        assert_eq!(Peers::load_cached(&path, &other).len(), 1);
+
+        // Entries that aren't addresses are skipped, a corrupt cache is empty.
+        std::fs::write(
+            &path,
+            format!(r#"{{"{}": ["nope", "127.0.0.1:1"]}}"#, info_hash.to_hex()),
+        )?;
+        assert_eq!(
+            Peers::load_cached(&path, &info_hash).to_string(),
+            "127.0.0.1:1\n"
+        );
+        std::fs::write(&path, "not json")?;
+        assert_eq!(Peers::load_cached(&path, &info_hash).len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_url_keeps_existing_query() -> Result<(), Box<dyn std::error::Error>> {
+        let client = Client::new(PeerID::new(), ClientOptions::default())?;
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        let req = torrent::PeerRequest {
+            url: url::Url::parse("http://tracker.example/announce?passkey=abc")?,
+            info_hash: &info_hash,
+            left: 42,
+            uploaded: 0,
+            downloaded: 7,
+            announce_list: Vec::new(),
+            event: None,
+            tracker_id: None,
+        };
+
+        let url = client.announce_url(&req, &req.url);
+        assert_eq!(url.matches('?').count(), 1);
+        assert!(url.starts_with("http://tracker.example/announce?passkey=abc&peer_id="));
+        assert!(url.contains("&downloaded=7&left=42&"));
+        assert!(url.ends_with(&format!("&info_hash={}", "%AB".repeat(20))));
+
+        let req = torrent::PeerRequest {
+            url: url::Url::parse("http://tracker.example/announce")?,
+            ..req
+        };
+        assert!(client
+            .announce_url(&req, &req.url)
+            .starts_with("http://tracker.example/announce?peer_id="));
+
+        let req = torrent::PeerRequest {
+            event: Some(AnnounceEvent::Stopped),
+            ..req
+        };
+        assert!(client
+            .announce_url(&req, &req.url)
+            .contains("&compact=1&event=stopped&info_hash="));
+
+        // Tracker ids may be binary, like the info hash.
+        let req = torrent::PeerRequest {
+            tracker_id: Some(b"\xffid".to_vec()),
+            ..req
+        };
+        assert!(client
+            .announce_url(&req, &req.url)
+            .ends_with(&format!("&info_hash={}&trackerid=%FFid", "%AB".repeat(20))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extra_announce_params() -> Result<(), Box<dyn std::error::Error>> {
+        let options = ClientOptions {
+            extra_params: BTreeMap::from([
+                ("supportcrypto".to_string(), "1".to_string()),
+                ("passkey".to_string(), "a b&c".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let client = Client::new(PeerID::new(), options)?;
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        let req = torrent::PeerRequest {
+            url: url::Url::parse("http://tracker.example/announce")?,
+            info_hash: &info_hash,
+            left: 42,
+            uploaded: 0,
+            downloaded: 7,
+            announce_list: Vec::new(),
+            event: None,
+            tracker_id: None,
+        };
+
+        // Encoded and sorted by key, after the standard parameters.
+        let url = client.announce_url(&req, &req.url);
+        assert!(
+            url.contains("&compact=1&passkey=a+b%26c&supportcrypto=1&info_hash="),
+            "{}",
+            url
+        );
+
+        let options = ClientOptions {
+            extra_params: BTreeMap::from([("left".to_string(), "0".to_string())]),
+            ..Default::default()
+        };
+        assert!(Client::new(PeerID::new(), options).is_err());
+
+        assert_eq!(
+            parse_query_param("passkey=abc=="),
+            Ok(("passkey".to_string(), "abc==".to_string()))
+        );
+        assert!(parse_query_param("passkey").is_err());
+        assert!(parse_query_param("=abc").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_bencode_body() {
+        let ok = reqwest::StatusCode::OK;
+        assert!(check_bencode_body(ok, Some("text/plain"), b"d8:intervali60ee").is_ok());
+        assert!(check_bencode_body(ok, None, b"d8:intervali60ee").is_ok());
+
+        let err = check_bencode_body(ok, None, b"<!DOCTYPE html><html>").unwrap_err();
+        assert!(err.to_string().contains("non-bencode response"));
+        let err = check_bencode_body(ok, Some("text/html; charset=UTF-8"), b"d1:ae").unwrap_err();
+        assert!(err.to_string().contains("text/html"));
+        assert!(check_bencode_body(ok, None, b"").is_err());
+
+        let page = format!(
+            "<html><title>502 Bad Gateway</title>{}</html>",
+            "x".repeat(500)
+        );
+        let err = check_bencode_body(ok, None, page.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("error page"));
+        assert!(err.to_string().contains("502 Bad Gateway"));
+        // Only a snippet of the body ends up in the error.
+        assert!(err.to_string().len() < page.len());
+    }
+
+    #[test]
+    fn test_decode_peers6_only() -> Result<(), Box<dyn std::error::Error>> {
+        let mut bencoded = b"d8:intervali60e6:peers618:".to_vec();
+        bencoded.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        bencoded.extend_from_slice(&[0; 11]);
+        bencoded.extend_from_slice(&[1, 0x1a, 0xe1]);
+        bencoded.push(b'e');
+
+        let response: TrackerResponse = serde_bencode::from_bytes(&bencoded)?;
+        assert!(response.peers.is_none());
+
+        let peers = Peers::from_tracker_response(response, false)?;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(
+            peers.iter().next().unwrap().to_string(),
+            "[2001:db8::1]:6881"
+        );
+
+        let empty: TrackerResponse = serde_bencode::from_bytes(b"d8:intervali60ee")?;
+        assert!(Peers::from_tracker_response(empty, false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peers_to_json() -> Result<(), Box<dyn std::error::Error>> {
+        let peers = Peers::from(vec![
+            "[2001:db8::1]:6881".parse::<Peer>()?,
+            "peer.example:6882".parse::<Peer>()?,
+        ]);
+        assert_eq!(
+            peers.to_json()?,
+            r#"[{"ip":"2001:db8::1","port":6881},{"ip":"peer.example","port":6882}]"#
+        );
+
+        Ok(())
+    }
+
+    /// Answers one announce with a single peer and returns the request head.
+    async fn serve_announce(listener: tokio::net::TcpListener) -> String {
+        serve_announce_with(
+            listener,
+            b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+        )
+        .await
+    }
+
+    async fn serve_announce_with(listener: tokio::net::TcpListener, body: &[u8]) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let mut resp =
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        resp.extend_from_slice(body);
+        stream.write_all(&resp).await.unwrap();
+
+        String::from_utf8(head).unwrap().to_ascii_lowercase()
+    }
+
+    #[tokio::test]
+    async fn test_find_peers_from_mock_tracker() -> Result<(), Box<dyn std::error::Error>> {
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        let client = Client::new(PeerID::new(), ClientOptions::default())?;
+        let announce = |body: &'static [u8]| {
+            let client = client.clone();
+            let info_hash = info_hash.clone();
+            async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+                let url = Url::parse(&format!("http://{}/announce", listener.local_addr()?))?;
+                let server = tokio::spawn(serve_announce_with(listener, body));
+                let response = client
+                    .find_peers(torrent::PeerRequest {
+                        url,
+                        info_hash: &info_hash,
+                        left: 42,
+                        uploaded: 0,
+                        downloaded: 0,
+                        announce_list: Vec::new(),
+                        event: None,
+                        tracker_id: None,
+                    })
+                    .await;
+                let head = server.await?;
+                assert!(head.starts_with("get /announce?"), "{}", head);
+                assert!(head.contains("info_hash=%ab%ab"), "{}", head);
+                anyhow::Ok(response.and_then(|response| client.peers(response)))
+            }
+        };
+
+        let body = b"d8:intervali60e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2e";
+        let peers = announce(body).await??;
+        let addrs: Vec<_> = peers.iter().map(|p| p.to_string()).collect();
+        assert_eq!(addrs, vec!["127.0.0.1:6881", "10.0.0.2:6882"]);
+
+        let Err(err) = announce(b"d14:failure reason17:torrent not founde").await? else {
+            panic!("expected the announce to fail");
+        };
+        assert!(err.to_string().contains("torrent not found"), "{:#}", err);
+
+        let peers = announce(b"d8:intervali60e5:peers0:e").await??;
+        assert_eq!(peers.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dump_unparseable_response() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("response");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/announce", listener.local_addr()?))?;
+        let server = tokio::spawn(serve_announce_with(listener, b"d8:intervali60e5:pe"));
+
+        let client = Client::new(
+            PeerID::new(),
+            ClientOptions {
+                dump_response: Some(path.clone()),
+                ..Default::default()
+            },
+        )?;
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        let res = client
+            .find_peers(torrent::PeerRequest {
+                url,
+                info_hash: &info_hash,
+                left: 42,
+                uploaded: 0,
+                downloaded: 0,
+                announce_list: Vec::new(),
+                event: None,
+                tracker_id: None,
+            })
+            .await;
+        server.await?;
+
+        assert!(res.is_err());
+        assert_eq!(std::fs::read(&path)?, b"d8:intervali60e5:pe");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() -> Result<(), Box<dyn std::error::Error>> {
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        for (user_agent, want) in [
+            (None, DEFAULT_USER_AGENT),
+            (Some(String::from("Allowed/1.0")), "Allowed/1.0"),
+        ] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let url = url::Url::parse(&format!("http://{}/announce", listener.local_addr()?))?;
+            let server = tokio::spawn(serve_announce(listener));
+
+            let client = Client::new(
+                PeerID::new(),
+                ClientOptions {
+                    user_agent,
+                    ..Default::default()
+                },
+            )?;
+            let response = client
+                .find_peers(torrent::PeerRequest {
+                    url,
+                    info_hash: &info_hash,
+                    left: 42,
+                    uploaded: 0,
+                    downloaded: 0,
+                    announce_list: Vec::new(),
+                    event: None,
+                    tracker_id: None,
+                })
+                .await?;
+            let peers = client.peers(response)?;
+            assert_eq!(peers.len(), 1);
+
+            let head = server.await?;
+            assert!(
+                head.contains(&format!("user-agent: {}\r\n", want.to_ascii_lowercase())),
+                "{}",
+                head
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_url() -> Result<(), Box<dyn std::error::Error>> {
+        let url = "http://old.example/announce?peer_id=x&info_hash=%AB%CD";
+        assert_eq!(
+            redirect_url(url, "http://new.example/a")?,
+            "http://new.example/a?peer_id=x&info_hash=%AB%CD"
+        );
+        assert_eq!(
+            redirect_url(url, "/moved?key=1")?,
+            "http://old.example/moved?key=1&peer_id=x&info_hash=%AB%CD"
+        );
+        // Locations that kept the parameters are used as they are.
+        assert_eq!(
+            redirect_url(url, "http://new.example/announce?info_hash=%AB%CD")?,
+            "http://new.example/announce?info_hash=%AB%CD"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_peers_follows_redirect() -> Result<(), Box<dyn std::error::Error>> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let moved = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let new_url = format!("http://{}/announce", moved.local_addr()?);
+        let new_tracker = tokio::spawn(serve_announce(moved));
+        let old = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/announce", old.local_addr()?))?;
+        tokio::spawn(async move {
+            let (mut stream, _) = old.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            let resp = format!(
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                new_url
+            );
+            stream.write_all(resp.as_bytes()).await.unwrap();
+        });
+
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        let client = Client::new(PeerID::new(), ClientOptions::default())?;
+        let response = client
+            .find_peers(torrent::PeerRequest {
+                url,
+                info_hash: &info_hash,
+                left: 42,
+                uploaded: 0,
+                downloaded: 0,
+                announce_list: Vec::new(),
+                event: None,
+                tracker_id: None,
+            })
+            .await?;
+        let peers = client.peers(response)?;
+        assert_eq!(peers.len(), 1);
+
+        let head = new_tracker.await?;
+        assert!(head.starts_with("get /announce?"), "{}", head);
+        assert!(head.contains("info_hash=%ab%ab"), "{}", head);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unresolvable_tracker_falls_back() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let fallback = Url::parse(&format!("http://{}/announce", listener.local_addr()?))?;
+        let server = tokio::spawn(serve_announce(listener));
+        // .invalid never resolves (RFC 6761).
+        let unresolvable = Url::parse("http://tracker.invalid/announce")?;
+
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        let client = Client::new(PeerID::new(), ClientOptions::default())?;
+        let req = |announce_list| torrent::PeerRequest {
+            url: unresolvable.clone(),
+            info_hash: &info_hash,
+            left: 42,
+            uploaded: 0,
+            downloaded: 0,
+            announce_list,
+            event: None,
+            tracker_id: None,
+        };
+
+        let Err(err) = client.find_peers(req(Vec::new())).await else {
+            panic!("expected the announce to fail");
+        };
+        assert!(err.is::<Unresolvable>(), "{:#}", err);
+        assert!(
+            err.to_string()
+                .starts_with("could not resolve tracker tracker.invalid: "),
+            "{}",
+            err
+        );
+
+        // A tracker refusing the connection resolved fine.
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+        let dead = Url::parse(&format!("http://{}/announce", dead))?;
+        let Err(err) = client.find_peers(req(vec![vec![dead]])).await else {
+            panic!("expected the announce to fail");
+        };
+        assert!(!err.is::<Unresolvable>(), "{:#}", err);
+
+        let response = client
+            .find_peers(req(vec![vec![unresolvable.clone()], vec![fallback]]))
+            .await?;
+        let peers = client.peers(response)?;
+        assert_eq!(peers.len(), 1);
+        server.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_peers_merges_trackers() -> Result<(), Box<dyn std::error::Error>> {
+        let first = MockTracker::new(
+            b"d8:completei3e8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe115:warning message4:slowe",
+        )
+        .spawn()
+        .await;
+        let second = MockTracker::new(
+            b"d8:completei5e8:intervali90e5:peers12:\x7f\x00\x00\x02\x1a\xe1\x7f\x00\x00\x01\x1a\xe1e",
+        );
+        let second_requests = second.requests();
+        let second = second.spawn().await;
+        // A dead tracker, even in the first tier, doesn't fail the announce.
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+        let dead = Url::parse(&format!("http://{}/announce", dead))?;
+
+        let info_hash = torrent::Hash::new([0xab; 20]);
+        let client = Client::new(PeerID::new(), ClientOptions::default())?;
+        let response = client
+            .find_peers(torrent::PeerRequest {
+                url: dead.clone(),
+                info_hash: &info_hash,
+                left: 42,
+                uploaded: 0,
+                downloaded: 0,
+                announce_list: vec![vec![dead], vec![first], vec![second]],
+                event: None,
+                tracker_id: None,
+            })
+            .await?;
+        // Later tiers are announced to as well.
+        assert_eq!(second_requests.lock().unwrap().len(), 1);
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+        assert_eq!(response.interval, Some(90));
+
+        let peers = client.peers(response)?;
+        let addrs: Vec<_> = peers.iter().map(|p| p.to_string()).collect();
+        assert_eq!(addrs, vec!["127.0.0.1:6881", "127.0.0.2:6881"]);
+        assert_eq!(peers.complete(), Some(5));
+
+        Ok(())
+    }
 }
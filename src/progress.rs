@@ -0,0 +1,168 @@
+use core::fmt;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Emitted by the download engine every time a piece was written.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Index of the piece that was just written.
+    pub piece: usize,
+    pub pieces_done: usize,
+    pub pieces_total: usize,
+    pub bytes_done: usize,
+    pub bytes_total: usize,
+    pub eta: Eta,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} pieces, {}/{} bytes, ETA: {}",
+            self.pieces_done, self.pieces_total, self.bytes_done, self.bytes_total, self.eta
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Eta {
+    /// Not enough data yet to estimate the throughput.
+    Calculating,
+    Remaining(Duration),
+}
+
+impl fmt::Display for Eta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Eta::Calculating => write!(f, "calculating"),
+            Eta::Remaining(d) => write!(f, "{}s", d.as_secs()),
+        }
+    }
+}
+
+/// Rolling average of the download rate over the last `window`.
+pub struct Throughput {
+    window: Duration,
+    started: Instant,
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl Throughput {
+    pub fn new(window: Duration, started: Instant) -> Self {
+        Self {
+            window,
+            started,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, at: Instant, bytes: usize) {
+        self.samples.push_back((at, bytes));
+        while let Some((oldest, _)) = self.samples.front() {
+            if at.duration_since(*oldest) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second over the window, None as long as nothing was downloaded within it.
+    pub fn rate(&self, now: Instant) -> Option<f64> {
+        let bytes: usize = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= self.window)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        // Early on the window is not filled yet, only average over the time we actually ran.
+        let elapsed = now.duration_since(self.started).min(self.window);
+        if bytes == 0 || elapsed.is_zero() {
+            return None;
+        }
+
+        Some(bytes as f64 / elapsed.as_secs_f64())
+    }
+
+    pub fn eta(&self, now: Instant, remaining_bytes: usize) -> Eta {
+        match self.rate(now) {
+            Some(rate) => Eta::Remaining(Duration::from_secs_f64(remaining_bytes as f64 / rate)),
+            None => Eta::Calculating,
+        }
+    }
+}
+
+/// Which pieces are written, drawn as a line of `#` (done), `+` (partly done) and `.` cells.
+pub struct PieceMap {
+    done: Vec<bool>,
+}
+
+impl PieceMap {
+    pub fn new(pieces_total: usize) -> Self {
+        Self {
+            done: vec![false; pieces_total],
+        }
+    }
+
+    pub fn set_done(&mut self, piece: usize) {
+        if let Some(done) = self.done.get_mut(piece) {
+            *done = true;
+        }
+    }
+
+    /// One cell per piece, or `width` cells covering several pieces each if there are more.
+    pub fn render(&self, width: usize) -> String {
+        let total = self.done.len();
+        let cells = width.min(total);
+        (0..cells)
+            .map(|cell| {
+                let pieces = &self.done[cell * total / cells..(cell + 1) * total / cells];
+                match pieces.iter().filter(|done| **done).count() {
+                    0 => '.',
+                    n if n == pieces.len() => '#',
+                    _ => '+',
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput_rolling_average() {
+        let start = Instant::now();
+        let mut tp = Throughput::new(Duration::from_secs(10), start);
+        assert_eq!(tp.rate(start), None);
+        assert_eq!(tp.eta(start, 100), Eta::Calculating);
+
+        // Window not filled yet: 1000 bytes in 2 seconds.
+        tp.record(start + Duration::from_secs(1), 400);
+        tp.record(start + Duration::from_secs(2), 600);
+        assert_eq!(tp.rate(start + Duration::from_secs(2)), Some(500.0));
+
+        // The first two samples fell out of the window: 2000 bytes over 10 seconds.
+        tp.record(start + Duration::from_secs(15), 2000);
+        let now = start + Duration::from_secs(15);
+        assert_eq!(tp.rate(now), Some(200.0));
+        assert_eq!(tp.eta(now, 1000), Eta::Remaining(Duration::from_secs(5)));
+
+        // Nothing within the window anymore.
+        assert_eq!(tp.rate(start + Duration::from_secs(30)), None);
+    }
+
+    #[test]
+    fn test_piece_map() {
+        let mut map = PieceMap::new(10);
+        for piece in [0, 1, 2, 3, 6, 9] {
+            map.set_done(piece);
+        }
+        assert_eq!(map.render(20), "####..#..#");
+        // Two pieces per cell.
+        assert_eq!(map.render(5), "##.++");
+        // Cells cover 3, 3 and 4 pieces.
+        assert_eq!(map.render(3), "#++");
+        assert_eq!(PieceMap::new(0).render(20), "");
+    }
+}
@@ -0,0 +1,782 @@
+use core::fmt;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use log::{debug, warn};
+use tokio::sync::Notify;
+
+use crate::bitfield::Bitfield;
+use crate::tracker::{FullPiece, Piece, RequestPayloadGen};
+use crate::verify::verify_piece;
+
+/// A single block of a piece, as sent in a Request message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockRequest {
+    pub(crate) piece_idx: usize,
+    pub(crate) begin: usize,
+    pub(crate) length: usize,
+}
+
+pub(crate) enum NextBlock {
+    Block(BlockRequest),
+    /// All remaining blocks are handed out to other workers (or the write side is behind), one
+    /// may come back if a worker fails.
+    Wait,
+    /// Every piece was downloaded.
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Pending,
+    Requested,
+    Done,
+}
+
+struct Block {
+    begin: usize,
+    length: usize,
+    state: BlockState,
+}
+
+struct ActivePiece {
+    piece: Piece,
+    data: Vec<u8>,
+    blocks: Vec<Block>,
+    // Peers that delivered blocks of the piece.
+    peers: Vec<String>,
+}
+
+impl ActivePiece {
+    fn new(piece: Piece) -> Self {
+        let mut gen = RequestPayloadGen::new(piece.len, piece.idx);
+        let mut blocks = Vec::new();
+        while let Some(req) = gen.next() {
+            blocks.push(Block {
+                begin: req.begin as usize,
+                length: req.length as usize,
+                state: BlockState::Pending,
+            });
+        }
+
+        Self {
+            data: vec![0; piece.len],
+            piece,
+            blocks,
+            peers: Vec::new(),
+        }
+    }
+
+    fn request_pending(&mut self) -> Option<BlockRequest> {
+        let block = self
+            .blocks
+            .iter_mut()
+            .find(|b| b.state == BlockState::Pending)?;
+        block.state = BlockState::Requested;
+
+        Some(BlockRequest {
+            piece_idx: self.piece.idx,
+            begin: block.begin,
+            length: block.length,
+        })
+    }
+
+    fn is_complete(&self) -> bool {
+        self.blocks.iter().all(|b| b.state == BlockState::Done)
+    }
+}
+
+struct State {
+    // Pieces no block was handed out for yet, in download order.
+    queued: VecDeque<Piece>,
+    // Pieces being assembled, by piece index.
+    active: BTreeMap<usize, ActivePiece>,
+    // Pieces that were started but not yet written, see `piece_written`.
+    outstanding: usize,
+    max_outstanding: usize,
+    // Cap on `active`, see `with_max_active`.
+    max_active: usize,
+    // Pieces handed out again, and the peers that failed each piece.
+    retries: usize,
+    failed_by: BTreeMap<usize, HashSet<String>>,
+    // Peers that delivered blocks of a piece whose hash matched.
+    verified_by: BTreeSet<String>,
+    // Set once `max_retries` is exceeded, no blocks are handed out after that.
+    exhausted: Option<RetriesExhausted>,
+    // Speeds of the workers blocked in `wait_for_block`, by registration, see `Waiting`.
+    waiting: BTreeMap<usize, f64>,
+    next_waiter: usize,
+}
+
+impl State {
+    fn hand_out(&mut self, avoid: &[usize], have: Option<&Bitfield>) -> Option<BlockRequest> {
+        let can_take = |idx: usize| !avoid.contains(&idx) && have.is_none_or(|h| h.has(idx));
+        // Finish started pieces first, so their buffers can be freed soon.
+        if let Some(req) = self
+            .active
+            .values_mut()
+            .filter(|p| can_take(p.piece.idx))
+            .find_map(|p| p.request_pending())
+        {
+            return Some(req);
+        }
+
+        if self.outstanding >= self.max_outstanding || self.active.len() >= self.max_active {
+            return None;
+        }
+        let pos = self.queued.iter().position(|p| can_take(p.idx))?;
+        let piece = self.queued.remove(pos)?;
+        debug!("Starting piece {}", piece);
+        let mut active = ActivePiece::new(piece);
+        let req = active.request_pending();
+        self.active.insert(active.piece.idx, active);
+        self.outstanding += 1;
+
+        req
+    }
+
+    fn retry(&mut self, piece_idx: usize, peer: &str, max_retries: Option<usize>) {
+        self.retries += 1;
+        self.failed_by
+            .entry(piece_idx)
+            .or_default()
+            .insert(peer.to_string());
+        if self.exhausted.is_some() || max_retries.is_none_or(|max| self.retries <= max) {
+            return;
+        }
+
+        let missing = self
+            .active
+            .keys()
+            .copied()
+            .chain(self.queued.iter().map(|p| p.idx));
+        let mut pieces: Vec<_> = missing
+            .map(|idx| (idx, self.failed_by.get(&idx).map_or(0, HashSet::len)))
+            .collect();
+        pieces.sort_unstable();
+        self.exhausted = Some(RetriesExhausted {
+            retries: self.retries,
+            pieces,
+        });
+    }
+}
+
+/// More than `DownloadConfig::max_retries` pieces had to be handed out again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetriesExhausted {
+    pub retries: usize,
+    /// Indices of the pieces never downloaded, with the number of distinct peers that failed
+    /// them.
+    pub pieces: Vec<(usize, usize)>,
+}
+
+impl fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gave up after {} retries, missing pieces:", self.retries)?;
+        for (idx, peers) in &self.pieces {
+            write!(f, " {} (tried by {} peers)", idx, peers)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RetriesExhausted {}
+
+/// Hands out individual blocks to whichever worker asks next and assembles the pieces from the
+/// returned blocks. Blocks of one piece can be downloaded by several peers at once.
+pub(crate) struct BlockScheduler {
+    state: Mutex<State>,
+    changed: Notify,
+    verify: bool,
+    max_retries: Option<usize>,
+}
+
+impl BlockScheduler {
+    /// At most `max_outstanding` pieces are started but not yet reported as written.
+    pub(crate) fn new(pieces: Vec<Piece>, max_outstanding: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                queued: pieces.into(),
+                active: BTreeMap::new(),
+                outstanding: 0,
+                max_outstanding,
+                max_active: usize::MAX,
+                retries: 0,
+                failed_by: BTreeMap::new(),
+                verified_by: BTreeSet::new(),
+                exhausted: None,
+                waiting: BTreeMap::new(),
+                next_waiter: 0,
+            }),
+            changed: Notify::new(),
+            verify: true,
+            max_retries: None,
+        }
+    }
+
+    /// Keeps at most `max_active` pieces partially assembled, each holding a buffer of its
+    /// length. A new piece is only started once one of them is complete.
+    pub(crate) fn with_max_active(self, max_active: usize) -> Self {
+        self.state
+            .lock()
+            .expect("scheduler lock poisoned")
+            .max_active = max_active.max(1);
+        self
+    }
+
+    /// Stops handing out blocks once pieces were handed out again more than `max_retries`
+    /// times in total, after a hash mismatch or a failed or too slow worker. The download then
+    /// fails with `retries_exhausted`.
+    pub(crate) fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Accepts completed pieces without comparing their hash. Only meant for benchmarking
+    /// downloads from trusted peers.
+    pub(crate) fn without_verification(mut self) -> Self {
+        self.verify = false;
+        self
+    }
+
+    /// Blocks of the started pieces in `avoid` are left to others, e.g. pieces a peer was too
+    /// slow for. Only pieces the peer `have`s are handed out, None if it has all of them. The
+    /// worker's peer delivered `speed` bytes per second so far, as long as a faster worker waits
+    /// for blocks it is fed first and this one has to wait.
+    pub(crate) fn next_block(
+        &self,
+        avoid: &[usize],
+        have: Option<&Bitfield>,
+        speed: f64,
+    ) -> NextBlock {
+        let mut state = self.state.lock().expect("scheduler lock poisoned");
+        if state.exhausted.is_some() {
+            return NextBlock::Finished;
+        }
+
+        if !state.waiting.values().any(|&waiting| waiting > speed) {
+            if let Some(req) = state.hand_out(avoid, have) {
+                return NextBlock::Block(req);
+            }
+        }
+
+        if state.queued.is_empty() && state.active.is_empty() {
+            return NextBlock::Finished;
+        }
+
+        NextBlock::Wait
+    }
+
+    /// Like `next_block`, but waits until a block becomes available. Returns None once every
+    /// piece was downloaded.
+    pub(crate) async fn wait_for_block(
+        &self,
+        have: Option<&Bitfield>,
+        speed: f64,
+    ) -> Option<BlockRequest> {
+        self.wait_for_block_avoiding(&[], have, speed).await
+    }
+
+    /// Like `wait_for_block`, but leaves the started pieces in `avoid` to others, see
+    /// `next_block`.
+    pub(crate) async fn wait_for_block_avoiding(
+        &self,
+        avoid: &[usize],
+        have: Option<&Bitfield>,
+        speed: f64,
+    ) -> Option<BlockRequest> {
+        // Slower workers leave blocks to this one while it waits. Not when avoiding pieces or
+        // lacking some, it might not take the blocks left to it.
+        let takes_any = avoid.is_empty() && have.is_none_or(|h| h.count() == h.len());
+        let _waiting = takes_any.then(|| Waiting::register(self, speed));
+        loop {
+            // Register interest before looking at the state, so no wakeup is lost in between.
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match self.next_block(avoid, have, speed) {
+                NextBlock::Block(req) => return Some(req),
+                NextBlock::Finished => return None,
+                NextBlock::Wait => notified.await,
+            }
+        }
+    }
+
+    /// Waits until every piece was downloaded or the retries are exhausted, so workers still
+    /// connecting to their peer can give up.
+    pub(crate) async fn finished(&self) {
+        loop {
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.is_finished() || self.retries_exhausted().is_some() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Stores a block received from `peer`. Returns the piece once all its blocks arrived and
+    /// its hash matches. On a hash mismatch the piece is queued again and an error returned.
+    pub(crate) fn complete_block(
+        &self,
+        req: &BlockRequest,
+        block: &[u8],
+        peer: &str,
+    ) -> Result<Option<FullPiece>> {
+        let mut state = self.state.lock().expect("scheduler lock poisoned");
+
+        let Some(active) = state.active.get_mut(&req.piece_idx) else {
+            debug!(
+                "Dropping block for piece {} that is no longer active",
+                req.piece_idx
+            );
+            return Ok(None);
+        };
+        let Some(b) = active.blocks.iter_mut().find(|b| b.begin == req.begin) else {
+            bail!(
+                "no block at offset {} in piece {}",
+                req.begin,
+                req.piece_idx
+            );
+        };
+        if b.state == BlockState::Done {
+            return Ok(None);
+        }
+        if block.len() > b.length {
+            bail!(
+                "peer sent more data than requested for piece {}: {} bytes for block at offset {}, requested {}",
+                req.piece_idx,
+                block.len(),
+                b.begin,
+                b.length
+            );
+        }
+        if block.len() != b.length {
+            bail!(
+                "received {} bytes for block at offset {} in piece {}, expected {}",
+                block.len(),
+                b.begin,
+                req.piece_idx,
+                b.length
+            );
+        }
+        b.state = BlockState::Done;
+        active.data[req.begin..req.begin + block.len()].copy_from_slice(block);
+        if !active.peers.iter().any(|p| p == peer) {
+            active.peers.push(peer.to_string());
+        }
+
+        if !active.is_complete() {
+            return Ok(None);
+        }
+
+        let active = state
+            .active
+            .remove(&req.piece_idx)
+            .expect("piece is active");
+        // Waiters may be finished now.
+        self.changed.notify_waiters();
+
+        if !self.verify {
+            debug!("Skipping hash check of piece {}", active.piece.idx);
+            return Ok(Some(FullPiece {
+                data: active.data,
+                piece: active.piece,
+            }));
+        }
+
+        if let Err(e) = verify_piece(&active.data, &active.piece.hash) {
+            warn!(
+                "Hash mismatch for piece {}, queueing it again",
+                active.piece.idx
+            );
+            let idx = active.piece.idx;
+            state.queued.push_front(active.piece);
+            state.outstanding -= 1;
+            state.retry(idx, peer, self.max_retries);
+            return Err(e);
+        }
+
+        debug!(
+            "Download of piece with idx {} was successful",
+            active.piece.idx
+        );
+        state.verified_by.extend(active.peers);
+
+        Ok(Some(FullPiece {
+            data: active.data,
+            piece: active.piece,
+        }))
+    }
+
+    /// Hands blocks requested by `peer`, a failed worker, out again.
+    pub(crate) fn release(&self, reqs: &[BlockRequest], peer: &str) {
+        let mut state = self.state.lock().expect("scheduler lock poisoned");
+        let mut retried = Vec::new();
+        for req in reqs {
+            if let Some(b) = state
+                .active
+                .get_mut(&req.piece_idx)
+                .and_then(|p| p.blocks.iter_mut().find(|b| b.begin == req.begin))
+            {
+                if b.state == BlockState::Requested {
+                    b.state = BlockState::Pending;
+                    if !retried.contains(&req.piece_idx) {
+                        retried.push(req.piece_idx);
+                    }
+                }
+            }
+        }
+        for idx in retried {
+            state.retry(idx, peer, self.max_retries);
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Whether every piece was downloaded.
+    pub(crate) fn is_finished(&self) -> bool {
+        let state = self.state.lock().expect("scheduler lock poisoned");
+        state.queued.is_empty() && state.active.is_empty()
+    }
+
+    /// Set once more retries than allowed were needed, see `with_max_retries`.
+    pub(crate) fn retries_exhausted(&self) -> Option<RetriesExhausted> {
+        let state = self.state.lock().expect("scheduler lock poisoned");
+        state.exhausted.clone()
+    }
+
+    /// Peers that delivered blocks of a piece whose hash matched, sorted. Empty without
+    /// verification.
+    pub(crate) fn verified_peers(&self) -> Vec<String> {
+        let state = self.state.lock().expect("scheduler lock poisoned");
+        state.verified_by.iter().cloned().collect()
+    }
+
+    /// Frees the slot of a downloaded piece once it was persisted.
+    pub(crate) fn piece_written(&self) {
+        let mut state = self.state.lock().expect("scheduler lock poisoned");
+        state.outstanding = state.outstanding.saturating_sub(1);
+        self.changed.notify_waiters();
+    }
+}
+
+/// A worker waiting for blocks, registered with its speed until dropped.
+struct Waiting<'a> {
+    scheduler: &'a BlockScheduler,
+    id: usize,
+}
+
+impl<'a> Waiting<'a> {
+    fn register(scheduler: &'a BlockScheduler, speed: f64) -> Self {
+        let mut state = scheduler.state.lock().expect("scheduler lock poisoned");
+        let id = state.next_waiter;
+        state.next_waiter += 1;
+        state.waiting.insert(id, speed);
+
+        Self { scheduler, id }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .scheduler
+            .state
+            .lock()
+            .expect("scheduler lock poisoned");
+        state.waiting.remove(&self.id);
+        // Slower workers left blocks to this one, they may take them now.
+        self.scheduler.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Hash;
+    use crate::tracker::BLOCK_SIZE;
+
+    fn piece(idx: usize, data: &[u8]) -> Piece {
+        Piece {
+            hash: Hash::hash(data),
+            idx,
+            len: data.len(),
+        }
+    }
+
+    fn expect_block(s: &BlockScheduler) -> BlockRequest {
+        match s.next_block(&[], None, 0.0) {
+            NextBlock::Block(req) => req,
+            NextBlock::Wait => panic!("expected block, got wait"),
+            NextBlock::Finished => panic!("expected block, got finished"),
+        }
+    }
+
+    #[test]
+    fn test_blocks_of_a_piece_are_shared() -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let s = BlockScheduler::new(vec![piece(0, &data)], 1);
+
+        // Two workers get the two blocks of the same piece.
+        let first = expect_block(&s);
+        let second = expect_block(&s);
+        assert_eq!((first.begin, second.begin), (0, BLOCK_SIZE));
+        assert_eq!(second.length, 100);
+        assert!(matches!(s.next_block(&[], None, 0.0), NextBlock::Wait));
+
+        assert!(s
+            .complete_block(&second, &data[BLOCK_SIZE..], "peer")?
+            .is_none());
+        let full = s
+            .complete_block(&first, &data[..BLOCK_SIZE], "peer")?
+            .expect("piece complete");
+        assert_eq!(full.data, data);
+        assert!(matches!(s.next_block(&[], None, 0.0), NextBlock::Finished));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_over_long_block_is_rejected() {
+        let s = BlockScheduler::new(vec![piece(0, &[1; 10])], 1);
+        let req = expect_block(&s);
+
+        let Err(err) = s.complete_block(&req, &[1; 11], "peer") else {
+            panic!("expected an error for the over-long block");
+        };
+        assert!(err
+            .to_string()
+            .contains("peer sent more data than requested for piece 0"));
+    }
+
+    #[test]
+    fn test_released_blocks_are_handed_out_again() {
+        let s = BlockScheduler::new(vec![piece(0, &[1; 10])], 1);
+        let req = expect_block(&s);
+        assert!(matches!(s.next_block(&[], None, 0.0), NextBlock::Wait));
+
+        s.release(&[req], "peer");
+        assert_eq!(expect_block(&s), req);
+    }
+
+    #[test]
+    fn test_avoided_pieces_are_left_to_others() {
+        let pieces = (0..2).map(|idx| piece(idx, &[idx as u8; 10])).collect();
+        let s = BlockScheduler::new(pieces, 2);
+        let slow = expect_block(&s);
+        s.release(&[slow], "peer");
+
+        // A new piece is started instead of the avoided one.
+        match s.next_block(&[0], None, 0.0) {
+            NextBlock::Block(req) => assert_eq!(req.piece_idx, 1),
+            _ => panic!("expected a block of piece 1"),
+        }
+        assert!(matches!(s.next_block(&[0], None, 0.0), NextBlock::Wait));
+        assert_eq!(expect_block(&s), slow);
+    }
+
+    #[test]
+    fn test_pieces_the_peer_lacks_are_left_to_others() {
+        let pieces = (0..2).map(|idx| piece(idx, &[idx as u8; 10])).collect();
+        let s = BlockScheduler::new(pieces, 2);
+        let mut have = Bitfield::new(2);
+        have.set(1);
+
+        match s.next_block(&[], Some(&have), 0.0) {
+            NextBlock::Block(req) => assert_eq!(req.piece_idx, 1),
+            _ => panic!("expected a block of piece 1"),
+        }
+        assert!(matches!(
+            s.next_block(&[], Some(&have), 0.0),
+            NextBlock::Wait
+        ));
+        assert_eq!(expect_block(&s).piece_idx, 0);
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let pieces = (0..3).map(|idx| piece(idx, &[idx as u8; 10])).collect();
+        let s = BlockScheduler::new(pieces, 3).with_max_retries(2);
+        let first = expect_block(&s);
+        s.complete_block(&first, &[0; 10], "a")
+            .unwrap()
+            .expect("piece complete");
+        let second = expect_block(&s);
+        s.release(&[second], "a");
+        let second = expect_block(&s);
+        s.release(&[second], "b");
+        assert!(s.retries_exhausted().is_none());
+
+        // A hash mismatch is a retry as well.
+        let second = expect_block(&s);
+        assert!(s.complete_block(&second, &[0; 10], "a").is_err());
+        assert!(matches!(s.next_block(&[], None, 0.0), NextBlock::Finished));
+        let exhausted = s.retries_exhausted().expect("budget exhausted");
+        assert_eq!(exhausted.pieces, vec![(1, 2), (2, 0)]);
+        assert_eq!(
+            exhausted.to_string(),
+            "gave up after 3 retries, missing pieces: 1 (tried by 2 peers) 2 (tried by 0 peers)"
+        );
+    }
+
+    #[test]
+    fn test_active_pieces_are_capped() {
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|_| rand::random::<u8>()).collect();
+        let pieces = (0..6).map(|idx| piece(idx, &data)).collect();
+        let s = BlockScheduler::new(pieces, 6).with_max_active(2);
+        let active = || s.state.lock().unwrap().active.len();
+
+        let mut requested = Vec::new();
+        let mut completed = 0;
+        loop {
+            match s.next_block(&[], None, 0.0) {
+                NextBlock::Block(req) => requested.push(req),
+                NextBlock::Wait => {
+                    // Complete the oldest request, like a peer answering in order.
+                    let req = requested.remove(0);
+                    let block = &data[req.begin..req.begin + req.length];
+                    if s.complete_block(&req, block, "peer").unwrap().is_some() {
+                        completed += 1;
+                        s.piece_written();
+                    }
+                }
+                NextBlock::Finished => break,
+            }
+            assert!(active() <= 2, "{} pieces active", active());
+        }
+        assert_eq!(completed, 6);
+    }
+
+    #[test]
+    fn test_hash_mismatch_requeues_piece() {
+        let s = BlockScheduler::new(vec![piece(0, &[1; 10])], 1);
+        let req = expect_block(&s);
+
+        assert!(s.complete_block(&req, &[2; 10], "peer").is_err());
+        assert_eq!(expect_block(&s), req);
+    }
+
+    #[test]
+    fn test_verified_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let s = BlockScheduler::new(vec![piece(0, &data)], 1);
+        let (first, second) = (expect_block(&s), expect_block(&s));
+
+        // Peers of a corrupt piece aren't recorded.
+        s.complete_block(&first, &data[..BLOCK_SIZE], "a")?;
+        assert!(s.complete_block(&second, &[0; 100], "b").is_err());
+        assert!(s.verified_peers().is_empty());
+
+        let (first, second) = (expect_block(&s), expect_block(&s));
+        s.complete_block(&first, &data[..BLOCK_SIZE], "c")?;
+        s.complete_block(&second, &data[BLOCK_SIZE..], "a")?;
+        assert_eq!(s.verified_peers(), vec!["a", "c"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_verification_accepts_any_data() -> Result<(), Box<dyn std::error::Error>> {
+        let s = BlockScheduler::new(vec![piece(0, &[1; 10])], 1).without_verification();
+        let req = expect_block(&s);
+
+        let full = s
+            .complete_block(&req, &[2; 10], "peer")?
+            .expect("piece complete");
+        assert_eq!(full.data, vec![2; 10]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_waits_for_written_pieces() {
+        let pieces = (0..3).map(|idx| piece(idx, &[idx as u8; 10])).collect();
+        let s = BlockScheduler::new(pieces, 2);
+
+        for idx in 0..2 {
+            let req = expect_block(&s);
+            assert_eq!(req.piece_idx, idx);
+            s.complete_block(&req, &[idx as u8; 10], "peer")
+                .unwrap()
+                .expect("piece complete");
+        }
+        // The third piece must not be started before a piece was written.
+        assert!(matches!(s.next_block(&[], None, 0.0), NextBlock::Wait));
+
+        s.piece_written();
+        assert_eq!(expect_block(&s).piece_idx, 2);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_block_wakes_up_on_release() {
+        let s = std::sync::Arc::new(BlockScheduler::new(vec![piece(0, &[1; 10])], 1));
+        let req = expect_block(&s);
+
+        let waiter = tokio::spawn({
+            let s = std::sync::Arc::clone(&s);
+            async move { s.wait_for_block(None, 0.0).await }
+        });
+        tokio::task::yield_now().await;
+        s.release(&[req], "peer");
+
+        assert_eq!(waiter.await.unwrap(), Some(req));
+    }
+
+    #[tokio::test]
+    async fn test_faster_waiting_workers_are_fed_first() {
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let s = std::sync::Arc::new(BlockScheduler::new(vec![piece(0, &data)], 1));
+        let first = expect_block(&s);
+        let second = expect_block(&s);
+
+        let fast = tokio::spawn({
+            let s = std::sync::Arc::clone(&s);
+            async move { s.wait_for_block(None, 1000.0).await }
+        });
+        tokio::task::yield_now().await;
+        s.release(&[first], "peer");
+
+        // The slow worker asks first, but the block is left to the fast one.
+        assert!(matches!(s.next_block(&[], None, 10.0), NextBlock::Wait));
+        assert_eq!(fast.await.unwrap(), Some(first));
+
+        // With nobody faster waiting, slow workers get blocks as well.
+        s.release(&[second], "peer");
+        match s.next_block(&[], None, 10.0) {
+            NextBlock::Block(req) => assert_eq!(req, second),
+            _ => panic!("expected the released block"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_block_finishes_with_last_piece() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let s = std::sync::Arc::new(BlockScheduler::new(vec![piece(0, &[1; 10])], 1));
+        let req = expect_block(&s);
+
+        // Idle workers sleep until there is work or nothing is left, they don't poll.
+        let waiter = tokio::spawn({
+            let s = std::sync::Arc::clone(&s);
+            async move { s.wait_for_block(None, 0.0).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        s.complete_block(&req, &[1; 10], "peer")?
+            .expect("piece complete");
+        let next = tokio::time::timeout(std::time::Duration::from_secs(5), waiter).await??;
+        assert_eq!(next, None);
+
+        Ok(())
+    }
+}
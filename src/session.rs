@@ -0,0 +1,479 @@
+//! A download's membership in the swarm, from the first announce to telling the tracker we left.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::cancel::CancelToken;
+use crate::peers::{self, AnnounceEvent};
+use crate::progress::Progress;
+use crate::stats::TransferTotals;
+use crate::torrent::{self, Torrent};
+use crate::tracker::{PeerFeed, PeerSender};
+
+// Trackers usually ask for an interval around 30 minutes.
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// For trackers without a `min interval`, so announcing after no peers were found doesn't hammer
+// them.
+const MIN_REANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+// Lower bound for a tracker's `min interval`, a tracker asking for 0 would get announced to in a
+// tight loop.
+const MIN_INTERVAL_FLOOR: Duration = Duration::from_secs(30);
+
+/// Announces a download to its trackers and keeps it supplied with peers while it runs.
+///
+/// Call `close` once the download is over, so the tracker is told we left the swarm. `Drop` can't
+/// announce, as that needs to await, it only stops the re-announces and cancels the download,
+/// which closes its connections.
+pub struct Session {
+    client: peers::Client,
+    torrent: Arc<Torrent>,
+    totals: TransferTotals,
+    announced: Arc<Announced>,
+    reannounce: JoinHandle<()>,
+    cancel: CancelToken,
+}
+
+impl Session {
+    /// Announces the `started` event and returns the session with the peers to download from.
+    /// With `cached` peers of an earlier run, the download starts with them right away and the
+    /// tracker's peers are added once it answered. Progress received on `progress_rx` is
+    /// reported on later announces, on top of `totals` from earlier sessions.
+    pub async fn start(
+        client: peers::Client,
+        torrent: Torrent,
+        totals: TransferTotals,
+        progress_rx: UnboundedReceiver<Progress>,
+        cached: peers::Peers,
+    ) -> Result<(Session, PeerFeed)> {
+        let announced = Arc::new(Announced::default());
+        let (peer_tx, feed, schedule) = if cached.len() == 0 {
+            let peers = announce_started(&client, &torrent, totals, &announced).await?;
+            let schedule = AnnounceSchedule::new(Instant::now(), Some(&peers));
+            let (peer_tx, feed) = PeerFeed::new(peers);
+            (peer_tx, feed, Some(schedule))
+        } else {
+            let (peer_tx, feed) = PeerFeed::new(cached);
+            (peer_tx, feed, None)
+        };
+
+        let torrent = Arc::new(torrent);
+        let reannounce = tokio::spawn(reannounce(
+            client.clone(),
+            Arc::clone(&torrent),
+            peer_tx,
+            totals,
+            progress_rx,
+            Arc::clone(&announced),
+            schedule,
+        ));
+        let session = Session {
+            client,
+            torrent,
+            totals,
+            announced,
+            reannounce,
+            cancel: CancelToken::new(),
+        };
+
+        Ok((session, feed))
+    }
+
+    /// Token to pass on to the download as `DownloadConfig::cancel`, so the session can stop it.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Stops a download still running and announces the `stopped` event with what was
+    /// downloaded.
+    pub async fn close(self) -> Result<()> {
+        self.reannounce.abort();
+        self.cancel.cancel();
+
+        let bytes_done = self.announced.bytes_done.load(Ordering::SeqCst);
+        let mut req = peer_request(&self.torrent, self.totals, bytes_done);
+        req.event = Some(AnnounceEvent::Stopped);
+        announce(&self.client, req, &self.announced).await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reannounce.abort();
+        self.cancel.cancel();
+    }
+}
+
+/// What the re-announce task shares with the `Session`.
+#[derive(Default)]
+struct Announced {
+    // Of the last progress received, reported on the next announce.
+    bytes_done: AtomicUsize,
+    // Handed out by the tracker, sent back on every later announce.
+    tracker_id: Mutex<Option<Vec<u8>>>,
+}
+
+/// When to announce next: after the tracker's `interval`, or sooner if the last announce found
+/// no peers, but never before its `min interval` passed.
+struct AnnounceSchedule {
+    last: Instant,
+    interval: Duration,
+    min_interval: Duration,
+    found_peers: bool,
+}
+
+impl AnnounceSchedule {
+    /// Starts with the announce at `at`, the defaults are used if it failed.
+    fn new(at: Instant, peers: Option<&peers::Peers>) -> Self {
+        let mut schedule = Self {
+            last: at,
+            interval: REANNOUNCE_INTERVAL,
+            min_interval: MIN_REANNOUNCE_INTERVAL,
+            found_peers: true,
+        };
+        schedule.announced(at, peers);
+        schedule
+    }
+
+    /// Records an announce at `at`. Failed announces keep the intervals of the last one and are
+    /// retried on the regular schedule.
+    fn announced(&mut self, at: Instant, peers: Option<&peers::Peers>) {
+        self.last = at;
+        self.found_peers = peers.is_none_or(|p| p.len() > 0);
+        if let Some(peers) = peers {
+            self.interval = peers.interval().unwrap_or(REANNOUNCE_INTERVAL);
+            self.min_interval = peers
+                .min_interval()
+                .unwrap_or(MIN_REANNOUNCE_INTERVAL)
+                .max(MIN_INTERVAL_FLOOR);
+        }
+    }
+
+    /// The next announce, as soon as allowed if the last one found no peers.
+    fn next(&self) -> Instant {
+        if self.found_peers {
+            self.last + self.interval.max(self.min_interval)
+        } else {
+            self.forced(self.last)
+        }
+    }
+
+    /// When an announce wanted at `at`, ahead of the schedule, may go out.
+    fn forced(&self, at: Instant) -> Instant {
+        at.max(self.last + self.min_interval)
+    }
+}
+
+/// Announces again as `schedule` asks and passes the peers on to the running download.
+///
+/// Without a schedule, the `started` event wasn't announced yet, so that is done first. Each
+/// announce reports `totals` plus the bytes downloaded so far, as received from `progress_rx`.
+async fn reannounce(
+    client: peers::Client,
+    torrent: Arc<Torrent>,
+    peer_tx: PeerSender,
+    totals: TransferTotals,
+    mut progress_rx: UnboundedReceiver<Progress>,
+    announced: Arc<Announced>,
+    schedule: Option<AnnounceSchedule>,
+) {
+    let mut schedule = match schedule {
+        Some(schedule) => schedule,
+        None => {
+            let at = Instant::now();
+            match announce_started(&client, &torrent, totals, &announced).await {
+                Ok(peers) => {
+                    let schedule = AnnounceSchedule::new(at, Some(&peers));
+                    if peer_tx.send(peers).is_err() {
+                        return;
+                    }
+                    schedule
+                }
+                Err(e) => {
+                    log::warn!("Announce failed, going on with the cached peers: {:#}", e);
+                    AnnounceSchedule::new(at, None)
+                }
+            }
+        }
+    };
+    let mut next = schedule.next();
+    // The download ran out of peers and waits for this announce.
+    let mut starved = false;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(next) => {}
+            // Kept up to date for the `stopped` announce of `Session::close`.
+            Some(progress) = progress_rx.recv() => {
+                announced.bytes_done.store(progress.bytes_done, Ordering::SeqCst);
+                continue;
+            }
+            _ = peer_tx.wanted() => {
+                starved = true;
+                next = schedule.forced(Instant::now());
+                continue;
+            }
+            // No more announces, but the last pieces may still be written.
+            _ = peer_tx.closed() => {
+                while let Some(progress) = progress_rx.recv().await {
+                    announced.bytes_done.store(progress.bytes_done, Ordering::SeqCst);
+                }
+                return;
+            }
+        }
+        let bytes_done = announced.bytes_done.load(Ordering::SeqCst);
+        let at = Instant::now();
+        let req = peer_request(&torrent, totals, bytes_done);
+        match announce(&client, req, &announced).await {
+            Ok(peers) => {
+                schedule.announced(at, Some(&peers));
+                next = schedule.next();
+                starved = false;
+                if peer_tx.send(peers).is_err() {
+                    return;
+                }
+            }
+            // Closing the feed fails a download without peers.
+            Err(e) if starved => {
+                log::warn!(
+                    "Re-announce failed, no peers left to download from: {:#}",
+                    e
+                );
+                return;
+            }
+            // The download goes on with the peers it has.
+            Err(e) => {
+                log::warn!("Re-announce failed: {:#}", e);
+                schedule.announced(at, None);
+                next = schedule.next();
+            }
+        }
+    }
+}
+
+async fn announce_started(
+    client: &peers::Client,
+    torrent: &Torrent,
+    totals: TransferTotals,
+    announced: &Announced,
+) -> Result<peers::Peers> {
+    let mut req = peer_request(torrent, totals, 0);
+    req.event = Some(AnnounceEvent::Started);
+    announce(client, req, announced).await
+}
+
+/// Announces `req` with the tracker id the tracker handed out earlier, if any, and keeps a new
+/// one. A warning of the tracker is logged.
+async fn announce(
+    client: &peers::Client,
+    mut req: torrent::PeerRequest<'_>,
+    announced: &Announced,
+) -> Result<peers::Peers> {
+    req.tracker_id = announced
+        .tracker_id
+        .lock()
+        .expect("tracker id lock poisoned")
+        .clone();
+    let response = client.find_peers(req).await?;
+    if let Some(warning) = &response.warning_message {
+        log::warn!("Tracker warning: {}", warning);
+    }
+    if let Some(id) = &response.tracker_id {
+        *announced
+            .tracker_id
+            .lock()
+            .expect("tracker id lock poisoned") = Some(id.clone());
+    }
+
+    client.peers(response)
+}
+
+fn peer_request(
+    torrent: &Torrent,
+    totals: TransferTotals,
+    bytes_done: usize,
+) -> torrent::PeerRequest<'_> {
+    let mut req = torrent.to_peer_request();
+    req.uploaded = totals.uploaded;
+    req.downloaded = totals.downloaded + bytes_done as u64;
+    req.left = req.left.saturating_sub(bytes_done as u64);
+    req
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::progress::Eta;
+    use crate::test_util::MockTracker;
+    use crate::torrent::TorrentFile;
+
+    fn sample_torrent() -> Result<Torrent> {
+        let torrent_file = TorrentFile::parse_from_file(&PathBuf::from("sample.torrent"), false)?;
+        Torrent::from_file_torrent(&torrent_file)
+    }
+
+    #[test]
+    fn test_peer_request_left() -> Result<()> {
+        let torrent = sample_torrent()?;
+        let totals = TransferTotals {
+            uploaded: 5,
+            downloaded: 100,
+        };
+
+        let req = peer_request(&torrent, totals, 0);
+        assert_eq!((req.left, req.downloaded), (92063, 100));
+        // One piece of 32768 bytes written.
+        let req = peer_request(&torrent, totals, 32768);
+        assert_eq!((req.left, req.downloaded), (92063 - 32768, 100 + 32768));
+        assert_eq!(req.uploaded, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_schedule_keeps_min_interval() {
+        let response = b"d8:intervali600e12:min intervali60e5:peers0:e";
+        let peers = peers::Peers::from_tracker_response(
+            serde_bencode::from_bytes(response).unwrap(),
+            false,
+        )
+        .unwrap();
+        let start = Instant::now();
+        let mut schedule = AnnounceSchedule::new(start, Some(&peers));
+
+        // No peers were found, but the min interval must pass before announcing again.
+        assert_eq!(schedule.next(), start + Duration::from_secs(60));
+        let early = start + Duration::from_secs(10);
+        assert_eq!(schedule.forced(early), start + Duration::from_secs(60));
+        let late = start + Duration::from_secs(90);
+        assert_eq!(schedule.forced(late), late);
+
+        // A failed announce keeps the intervals and waits for the regular one.
+        schedule.announced(late, None);
+        assert_eq!(schedule.next(), late + Duration::from_secs(600));
+        assert_eq!(schedule.forced(late), late + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_announce_schedule_min_interval_floor() {
+        let response = b"d8:intervali0e12:min intervali0e5:peers0:e";
+        let peers = peers::Peers::from_tracker_response(
+            serde_bencode::from_bytes(response).unwrap(),
+            false,
+        )
+        .unwrap();
+        let start = Instant::now();
+        let schedule = AnnounceSchedule::new(start, Some(&peers));
+
+        assert_eq!(schedule.next(), start + MIN_INTERVAL_FLOOR);
+        assert_eq!(schedule.forced(start), start + MIN_INTERVAL_FLOOR);
+    }
+
+    #[tokio::test]
+    async fn test_session_announces_start_and_stop() -> Result<()> {
+        let tracker = MockTracker::new(b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e");
+        let requests = tracker.requests();
+        let url = tracker.spawn().await;
+        let torrent = sample_torrent()?.with_tracker(url);
+        let client = peers::Client::new(peers::PeerID::new(), peers::ClientOptions::default())?;
+        let (progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let (session, _feed) = Session::start(
+            client,
+            torrent,
+            TransferTotals::default(),
+            progress_rx,
+            peers::Peers::from(Vec::new()),
+        )
+        .await?;
+        let cancel = session.cancel_token();
+        progress_tx.send(Progress {
+            piece: 0,
+            pieces_done: 1,
+            pieces_total: 3,
+            bytes_done: 32768,
+            bytes_total: 92063,
+            eta: Eta::Calculating,
+        })?;
+        // Give the re-announce task a moment to take the progress.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        session.close().await?;
+
+        assert!(cancel.is_cancelled());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("&left=92063&"), "{}", requests[0]);
+        assert!(requests[0].contains("&event=started&"), "{}", requests[0]);
+        // One piece of 32768 bytes written.
+        assert!(requests[1].contains("&left=59295&"), "{}", requests[1]);
+        assert!(requests[1].contains("&event=stopped&"), "{}", requests[1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_sends_tracker_id_back() -> Result<()> {
+        let tracker = MockTracker::new(b"d8:intervali60e5:peers0:10:tracker id3:abce");
+        let requests = tracker.requests();
+        let url = tracker.spawn().await;
+        let torrent = sample_torrent()?.with_tracker(url);
+        let client = peers::Client::new(peers::PeerID::new(), peers::ClientOptions::default())?;
+        let (_progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let (session, _feed) = Session::start(
+            client,
+            torrent,
+            TransferTotals::default(),
+            progress_rx,
+            peers::Peers::from(Vec::new()),
+        )
+        .await?;
+        session.close().await?;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].contains("trackerid"), "{}", requests[0]);
+        assert!(requests[1].contains("&trackerid=abc "), "{}", requests[1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_starts_with_cached_peers() -> Result<()> {
+        let tracker = MockTracker::new(b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e");
+        let requests = tracker.requests();
+        let received = tracker.received();
+        let url = tracker.spawn().await;
+        let torrent = sample_torrent()?.with_tracker(url);
+        let client = peers::Client::new(peers::PeerID::new(), peers::ClientOptions::default())?;
+        let (_progress_tx, progress_rx) = tokio::sync::mpsc::unbounded_channel();
+        let cached = peers::Peers::from(vec!["10.0.0.1:6881".parse().unwrap()]);
+
+        let (session, _feed) = Session::start(
+            client,
+            torrent,
+            TransferTotals::default(),
+            progress_rx,
+            cached,
+        )
+        .await?;
+        // The `started` event is announced in the background.
+        received.notified().await;
+        session.close().await?;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("&event=started&"), "{}", requests[0]);
+        assert!(requests[1].contains("&event=stopped&"), "{}", requests[1]);
+
+        Ok(())
+    }
+}
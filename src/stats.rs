@@ -0,0 +1,431 @@
+use core::fmt;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::torrent::InfoHash;
+
+/// Summary of a finished download.
+#[derive(Debug, Default)]
+pub struct DownloadStats {
+    /// Every peer that delivered at least one block, fastest first.
+    pub peers: Vec<PeerStats>,
+}
+
+impl fmt::Display for DownloadStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for peer in &self.peers {
+            writeln!(f, "{}", peer)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub peer: String,
+    pub bytes: usize,
+    /// Time between connecting and the last received block, summed over all connections.
+    pub active: Duration,
+    /// Summed over all connections, None unless `DownloadConfig::connection_metrics` is set.
+    pub connection: Option<ConnectionMetrics>,
+}
+
+impl PeerStats {
+    /// Bytes per second, 0 as long as nothing was received.
+    pub fn score(&self) -> f64 {
+        if self.active.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.active.as_secs_f64()
+    }
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} bytes in {:.1}s ({:.0} B/s)",
+            self.peer,
+            self.bytes,
+            self.active.as_secs_f64(),
+            self.score()
+        )?;
+        if let Some(metrics) = &self.connection {
+            write!(f, ", {}", metrics)?;
+        }
+        Ok(())
+    }
+}
+
+/// Traffic of peer connections, counted from the unchoke on, so the handshake isn't included.
+/// Comparing `bytes_read` to the block bytes of `PeerStats` shows the protocol overhead.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionMetrics {
+    /// Bytes received, message headers included.
+    pub bytes_read: u64,
+    /// Bytes sent, message headers included.
+    pub bytes_written: u64,
+    /// Messages the download acted on, i.e. blocks. Keep-alives and ignored messages aren't
+    /// counted.
+    pub messages_read: u64,
+    /// Messages sent, i.e. requests and cancels.
+    pub messages_written: u64,
+}
+
+impl ConnectionMetrics {
+    fn add(&mut self, other: &ConnectionMetrics) {
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.messages_read += other.messages_read;
+        self.messages_written += other.messages_written;
+    }
+}
+
+impl fmt::Display for ConnectionMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "read {} bytes in {} messages, wrote {} bytes in {} messages",
+            self.bytes_read, self.messages_read, self.bytes_written, self.messages_written
+        )
+    }
+}
+
+/// Peer connection the download loop counts its messages on. Only `MeteredStream` keeps them,
+/// so plain connections have no overhead.
+pub(crate) trait MessageCounter {
+    fn message_read(&mut self) {}
+    fn message_written(&mut self) {}
+}
+
+impl MessageCounter for TcpStream {}
+
+/// Wraps a stream to collect its `ConnectionMetrics`.
+pub(crate) struct MeteredStream<S> {
+    inner: S,
+    metrics: ConnectionMetrics,
+}
+
+impl<S> MeteredStream<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            inner,
+            metrics: ConnectionMetrics::default(),
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> ConnectionMetrics {
+        self.metrics
+    }
+}
+
+impl<S> MessageCounter for MeteredStream<S> {
+    fn message_read(&mut self) {
+        self.metrics.messages_read += 1;
+    }
+
+    fn message_written(&mut self) {
+        self.metrics.messages_written += 1;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.metrics.bytes_read += (buf.filled().len() - before) as u64;
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.metrics.bytes_written += n as u64;
+        }
+        res
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct Entry {
+    stats: PeerStats,
+    // Start of the current connection and time already accounted for in `stats.active`.
+    connected: Instant,
+    counted: Duration,
+}
+
+/// Throughput of every peer of a download, shared by the peer workers.
+#[derive(Default)]
+pub(crate) struct PeerScores {
+    peers: Mutex<HashMap<String, Entry>>,
+}
+
+impl PeerScores {
+    /// Starts measuring a new connection to `peer`.
+    pub(crate) fn connect(self: &Arc<Self>, peer: &str) -> PeerScore {
+        let mut peers = self.peers.lock().expect("peer scores lock poisoned");
+        let entry = peers.entry(peer.to_string()).or_insert_with(|| Entry {
+            stats: PeerStats {
+                peer: peer.to_string(),
+                bytes: 0,
+                active: Duration::ZERO,
+                connection: None,
+            },
+            connected: Instant::now(),
+            counted: Duration::ZERO,
+        });
+        entry.connected = Instant::now();
+        entry.counted = Duration::ZERO;
+
+        PeerScore {
+            scores: Arc::clone(self),
+            peer: peer.to_string(),
+        }
+    }
+
+    fn record(&self, peer: &str, bytes: usize, at: Instant) {
+        let mut peers = self.peers.lock().expect("peer scores lock poisoned");
+        if let Some(entry) = peers.get_mut(peer) {
+            let elapsed = at.duration_since(entry.connected);
+            entry.stats.active += elapsed.saturating_sub(entry.counted);
+            entry.counted = elapsed;
+            entry.stats.bytes += bytes;
+        }
+    }
+
+    /// Scales `max_depth` by how fast `peer` is compared to the fastest peer, so slow peers keep
+    /// fewer blocks tied up and fast peers get to download most of them. Peers without a score
+    /// yet get the full depth.
+    fn pipeline_depth(&self, peer: &str, max_depth: usize) -> usize {
+        let peers = self.peers.lock().expect("peer scores lock poisoned");
+        let best = peers.values().map(|e| e.stats.score()).fold(0.0, f64::max);
+        let score = peers.get(peer).map_or(0.0, |e| e.stats.score());
+        if best == 0.0 || score == 0.0 {
+            return max_depth;
+        }
+
+        ((max_depth as f64 * score / best).ceil() as usize).clamp(1, max_depth)
+    }
+
+    /// Bytes per second received from `peer` so far, see `PeerStats::score`.
+    fn speed(&self, peer: &str) -> f64 {
+        let peers = self.peers.lock().expect("peer scores lock poisoned");
+        peers.get(peer).map_or(0.0, |e| e.stats.score())
+    }
+
+    fn add_metrics(&self, peer: &str, metrics: &ConnectionMetrics) {
+        let mut peers = self.peers.lock().expect("peer scores lock poisoned");
+        if let Some(entry) = peers.get_mut(peer) {
+            entry
+                .stats
+                .connection
+                .get_or_insert_with(ConnectionMetrics::default)
+                .add(metrics);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> DownloadStats {
+        let peers = self.peers.lock().expect("peer scores lock poisoned");
+        let mut stats: Vec<PeerStats> = peers
+            .values()
+            .filter(|e| e.stats.bytes > 0)
+            .map(|e| e.stats.clone())
+            .collect();
+        stats.sort_by(|a, b| b.score().total_cmp(&a.score()));
+
+        DownloadStats { peers: stats }
+    }
+}
+
+/// Measures a single connection, see `PeerScores::connect`.
+pub(crate) struct PeerScore {
+    scores: Arc<PeerScores>,
+    peer: String,
+}
+
+impl PeerScore {
+    pub(crate) fn record(&self, bytes: usize) {
+        self.scores.record(&self.peer, bytes, Instant::now());
+    }
+
+    pub(crate) fn pipeline_depth(&self, max_depth: usize) -> usize {
+        self.scores.pipeline_depth(&self.peer, max_depth)
+    }
+
+    /// Used to feed faster peers first, see `BlockScheduler::next_block`.
+    pub(crate) fn speed(&self) -> f64 {
+        self.scores.speed(&self.peer)
+    }
+
+    pub(crate) fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Adds the metrics of a closed connection.
+    pub(crate) fn add_metrics(&self, metrics: &ConnectionMetrics) {
+        self.scores.add_metrics(&self.peer, metrics);
+    }
+}
+
+/// Bytes transferred for a torrent over all sessions, reported to the tracker on announce.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferTotals {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+// Stats file content, keyed by info hash hex.
+type StatsFile = BTreeMap<String, TransferTotals>;
+
+impl TransferTotals {
+    /// Reads the totals of `info_hash` from the stats file at `path`. A missing or corrupt file
+    /// counts as nothing transferred yet.
+    pub fn load(path: &Path, info_hash: &InfoHash) -> TransferTotals {
+        read_stats_file(path)
+            .get(&info_hash.to_hex())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Stores the totals of `info_hash` in the stats file at `path`, keeping those of other
+    /// torrents.
+    pub fn save(&self, path: &Path, info_hash: &InfoHash) -> Result<()> {
+        let mut stats = read_stats_file(path);
+        stats.insert(info_hash.to_hex(), *self);
+        std::fs::write(path, serde_json::to_vec_pretty(&stats)?)
+            .with_context(|| format!("could not write stats file {}", path.display()))
+    }
+}
+
+fn read_stats_file(path: &Path) -> StatsFile {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return StatsFile::new(),
+        Err(e) => {
+            log::warn!("Could not read stats file {}: {}", path.display(), e);
+            return StatsFile::new();
+        }
+    };
+    serde_json::from_slice(&content).unwrap_or_else(|e| {
+        log::warn!("Ignoring corrupt stats file {}: {}", path.display(), e);
+        StatsFile::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slow_peers_get_a_shallower_pipeline() {
+        let scores = Arc::new(PeerScores::default());
+        let fast = scores.connect("fast");
+        let slow = scores.connect("slow");
+        let fresh = scores.connect("fresh");
+        let now = Instant::now() + Duration::from_secs(1);
+        scores.record("fast", 10_000, now);
+        scores.record("slow", 1_000, now);
+
+        assert_eq!(fast.pipeline_depth(10), 10);
+        assert!(slow.pipeline_depth(10) <= 2);
+        assert_eq!(fresh.pipeline_depth(10), 10);
+
+        let stats = scores.stats();
+        let order: Vec<_> = stats.peers.iter().map(|p| p.peer.as_str()).collect();
+        // Peers that never delivered anything are left out.
+        assert_eq!(order, vec!["fast", "slow"]);
+    }
+
+    #[tokio::test]
+    async fn test_metered_stream() -> Result<(), Box<dyn std::error::Error>> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut stream = MeteredStream::new(client);
+        stream.write_all(b"request").await?;
+        stream.message_written();
+        server.write_all(b"block").await?;
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        stream.message_read();
+
+        let metrics = stream.metrics();
+        assert_eq!(
+            metrics,
+            ConnectionMetrics {
+                bytes_read: 5,
+                bytes_written: 7,
+                messages_read: 1,
+                messages_written: 1,
+            }
+        );
+
+        let scores = Arc::new(PeerScores::default());
+        let score = scores.connect("peer");
+        scores.record("peer", 5, Instant::now() + Duration::from_secs(1));
+        score.add_metrics(&metrics);
+        score.add_metrics(&metrics);
+        let stats = scores.stats();
+        assert_eq!(stats.peers[0].connection.map(|m| m.bytes_read), Some(10));
+        assert!(stats.peers[0]
+            .to_string()
+            .ends_with("read 10 bytes in 2 messages, wrote 14 bytes in 2 messages"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_totals_persist() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("stats.json");
+        let a = InfoHash::new([0xaa; 20]);
+        let b = InfoHash::new([0xbb; 20]);
+
+        assert_eq!(TransferTotals::load(&path, &a), TransferTotals::default());
+
+        let totals = TransferTotals {
+            uploaded: 1,
+            downloaded: 2,
+        };
+        totals.save(&path, &a)?;
+        TransferTotals::default().save(&path, &b)?;
+        assert_eq!(TransferTotals::load(&path, &a), totals);
+
+        std::fs::write(&path, "{not json")?;
+        assert_eq!(TransferTotals::load(&path, &a), TransferTotals::default());
+
+        Ok(())
+    }
+}
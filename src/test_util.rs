@@ -0,0 +1,403 @@
+//! Test doubles speaking the peer wire protocol or HTTP over localhost.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::bitfield::Bitfield;
+use crate::peers::Peer;
+
+/// Serves `data` as a torrent with pieces of `piece_len` bytes to every connecting client.
+pub(crate) struct MockPeer {
+    data: Arc<Vec<u8>>,
+    piece_len: usize,
+    // Closes the first connection after answering this many requests.
+    drop_after: Option<usize>,
+    dropped: AtomicBool,
+    // Answers every request twice, like a peer sending unsolicited blocks.
+    send_twice: bool,
+    // Collects all requests the client pipelined before answering them.
+    batch: bool,
+    max_pipelined: Arc<AtomicUsize>,
+    // Answers the handshake with the client's own peer id.
+    echo_peer_id: bool,
+    // Reads requests but never answers them.
+    silent: bool,
+    // Never answers requests for this piece.
+    stalled_piece: Option<usize>,
+    // Pieces left out of the bitfield, requesting one closes the connection.
+    missing: Vec<usize>,
+    // Waits this long before answering each request.
+    delay: Duration,
+    connections: Arc<AtomicUsize>,
+    cancels: Arc<AtomicUsize>,
+}
+
+impl MockPeer {
+    pub(crate) fn new(data: Vec<u8>, piece_len: usize) -> Self {
+        Self {
+            data: Arc::new(data),
+            piece_len,
+            drop_after: None,
+            dropped: AtomicBool::new(false),
+            send_twice: false,
+            batch: false,
+            max_pipelined: Arc::new(AtomicUsize::new(0)),
+            echo_peer_id: false,
+            silent: false,
+            stalled_piece: None,
+            missing: Vec::new(),
+            delay: Duration::ZERO,
+            connections: Arc::new(AtomicUsize::new(0)),
+            cancels: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Simulates a flaky peer: the first connection is closed after `requests` answered
+    /// requests, later connections are served normally.
+    pub(crate) fn drop_first_connection_after(mut self, requests: usize) -> Self {
+        self.drop_after = Some(requests);
+        self
+    }
+
+    pub(crate) fn send_blocks_twice(mut self) -> Self {
+        self.send_twice = true;
+        self
+    }
+
+    /// Only answers once the client stopped sending requests, so the most requests seen at once
+    /// show the client's pipeline depth, see `max_pipelined`.
+    pub(crate) fn answer_in_batches(mut self) -> Self {
+        self.batch = true;
+        self
+    }
+
+    /// Behaves like the client connected to itself.
+    pub(crate) fn echo_peer_id(mut self) -> Self {
+        self.echo_peer_id = true;
+        self
+    }
+
+    /// Simulates a stalled peer that keeps the connection open without answering requests.
+    pub(crate) fn never_answer(mut self) -> Self {
+        self.silent = true;
+        self
+    }
+
+    /// Simulates a peer that is slow for one piece but serves all others.
+    pub(crate) fn stall_piece(mut self, idx: usize) -> Self {
+        self.stalled_piece = Some(idx);
+        self
+    }
+
+    /// Simulates a peer that doesn't have piece `idx` yet.
+    pub(crate) fn without_piece(mut self, idx: usize) -> Self {
+        self.missing.push(idx);
+        self
+    }
+
+    /// Simulates a slow peer.
+    pub(crate) fn delay_answers(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub(crate) fn max_pipelined(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.max_pipelined)
+    }
+
+    /// Connections accepted so far.
+    pub(crate) fn connections(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.connections)
+    }
+
+    /// Cancel messages received so far.
+    pub(crate) fn cancels(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.cancels)
+    }
+
+    /// Starts accepting connections in the background and returns the address to connect to.
+    pub(crate) async fn spawn(self) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let this = Arc::new(self);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let this = Arc::clone(&this);
+                tokio::spawn(async move {
+                    // Errors just mean the client went away.
+                    let _ = this.serve(stream).await;
+                });
+            }
+        });
+
+        addr.to_string().parse().unwrap()
+    }
+
+    async fn serve(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await?;
+        // Answer with the same info hash and a peer id of our own.
+        if !self.echo_peer_id {
+            handshake[48..68].copy_from_slice(b"-MK0001-mockpeer0000");
+        }
+        stream.write_all(&handshake).await?;
+
+        let pieces_cnt = self.data.len().div_ceil(self.piece_len);
+        let mut bitfield = Bitfield::new(pieces_cnt);
+        for idx in (0..pieces_cnt).filter(|idx| !self.missing.contains(idx)) {
+            bitfield.set(idx);
+        }
+        write_message(&mut stream, 5, bitfield.to_bytes()).await?;
+
+        // Interested
+        let (id, _) = read_message(&mut stream).await?;
+        assert_eq!(id, 2);
+        write_message(&mut stream, 1, &[]).await?;
+
+        let drop_after = match self.drop_after {
+            Some(n) if !self.dropped.swap(true, Ordering::SeqCst) => Some(n),
+            _ => None,
+        };
+        let mut answered = 0;
+        loop {
+            if drop_after == Some(answered) {
+                return Ok(());
+            }
+            let mut requests = vec![read_message(&mut stream).await?];
+            if self.silent {
+                continue;
+            }
+            if self.batch {
+                while let Ok(msg) =
+                    tokio::time::timeout(Duration::from_millis(50), read_message(&mut stream)).await
+                {
+                    requests.push(msg?);
+                }
+                self.max_pipelined
+                    .fetch_max(requests.len(), Ordering::SeqCst);
+            }
+            for (id, payload) in requests {
+                self.answer(&mut stream, id, &payload).await?;
+                answered += 1;
+            }
+        }
+    }
+
+    async fn answer(&self, stream: &mut TcpStream, id: u8, payload: &[u8]) -> std::io::Result<()> {
+        if id == 8 {
+            self.cancels.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        }
+        assert_eq!(id, 6, "expected Request message");
+        let index = u32::from_be_bytes(payload[0..4].try_into().unwrap()) as usize;
+        if self.stalled_piece == Some(index) {
+            return Ok(());
+        }
+        if self.missing.contains(&index) {
+            return Err(std::io::Error::other(format!(
+                "piece {} is not in the bitfield",
+                index
+            )));
+        }
+        tokio::time::sleep(self.delay).await;
+        let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+        let length = u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+
+        let start = index * self.piece_len + begin;
+        let mut out = payload[0..8].to_vec();
+        out.extend_from_slice(&self.data[start..start + length]);
+        write_message(stream, 7, &out).await?;
+        if self.send_twice {
+            write_message(stream, 7, &out).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn read_message(stream: &mut TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut len = 0;
+    // Skip keep-alives.
+    while len == 0 {
+        len = stream.read_u32().await? as usize;
+    }
+    let id = stream.read_u8().await?;
+    let mut payload = vec![0; len - 1];
+    stream.read_exact(&mut payload).await?;
+
+    Ok((id, payload))
+}
+
+async fn write_message(stream: &mut TcpStream, id: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut out = ((payload.len() + 1) as u32).to_be_bytes().to_vec();
+    out.push(id);
+    out.extend_from_slice(payload);
+    stream.write_all(&out).await
+}
+
+/// Serves files over HTTP, whole or answering range requests like a web seed.
+pub(crate) struct MockWebSeed {
+    // By URL path, e.g. "/name/a.txt".
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MockWebSeed {
+    pub(crate) fn new(files: HashMap<String, Vec<u8>>) -> Self {
+        Self { files }
+    }
+
+    /// Starts serving in the background and returns the base URL.
+    pub(crate) async fn spawn(self) -> url::Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let this = Arc::new(self);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let this = Arc::clone(&this);
+                tokio::spawn(async move {
+                    let _ = this.serve(stream).await;
+                });
+            }
+        });
+
+        url::Url::parse(&format!("http://{}/", addr)).unwrap()
+    }
+
+    async fn serve(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut request_line = String::new();
+            if stream.read_line(&mut request_line).await? == 0 {
+                return Ok(());
+            }
+            let path = request_line
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or("")
+                .to_string();
+            let mut range = None;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                    let (start, end) = value.split_once('-').unwrap();
+                    range = Some((
+                        start.parse::<usize>().unwrap(),
+                        end.parse::<usize>().unwrap(),
+                    ));
+                }
+            }
+
+            let response = match (self.files.get(&path), range) {
+                (Some(data), Some((start, end))) if end < data.len() => {
+                    let body = &data[start..=end];
+                    let mut out = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                        start,
+                        end,
+                        data.len(),
+                        body.len()
+                    )
+                    .into_bytes();
+                    out.extend_from_slice(body);
+                    out
+                }
+                (Some(data), None) => {
+                    let mut out =
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len())
+                            .into_bytes();
+                    out.extend_from_slice(data);
+                    out
+                }
+                _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            };
+            stream.get_mut().write_all(&response).await?;
+        }
+    }
+}
+
+/// Answers every announce with the same bencoded body and records the request lines, to check
+/// what was sent.
+pub(crate) struct MockTracker {
+    body: Vec<u8>,
+    requests: Arc<Mutex<Vec<String>>>,
+    received: Arc<Notify>,
+}
+
+impl MockTracker {
+    pub(crate) fn new(body: &[u8]) -> Self {
+        Self {
+            body: body.to_vec(),
+            requests: Arc::default(),
+            received: Arc::default(),
+        }
+    }
+
+    /// Request lines like `GET /announce?peer_id=... HTTP/1.1`, oldest first.
+    pub(crate) fn requests(&self) -> Arc<Mutex<Vec<String>>> {
+        Arc::clone(&self.requests)
+    }
+
+    /// Notified after each request was recorded in `requests`.
+    pub(crate) fn received(&self) -> Arc<Notify> {
+        Arc::clone(&self.received)
+    }
+
+    /// Starts serving in the background and returns the announce URL.
+    pub(crate) async fn spawn(self) -> url::Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let this = Arc::new(self);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let this = Arc::clone(&this);
+                tokio::spawn(async move {
+                    let _ = this.serve(stream).await;
+                });
+            }
+        });
+
+        url::Url::parse(&format!("http://{}/announce", addr)).unwrap()
+    }
+
+    async fn serve(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut request_line = String::new();
+            if stream.read_line(&mut request_line).await? == 0 {
+                return Ok(());
+            }
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                if line.trim_end().is_empty() {
+                    break;
+                }
+            }
+            self.requests
+                .lock()
+                .unwrap()
+                .push(request_line.trim_end().to_string());
+            self.received.notify_one();
+
+            let mut out = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                self.body.len()
+            )
+            .into_bytes();
+            out.extend_from_slice(&self.body);
+            stream.get_mut().write_all(&out).await?;
+        }
+    }
+}
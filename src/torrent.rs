@@ -1,73 +1,702 @@
 use core::fmt;
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, Bytes};
-use sha1::{Digest, Sha1};
+use serde_with::{formats::PreferMany, serde_as, Bytes, OneOrMany};
+#[cfg(not(feature = "sha1-smol"))]
+use sha1::Digest;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 use url::Url;
 
 use anyhow::{Context, Result};
 
+use crate::bencode;
+use crate::peers::AnnounceEvent;
+
+/// Written to the `created by` field of created torrents.
+const CREATED_BY: &str = "rusty-bittorrent 0.1";
+const AUTO_MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const AUTO_MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+// Halving the pieces by doubling their length, this ends up between 1000 and 2000 pieces.
+const AUTO_MAX_PIECES: u64 = 2000;
+
+#[serde_as]
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct TorrentFile {
     #[serde(rename = "announce")]
     tracker_url: String,
     #[serde(rename = "created by")]
     created_by: String,
+    // Unix timestamp. Some clients write garbage here, which is read as absent.
+    #[serde(
+        rename = "creation date",
+        default,
+        deserialize_with = "lenient_timestamp",
+        skip_serializing_if = "Option::is_none"
+    )]
+    creation_date: Option<i64>,
     info: FileInfo,
+    // Tiers of trackers (BEP 12), used instead of `announce` if present.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce_list: Option<Vec<Vec<String>>>,
+    // HTTP web seeds (BEP 19), either a single URL or a list of them.
+    #[serde_as(as = "Option<OneOrMany<_, PreferMany>>")]
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none")]
+    url_list: Option<Vec<String>>,
 }
 
+// Fields are kept in bencode key order, as the info hash is computed over the re-encoded dict.
 #[serde_as]
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 struct FileInfo {
-    length: u32,
+    // BitTorrent v2 (BEP 52) file layout, only read to reject such torrents.
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    file_tree: Option<serde_bencode::value::Value>,
+    // Only present for multi-file torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<FileEntry>>,
+    // Only present for single-file torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    // 2 for BitTorrent v2 torrents.
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    meta_version: Option<i64>,
     name: String,
     #[serde(rename = "piece length")]
     piece_length: u32,
+    // Missing in v2-only torrents, see `Info::from_file_info`.
     #[serde_as(as = "Bytes")]
+    #[serde(default)]
     pieces: Vec<u8>,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct FileEntry {
+    pub length: u64,
+    // Path segments, the last one being the file name (BEP 3).
+    pub path: Vec<String>,
+}
+
+/// What an info dict describes, see `info_hash`.
+pub enum InfoContent<'a> {
+    /// A single file named like the torrent.
+    File { length: u64 },
+    /// Files below a directory named like the torrent.
+    Files(&'a [FileEntry]),
+}
+
+/// Computes the info hash of a v1 torrent with the given info dict fields, e.g. for a torrent
+/// being built.
+pub fn info_hash(
+    name: &str,
+    piece_length: u32,
+    pieces: &[PieceHash],
+    content: InfoContent<'_>,
+) -> Result<InfoHash> {
+    let (length, files) = match content {
+        InfoContent::File { length } => (Some(length), None),
+        InfoContent::Files(files) => (None, Some(files.to_vec())),
+    };
+    let fi = FileInfo {
+        file_tree: None,
+        files,
+        length,
+        meta_version: None,
+        name: name.to_string(),
+        piece_length,
+        pieces: pieces.iter().flat_map(Hash::as_bytes).copied().collect(),
+    };
+    let info_encoded = serde_bencode::to_bytes(&fi).context("could not bencode info")?;
+
+    Ok(Hash::hash(&info_encoded))
+}
+
+impl FileEntry {
+    /// Joins the path segments below the torrent's directory `name`. Segments come from an
+    /// untrusted torrent file, so anything that could escape that directory is rejected.
+    fn to_download_file(&self, name: &str) -> Result<DownloadFile> {
+        if self.path.is_empty() {
+            anyhow::bail!("file entry has an empty path");
+        }
+
+        let mut path = PathBuf::new();
+        for segment in std::iter::once(name).chain(self.path.iter().map(String::as_str)) {
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(c)), None) if c == segment => path.push(c),
+                _ => anyhow::bail!("invalid path segment {:?} in file entry", segment),
+            }
+        }
+
+        Ok(DownloadFile {
+            path,
+            length: usize::try_from(self.length)
+                .with_context(|| format!("file of {} bytes is too large", self.length))?,
+        })
+    }
+}
+
+fn lenient_timestamp<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_bencode::value::Value::deserialize(deserializer)? {
+        serde_bencode::value::Value::Int(timestamp) => Ok(Some(timestamp)),
+        other => {
+            log::warn!("Ignoring creation date that is not an integer: {:?}", other);
+            Ok(None)
+        }
+    }
+}
+
 impl TorrentFile {
-    pub fn parse_from_file(torrent_path: &PathBuf) -> Result<TorrentFile> {
+    /// With `lenient`, integers like `i+5e` that some broken torrent creators write are
+    /// accepted, see `bencode::decode`.
+    pub fn parse_from_file(torrent_path: &PathBuf, lenient: bool) -> Result<TorrentFile> {
         let mut file = File::open(torrent_path)?;
 
         let mut content = Vec::new();
         file.read_to_end(&mut content)?;
 
+        Self::parse_with(content, lenient)
+    }
+
+    /// Reads the torrent file at `source`, which may also be an http(s) URL to fetch it from.
+    pub async fn load(source: &Path, lenient: bool) -> Result<TorrentFile> {
+        match source.to_str().and_then(torrent_url) {
+            Some(url) => Self::fetch(url, lenient).await,
+            None => Self::parse_from_file(&source.to_path_buf(), lenient),
+        }
+    }
+
+    async fn fetch(url: Url, lenient: bool) -> Result<TorrentFile> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let resp = client
+            .get(url.clone())
+            .send()
+            .await
+            .with_context(|| format!("could not fetch torrent file from {}", url))?;
+        if !resp.status().is_success() {
+            anyhow::bail!(
+                "fetching torrent file from {} failed with status {}",
+                url,
+                resp.status()
+            );
+        }
+
+        Self::parse_with(resp.bytes().await?.to_vec(), lenient)
+    }
+
+    fn parse_with(content: Vec<u8>, lenient: bool) -> Result<TorrentFile> {
+        let content =
+            bencode::normalize(&content, lenient).context("could not parse content into Meta")?;
         Self::parse(content)
     }
 
     fn parse(content: Vec<u8>) -> Result<TorrentFile> {
-        serde_bencode::from_bytes(&content).context("could not parse content into Meta")
+        let tf =
+            serde_bencode::from_bytes(&content).context("could not parse content into Meta")?;
+        // The info hash is computed over the re-encoded info dict, which only matches the
+        // original bytes if they were canonical.
+        if let Ok(info) = raw_info(&content) {
+            if let Err(e) = bencode::check_canonical(info) {
+                log::warn!(
+                    "Info dict is not canonical bencode, the info hash may not match other clients: {:#}",
+                    e
+                );
+            }
+        }
+
+        Ok(tf)
+    }
+
+    /// Creates a torrent announcing to `tracker` of the file or directory at `source`, hashing
+    /// its content in pieces of `piece_length` bytes, see `auto_piece_length`.
+    pub fn create(source: &Path, tracker: &Url, piece_length: u32) -> Result<TorrentFile> {
+        if !piece_length.is_power_of_two() {
+            anyhow::bail!("piece length must be a power of two, got {}", piece_length);
+        }
+        let name = source
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} has no usable file name", source.display()))?
+            .to_string();
+        let (length, files, paths) = if source.is_dir() {
+            let mut entries = Vec::new();
+            collect_files(source, &mut Vec::new(), &mut entries)?;
+            let (files, paths): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+            (None, Some(files), paths)
+        } else {
+            let length = std::fs::metadata(source)?.len();
+            (Some(length), None, vec![source.to_path_buf()])
+        };
+
+        let pieces: Vec<u8> = hash_pieces(&paths, usize::try_from(piece_length)?)?
+            .iter()
+            .flat_map(|hash| hash.as_bytes().to_vec())
+            .collect();
+        if pieces.is_empty() {
+            anyhow::bail!("{} contains no data", source.display());
+        }
+
+        let creation_date = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+
+        Ok(TorrentFile {
+            tracker_url: tracker.to_string(),
+            created_by: String::from(CREATED_BY),
+            creation_date: Some(creation_date),
+            info: FileInfo {
+                file_tree: None,
+                files,
+                length,
+                meta_version: None,
+                name,
+                piece_length,
+                pieces,
+            },
+            announce_list: None,
+            url_list: None,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("could not bencode torrent file")
+    }
+}
+
+/// Adds the files below `dir` to `entries`, sorted by path, with `prefix` being the path
+/// segments of `dir` below the torrent's directory.
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    entries: &mut Vec<(FileEntry, PathBuf)>,
+) -> Result<()> {
+    let mut children: Vec<_> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    children.sort();
+
+    for child in children {
+        let segment = child
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} has no usable file name", child.display()))?
+            .to_string();
+        prefix.push(segment);
+        if child.is_dir() {
+            collect_files(&child, prefix, entries)?;
+        } else {
+            let entry = FileEntry {
+                length: std::fs::metadata(&child)?.len(),
+                path: prefix.clone(),
+            };
+            entries.push((entry, child));
+        }
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+/// SHA1 of every `piece_length` bytes of the files at `paths`, read back to back like the
+/// content of a torrent, so pieces span file boundaries. The last piece may be shorter.
+pub fn hash_pieces(paths: &[PathBuf], piece_length: usize) -> Result<Vec<PieceHash>> {
+    if piece_length == 0 {
+        anyhow::bail!("piece length must not be 0");
+    }
+
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+    for path in paths {
+        let mut file =
+            File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+        loop {
+            let start = piece.len();
+            piece.resize(piece_length, 0);
+            let n = file.read(&mut piece[start..])?;
+            piece.truncate(start + n);
+            if piece.len() == piece_length {
+                pieces.push(Hash::hash(&piece));
+                piece.clear();
+            } else if n == 0 {
+                break;
+            }
+        }
+    }
+    if !piece.is_empty() {
+        pieces.push(Hash::hash(&piece));
+    }
+
+    Ok(pieces)
+}
+
+/// Piece length for `total` bytes of content: the smallest power of two giving at most
+/// `AUTO_MAX_PIECES` pieces, clamped to 16 KiB to 16 MiB like other clients do.
+pub fn auto_piece_length(total: u64) -> u32 {
+    let mut piece_length = AUTO_MIN_PIECE_LENGTH;
+    while piece_length < AUTO_MAX_PIECE_LENGTH
+        && total.div_ceil(u64::from(piece_length)) > AUTO_MAX_PIECES
+    {
+        piece_length *= 2;
+    }
+
+    piece_length
+}
+
+/// Formats a Unix timestamp as "YYYY-MM-DD HH:MM:SS UTC".
+fn format_utc(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let secs = timestamp.rem_euclid(86400);
+
+    // Converts days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Matches `text` against a glob `pattern`, where `*` matches any run of characters and `?` a
+/// single one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text it was matched up to, to backtrack to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` swallow one more character.
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Parses `source` as an http(s) URL, anything else is a local path.
+fn torrent_url(source: &str) -> Option<Url> {
+    Url::parse(source)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Points the torrent file `content` at `tracker`, dropping its `announce-list` if
+/// `clear_announce_list` is set. Everything else, the info dict in particular, is copied byte for
+/// byte, so the info hash stays the same.
+pub fn replace_tracker(
+    content: &[u8],
+    tracker: &Url,
+    clear_announce_list: bool,
+) -> Result<Vec<u8>> {
+    let (entries, length) = bencode::split_dict(content).context("could not parse torrent file")?;
+    if length != content.len() {
+        anyhow::bail!("unexpected data after the torrent dict");
+    }
+
+    let announce = bencode::encode_string(tracker.as_str().as_bytes());
+    let mut edited: Vec<(&[u8], &[u8])> = entries
+        .into_iter()
+        .filter(|(key, _)| {
+            *key != b"announce" && !(clear_announce_list && *key == b"announce-list")
+        })
+        .collect();
+    edited.push((b"announce", &announce));
+
+    Ok(bencode::encode_dict(&edited))
+}
+
+/// Outcome of one check of `lint`.
+pub struct LintCheck {
+    pub name: &'static str,
+    pub result: Result<()>,
+}
+
+/// The info dict of the torrent file `content`, exactly as encoded there, which is what peers
+/// exchange as the torrent's metadata (BEP 9). `Torrent::info_hash` is computed over the
+/// re-encoded dict, so it's the SHA1 of these bytes only if they are canonical bencode.
+pub fn raw_info(content: &[u8]) -> Result<&[u8]> {
+    let (entries, _) = bencode::split_dict(content)?;
+    entries
+        .into_iter()
+        .find(|(key, _)| *key == b"info")
+        .map(|(_, info)| info)
+        .context("no info dict found")
+}
+
+/// Fails unless `count` pieces of `piece_length` bytes make up exactly `length` bytes.
+fn check_piece_count(length: u64, piece_length: u32, count: usize) -> Result<()> {
+    let want = length.div_ceil(u64::from(piece_length.max(1)));
+    if want != count as u64 {
+        anyhow::bail!(
+            "{} bytes need {} pieces of {} bytes, info has {} piece hashes",
+            length,
+            want,
+            piece_length,
+            count
+        );
     }
+    Ok(())
+}
+
+/// Runs every structural check on the torrent file `content`. A failed check doesn't stop the
+/// others, except that nothing but the info dict's encoding can be checked if the torrent file
+/// can't be parsed at all.
+pub fn lint(content: &[u8]) -> Vec<LintCheck> {
+    let mut checks = Vec::new();
+    let raw_info = raw_info(content).ok();
+    checks.push(LintCheck {
+        name: "info dict is canonical bencode",
+        result: match raw_info {
+            Some(info) => bencode::check_canonical(info),
+            None => Err(anyhow::anyhow!("no info dict found")),
+        },
+    });
+
+    let tf: TorrentFile = match serde_bencode::from_bytes(content) {
+        Ok(tf) => tf,
+        Err(e) => {
+            checks.push(LintCheck {
+                name: "required fields are present",
+                result: Err(e.into()),
+            });
+            return checks;
+        }
+    };
+    checks.push(LintCheck {
+        name: "required fields are present",
+        result: Ok(()),
+    });
+
+    let fi = &tf.info;
+    let digest_len = HashAlgorithm::Sha1.digest_len();
+    checks.push(LintCheck {
+        name: "pieces are whole hashes",
+        result: match fi.pieces.len() % digest_len {
+            0 => Ok(()),
+            _ => Err(anyhow::anyhow!(
+                "pieces length {} is not a multiple of {}",
+                fi.pieces.len(),
+                digest_len
+            )),
+        },
+    });
+    checks.push(LintCheck {
+        name: "piece length is a power of two",
+        result: match fi.piece_length.is_power_of_two() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("piece length is {}", fi.piece_length)),
+        },
+    });
+
+    let length = match (fi.length, &fi.files) {
+        (Some(length), None) => Ok(length),
+        (None, Some(files)) => files
+            .iter()
+            .try_fold(0u64, |total, f| total.checked_add(f.length))
+            .context("total length of files does not fit into u64"),
+        _ => Err(anyhow::anyhow!(
+            "info must contain exactly one of length or files"
+        )),
+    };
+    let piece_count = length.and_then(|length| {
+        check_piece_count(length, fi.piece_length, fi.pieces.len() / digest_len)
+    });
+    checks.push(LintCheck {
+        name: "piece count matches length",
+        result: piece_count,
+    });
+
+    let info_hash = Torrent::from_file_torrent(&tf).and_then(|torrent| {
+        let raw = Hash::hash(raw_info.context("no info dict found")?);
+        if raw != *torrent.info_hash() {
+            anyhow::bail!(
+                "info hash {} of the raw info dict differs from {} of its parsed fields",
+                raw.to_hex(),
+                torrent.info_hash().to_hex()
+            );
+        }
+        Ok(())
+    });
+    checks.push(LintCheck {
+        name: "info hash matches the raw bytes",
+        result: info_hash,
+    });
+
+    checks
 }
 
 pub struct PeerRequest<'a> {
     pub url: Url,
-    pub info_hash: &'a Hash,
-    pub length: u32,
+    pub info_hash: &'a InfoHash,
+    /// Bytes still missing. The whole torrent, unless a download is under way.
+    pub left: u64,
+    /// Totals reported to the tracker, see `TransferTotals`.
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Tiers of trackers (BEP 12). If set, these are announced to instead of `url`.
+    pub announce_list: Vec<Vec<Url>>,
+    /// None for the regular announces while downloading.
+    pub event: Option<AnnounceEvent>,
+    /// Sent back as `trackerid` once a tracker handed one out, see `TrackerResponse`.
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+impl PeerRequest<'_> {
+    /// The trackers to announce to, by tier (BEP 12). Without an announce-list, the single
+    /// tier is the `announce` URL.
+    pub fn tiers(&self) -> Vec<Vec<Url>> {
+        if self.announce_list.is_empty() {
+            return vec![vec![self.url.clone()]];
+        }
+        self.announce_list.clone()
+    }
+}
+
+/// A single file of a multi-file torrent, laid out back to back with the others.
+#[derive(Clone, Debug)]
+pub struct DownloadFile {
+    /// Relative to the download directory.
+    pub path: PathBuf,
+    pub length: usize,
+}
+
+/// Where a file lies within the pieces of a torrent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileSpan {
+    pub path: PathBuf,
+    pub length: usize,
+    /// Offset of the file's first byte within the whole torrent.
+    pub offset: usize,
+    /// Pieces the file overlaps. Empty files report the piece at their offset.
+    pub pieces: RangeInclusive<usize>,
+    /// Offset of the file's first byte within the first piece.
+    pub first_piece_begin: usize,
+    /// Offset after the file's last byte within the last piece.
+    pub last_piece_end: usize,
+}
+
+fn file_spans(files: &[DownloadFile], piece_length: usize) -> Vec<FileSpan> {
+    let mut spans = Vec::with_capacity(files.len());
+    let mut offset = 0;
+    for file in files {
+        let first = offset / piece_length;
+        // For empty files the "last byte" would be before the first one.
+        let end = offset + file.length.max(1);
+        let last = (end - 1) / piece_length;
+        spans.push(FileSpan {
+            path: file.path.clone(),
+            length: file.length,
+            offset,
+            pieces: first..=last,
+            first_piece_begin: offset - first * piece_length,
+            last_piece_end: offset + file.length - last * piece_length,
+        });
+        offset += file.length;
+    }
+
+    spans
 }
 
 pub struct DownloadRequest {
     pub length: usize,
     pub piece_length: usize,
-    pub pieces: Vec<Hash>,
-    pub info_hash: Hash,
+    pub pieces: Vec<PieceHash>,
+    pub info_hash: InfoHash,
+    /// None for single-file torrents.
+    pub files: Option<Vec<DownloadFile>>,
+    /// Web seeds, see `Torrent::web_seeds`.
+    pub web_seeds: Vec<Url>,
 }
 
 impl DownloadRequest {
-    pub fn last_piece_len(&self) -> usize {
-        if self.pieces.len() == 1 {
-            return self.piece_length as usize;
+    /// Returns the sorted indices of all pieces overlapping any of the selected files.
+    pub fn pieces_for_files(&self, selected: &[usize]) -> Result<Vec<usize>> {
+        let files = self
+            .files
+            .as_ref()
+            .context("file selection is only supported for multi-file torrents")?;
+
+        if let Some(invalid) = selected.iter().find(|idx| **idx >= files.len()) {
+            anyhow::bail!(
+                "file index {} out of range, torrent has {} files",
+                invalid,
+                files.len()
+            );
+        }
+
+        let mut wanted = Vec::new();
+        for (idx, span) in file_spans(files, self.piece_length).into_iter().enumerate() {
+            if selected.contains(&idx) && span.length > 0 {
+                wanted.extend(span.pieces);
+            }
         }
 
-        let single_piece_len = self.piece_length as usize;
+        // Neighbouring files may share a piece, it only needs to be downloaded once.
+        wanted.sort_unstable();
+        wanted.dedup();
+
+        Ok(wanted)
+    }
+
+    /// Actual length of the piece at `idx`, only the last piece may be shorter than
+    /// `piece_length`.
+    pub fn piece_len(&self, idx: usize) -> usize {
+        self.piece_length
+            .min(self.length.saturating_sub(idx * self.piece_length))
+    }
+
+    /// Also right for a single piece, which is shorter than `piece_length` for a small file.
+    pub fn last_piece_len(&self) -> usize {
+        let single_piece_len = self.piece_length;
         let count_full_len_pieces = self.pieces.len() - 1;
         let total_len = self.length;
         let full_pieces_len = single_piece_len * count_full_len_pieces;
-        let actual_piece_len = total_len as usize - full_pieces_len;
+        let actual_piece_len = total_len.saturating_sub(full_pieces_len);
 
         actual_piece_len
     }
@@ -75,13 +704,38 @@ impl DownloadRequest {
 
 pub struct Torrent {
     tracker_url: Url,
+    announce_list: Vec<Vec<Url>>,
     info: Info,
+    web_seeds: Vec<Url>,
+    creation_date: Option<i64>,
+    // Bytes the selected files need, see `with_selected_files`.
+    selected_length: Option<u64>,
 }
 
 impl fmt::Display for Torrent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Tracker URL: {}", self.tracker_url)?;
-        writeln!(f, "{}", self.info)
+        writeln!(f, "Tracker URL: {}", self.tracker_url())?;
+        if let Some(timestamp) = self.creation_date {
+            writeln!(f, "Created: {}", format_utc(timestamp))?;
+        }
+        writeln!(f, "{}", self.info)?;
+        if self.info.files.is_some() {
+            writeln!(f, "Files")?;
+            let files = self.files().map_err(|_| fmt::Error)?;
+            for (idx, span) in files.iter().enumerate() {
+                writeln!(
+                    f,
+                    "{}: {} ({} bytes, pieces {}-{})",
+                    idx,
+                    span.path.display(),
+                    span.length,
+                    span.pieces.start(),
+                    span.pieces.end()
+                )?;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -89,10 +743,43 @@ impl Torrent {
     pub fn from_file_torrent(tf: &TorrentFile) -> Result<Torrent> {
         let parsed_url = url::Url::parse(&tf.tracker_url)?;
         let info = Info::from_file_info(&tf.info)?;
+        let announce_list = tf
+            .announce_list
+            .iter()
+            .flatten()
+            .map(|tier| {
+                tier.iter()
+                    .filter_map(|url| match Url::parse(url) {
+                        Ok(url) => Some(url),
+                        Err(e) => {
+                            log::warn!("Ignoring invalid tracker {}: {}", url, e);
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        let web_seeds = tf
+            .url_list
+            .iter()
+            .flatten()
+            .filter_map(|url| match Url::parse(url) {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    log::warn!("Ignoring invalid web seed {}: {}", url, e);
+                    None
+                }
+            })
+            .collect();
 
         Ok(Torrent {
             tracker_url: parsed_url,
+            announce_list,
             info,
+            web_seeds,
+            creation_date: tf.creation_date,
+            selected_length: None,
         })
     }
 
@@ -101,25 +788,193 @@ impl Torrent {
             // Cloning is ok here, as it is done once per file.
             url: self.tracker_url.clone(),
             info_hash: &self.info.hash,
-            length: self.info.length,
+            left: self.selected_length.unwrap_or(self.info.length),
+            uploaded: 0,
+            downloaded: 0,
+            announce_list: self.announce_list.clone(),
+            event: None,
+            tracker_id: None,
+        }
+    }
+
+    /// Announces to `tracker` only, instead of the torrent's own trackers.
+    pub fn with_tracker(mut self, tracker: Url) -> Self {
+        self.tracker_url = tracker;
+        self.announce_list.clear();
+        self
+    }
+
+    /// Announces only what the `selected` files need as `left`: every piece they span, as pieces
+    /// are downloaded whole.
+    pub fn with_selected_files(mut self, selected: &[usize]) -> Result<Self> {
+        let req = self.to_download_request()?;
+        let pieces = req.pieces_for_files(selected)?;
+        self.selected_length = Some(pieces.iter().map(|&idx| req.piece_len(idx) as u64).sum());
+        Ok(self)
+    }
+
+    /// Fails if the torrent's content is larger than `max_size` bytes.
+    pub fn check_size(&self, max_size: u64) -> Result<()> {
+        if self.length() > max_size {
+            anyhow::bail!(
+                "torrent {} is {} bytes, more than the allowed {} bytes",
+                self.info.name,
+                self.length(),
+                max_size
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info.hash
+    }
+
+    pub fn tracker_url(&self) -> &Url {
+        &self.tracker_url
+    }
+
+    /// Total size of the torrent's content in bytes.
+    pub fn length(&self) -> u64 {
+        self.info.length
+    }
+
+    /// Size of every piece but the last one in bytes.
+    pub fn piece_length(&self) -> u32 {
+        self.info.piece_length
+    }
+
+    pub fn pieces(&self) -> &[PieceHash] {
+        &self.info.pieces
+    }
+
+    /// One line with name, size, piece count and info hash, for listing many torrents.
+    pub fn summary(&self) -> String {
+        format!(
+            "{}\t{} bytes\t{} pieces\t{}",
+            self.info.name,
+            self.length(),
+            self.pieces().len(),
+            self.info_hash().to_hex()
+        )
+    }
+
+    /// Every file of the torrent with the pieces it occupies. Single-file torrents have exactly
+    /// one file.
+    pub fn files(&self) -> Result<Vec<FileSpan>> {
+        let single;
+        let files = match &self.info.files {
+            Some(files) => files.as_slice(),
+            None => {
+                single = [DownloadFile {
+                    path: PathBuf::from(&self.info.name),
+                    length: self.info.usize_length()?,
+                }];
+                &single[..]
+            }
+        };
+
+        Ok(file_spans(files, usize::try_from(self.piece_length())?))
+    }
+
+    /// Indices of the files chosen by `spec`, a comma separated list of file indices and glob
+    /// patterns like `*.mkv`. Patterns match the path below the torrent's directory, `*` also
+    /// matching `/`.
+    pub fn select_files(&self, spec: &str) -> Result<Vec<usize>> {
+        let files = self.files()?;
+        let mut selected = Vec::new();
+        for item in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            if let Ok(idx) = item.parse::<usize>() {
+                if idx >= files.len() {
+                    anyhow::bail!(
+                        "file index {} out of range, torrent has {} files",
+                        idx,
+                        files.len()
+                    );
+                }
+                selected.push(idx);
+                continue;
+            }
+
+            let before = selected.len();
+            for (idx, span) in files.iter().enumerate() {
+                let path = match self.info.files {
+                    Some(_) => span
+                        .path
+                        .strip_prefix(&self.info.name)
+                        .unwrap_or(&span.path),
+                    None => &span.path,
+                };
+                let path: Vec<_> = path.iter().map(|c| c.to_string_lossy()).collect();
+                if glob_match(item, &path.join("/")) {
+                    selected.push(idx);
+                }
+            }
+            if selected.len() == before {
+                anyhow::bail!("no file matches {}", item);
+            }
         }
+        // Patterns may overlap.
+        selected.sort_unstable();
+        selected.dedup();
+
+        Ok(selected)
     }
 
-    pub fn to_download_request(&self) -> DownloadRequest {
-        DownloadRequest {
-            length: self.info.length as usize,
-            piece_length: self.info.piece_length as usize,
+    /// Web seed URLs serving the torrent's content over HTTP. For single-file torrents they point
+    /// at the file itself, for multi-file torrents at the directory containing the torrent's
+    /// directory (BEP 19).
+    pub fn web_seeds(&self) -> Vec<Url> {
+        self.web_seeds
+            .iter()
+            .map(|url| {
+                let mut url = url.clone();
+                match (&self.info.files, url.path().ends_with('/')) {
+                    // The URL names a directory, the file is in there.
+                    (None, true) => {
+                        if let Ok(mut segments) = url.path_segments_mut() {
+                            segments.pop_if_empty().push(&self.info.name);
+                        }
+                    }
+                    (Some(_), false) => {
+                        if let Ok(mut segments) = url.path_segments_mut() {
+                            segments.push("");
+                        }
+                    }
+                    _ => {}
+                }
+                url
+            })
+            .collect()
+    }
+
+    /// Fails if the torrent is too large to be downloaded on this platform, e.g. over 4 GiB on a
+    /// 32-bit target.
+    pub fn to_download_request(&self) -> Result<DownloadRequest> {
+        Ok(DownloadRequest {
+            length: self.info.usize_length()?,
+            piece_length: usize::try_from(self.info.piece_length)?,
             pieces: self.info.pieces.clone(),
             info_hash: self.info.hash.clone(),
-        }
+            files: self.info.files.clone(),
+            web_seeds: self.web_seeds(),
+        })
     }
 }
 
 struct Info {
-    length: u32,
+    name: String,
+    length: u64,
     piece_length: u32,
-    pieces: Vec<Hash>,
-    hash: Hash,
+    // The hash algorithm is carried by the hashes themselves.
+    pieces: Vec<PieceHash>,
+    hash: InfoHash,
+    files: Option<Vec<DownloadFile>>,
 }
 
 impl fmt::Display for Info {
@@ -137,44 +992,138 @@ impl fmt::Display for Info {
 }
 
 impl Info {
+    /// Lengths are u64 so any torrent can be inspected, downloading needs them as usize.
+    fn usize_length(&self) -> Result<usize> {
+        usize::try_from(self.length)
+            .with_context(|| format!("torrent of {} bytes is too large", self.length))
+    }
+
     fn from_file_info(fi: &FileInfo) -> Result<Info> {
-        let mut pieces: Vec<Hash> = Vec::new();
-        let chunks = fi.pieces.chunks(20);
+        // Only v1 torrents are supported for now. Hybrid torrents would work with their v1
+        // metadata, but are rejected as well until v2 is supported as a whole.
+        if fi.meta_version.is_some_and(|v| v >= 2) || fi.file_tree.is_some() {
+            anyhow::bail!("BitTorrent v2 torrents are not yet supported");
+        }
+        if fi.pieces.is_empty() {
+            anyhow::bail!("info contains no pieces");
+        }
+        if fi.piece_length == 0 {
+            anyhow::bail!("piece length must not be 0");
+        }
+        let algorithm = HashAlgorithm::Sha1;
+        let digest_len = algorithm.digest_len();
+        if !fi.pieces.len().is_multiple_of(digest_len) {
+            anyhow::bail!(
+                "pieces length {} is not a multiple of {}, a piece hash is truncated",
+                fi.pieces.len(),
+                digest_len
+            );
+        }
+
+        let mut pieces: Vec<PieceHash> = Vec::new();
+        let chunks = fi.pieces.chunks(digest_len);
 
         for chunk in chunks {
-            pieces.push(Hash::new(
-                chunk
-                    .try_into()
-                    .context("expected to cast chunk of 20 into array of 20")?,
-            ))
+            pieces.push(
+                Hash::from_bytes(algorithm, chunk)
+                    .context("expected to cast chunk into a piece hash")?,
+            )
         }
 
-        let hash = Self::hash(fi)?;
+        let (length, content) = match (&fi.length, &fi.files) {
+            (Some(length), None) => (*length, InfoContent::File { length: *length }),
+            (None, Some(files)) => (
+                files
+                    .iter()
+                    .try_fold(0u64, |total, f| total.checked_add(f.length))
+                    .context("total length of files does not fit into u64")?,
+                InfoContent::Files(files),
+            ),
+            _ => anyhow::bail!("info must contain exactly one of length or files"),
+        };
+        // The last piece's length is derived from these, so they must agree.
+        check_piece_count(length, fi.piece_length, pieces.len())?;
+        // v2 fields were rejected above, so this re-encodes exactly the parsed fields.
+        let hash = info_hash(&fi.name, fi.piece_length, &pieces, content)?;
+
+        let files = match &fi.files {
+            Some(files) => Some(
+                files
+                    .iter()
+                    .map(|f| f.to_download_file(&fi.name))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            None => None,
+        };
 
         Ok(Info {
-            length: fi.length,
+            name: fi.name.clone(),
+            length,
             piece_length: fi.piece_length,
             pieces,
             hash,
+            files,
         })
     }
+}
 
-    fn hash(fi: &FileInfo) -> Result<Hash> {
-        let info_encoded = serde_bencode::to_bytes(fi).context("could not bencode info")?;
+/// Digest algorithm of a torrent's hashes. v1 torrents use SHA1, v2 (BEP 52) torrents SHA-256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
 
-        Ok(Hash::hash(&info_encoded))
+impl HashAlgorithm {
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct Hash([u8; 20]);
+/// Incremental SHA1 hashing. Backed by the `sha1` crate, or by `sha1_smol` with the `sha1-smol`
+/// feature.
+#[derive(Default)]
+pub struct Sha1Hasher {
+    #[cfg(not(feature = "sha1-smol"))]
+    inner: sha1::Sha1,
+    #[cfg(feature = "sha1-smol")]
+    inner: sha1_smol::Sha1,
+}
+
+impl Sha1Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
 
-impl Clone for Hash {
-    fn clone(&self) -> Hash {
-        Hash(self.0.clone())
+    /// Returns the hash of everything passed to `update` and starts over.
+    pub fn finalize_reset(&mut self) -> Hash {
+        #[cfg(not(feature = "sha1-smol"))]
+        let hash = self.inner.finalize_reset().into();
+        #[cfg(feature = "sha1-smol")]
+        let hash = {
+            let hash = self.inner.digest().bytes();
+            self.inner.reset();
+            hash
+        };
+        Hash::Sha1(hash)
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum Hash {
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+}
+
+/// Hash of a single piece, as listed in the info dict.
+pub type PieceHash = Hash;
+
+/// Hash of the bencoded info dict, identifying the torrent.
+pub type InfoHash = Hash;
+
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{}", self.to_hex())
@@ -183,44 +1132,837 @@ impl fmt::Display for Hash {
 
 impl PartialEq for Hash {
     fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
+        constant_time_eq(self.as_bytes(), other.as_bytes())
+    }
+}
+
+/// Compares without exiting early on the first differing byte. Inputs of differing length never
+/// compare equal, so a truncated digest can't match on its prefix.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
     }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 impl Hash {
     pub fn new(hash: [u8; 20]) -> Hash {
-        Hash(hash)
+        Hash::Sha1(hash)
     }
 
+    /// The 20 bytes used on the wire (handshake, tracker). SHA-256 hashes are truncated, as
+    /// BEP 52 specifies for v2 info hashes.
     pub fn get_hash(&self) -> &[u8; 20] {
-        &self.0
+        match self {
+            Hash::Sha1(hash) => hash,
+            Hash::Sha256(hash) => hash[..20]
+                .try_into()
+                .expect("[u8; 32] into [u8; 20] will always work"),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Hash::Sha1(hash) => hash,
+            Hash::Sha256(hash) => hash,
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            Hash::Sha1(_) => HashAlgorithm::Sha1,
+            Hash::Sha256(_) => HashAlgorithm::Sha256,
+        }
+    }
+
+    pub fn from_bytes(algorithm: HashAlgorithm, bytes: &[u8]) -> Result<Hash> {
+        let hash = match algorithm {
+            HashAlgorithm::Sha1 => Hash::Sha1(bytes.try_into()?),
+            HashAlgorithm::Sha256 => Hash::Sha256(bytes.try_into()?),
+        };
+
+        Ok(hash)
     }
 
-    pub fn hash(data: &Vec<u8>) -> Hash {
-        let mut hasher = Sha1::new();
+    pub fn hash(data: &[u8]) -> Hash {
+        let mut hasher = Sha1Hasher::default();
         hasher.update(data);
-        let res = hasher.finalize();
-        Hash(res.into())
+        hasher.finalize_reset()
+    }
+
+    /// Hashes `data` with the given algorithm, e.g. to verify a piece against its `PieceHash`.
+    pub fn digest(algorithm: HashAlgorithm, data: &[u8]) -> Result<Hash> {
+        match algorithm {
+            HashAlgorithm::Sha1 => Ok(Self::hash(data)),
+            HashAlgorithm::Sha256 => anyhow::bail!("SHA-256 hashing is not supported yet"),
+        }
     }
 
     pub fn to_hex(&self) -> String {
-        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+        self.as_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Parses the hex form of a hash, 40 digits for SHA1 and 64 for SHA-256.
+    pub fn from_hex(hex: &str) -> Result<Hash> {
+        let algorithm = match hex.len() {
+            40 => HashAlgorithm::Sha1,
+            64 => HashAlgorithm::Sha256,
+            len => anyhow::bail!("hash must be 40 or 64 hex digits, got {}", len),
+        };
+        // `from_str_radix` alone would also take a sign.
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("{} is not a hex encoded hash", hex);
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?;
+
+        Self::from_bytes(algorithm, &bytes)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::str::FromStr;
 
     use super::*;
+    use crate::test_util::MockWebSeed;
 
     #[test]
     fn test_torrent() -> Result<(), Box<dyn std::error::Error>> {
         let path = PathBuf::from_str("sample.torrent")?;
-        let torrent_file = TorrentFile::parse_from_file(&path)?;
+        let torrent_file = TorrentFile::parse_from_file(&path, false)?;
         let torrent = Torrent::from_file_torrent(&torrent_file)?;
         println!("{}", torrent);
 
+        assert_eq!(
+            torrent.summary(),
+            "sample.txt\t92063 bytes\t3 pieces\td69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+
+        assert_eq!(
+            torrent.tracker_url().as_str(),
+            "http://bittorrent-test-tracker.codecrafters.io/announce"
+        );
+        assert_eq!(torrent.length(), 92063);
+        assert_eq!(torrent.piece_length(), 32768);
+        assert_eq!(torrent.pieces().len(), 3);
+
+        let files = torrent.files()?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].pieces, 0..=2);
+        assert_eq!(files[0].last_piece_end, 92063 - 2 * 32768);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_size() -> Result<(), Box<dyn std::error::Error>> {
+        let torrent_file = TorrentFile::parse_from_file(&PathBuf::from("sample.torrent"), false)?;
+        let torrent = Torrent::from_file_torrent(&torrent_file)?;
+
+        assert!(torrent.check_size(92063).is_ok());
+        let Err(err) = torrent.check_size(92062) else {
+            panic!("expected the torrent to be too large");
+        };
+        assert_eq!(
+            err.to_string(),
+            "torrent sample.txt is 92063 bytes, more than the allowed 92062 bytes"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_file_web_seeds() -> Result<(), Box<dyn std::error::Error>> {
+        let mut torrent_file =
+            TorrentFile::parse_from_file(&PathBuf::from("sample.torrent"), false)?;
+        torrent_file.url_list = Some(vec![
+            String::from("http://seed.example/dir/"),
+            String::from("http://seed.example/file.bin"),
+            String::from("not a url"),
+        ]);
+        let torrent = Torrent::from_file_torrent(&torrent_file)?;
+
+        let seeds: Vec<_> = torrent.web_seeds().iter().map(|u| u.to_string()).collect();
+        assert_eq!(
+            seeds,
+            vec![
+                "http://seed.example/dir/sample.txt",
+                "http://seed.example/file.bin"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_lenient() -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read("sample.torrent")?;
+        let strict = TorrentFile::parse(content.clone())?;
+        let at = content
+            .windows(13)
+            .position(|w| w == b"lengthi92063e")
+            .expect("length in sample.torrent");
+        // The length written as i+92063e, like some broken torrent creators do.
+        let sloppy = [&content[..at + 7], b"+", &content[at + 7..]].concat();
+
+        let Err(err) = TorrentFile::parse_with(sloppy.clone(), false) else {
+            panic!("expected i+92063e to be rejected");
+        };
+        assert!(
+            format!("{:#}", err).contains("unexpected char '+'"),
+            "{err:#}"
+        );
+        let lenient = TorrentFile::parse_with(sloppy, true)?;
+        assert_eq!(lenient, strict);
+        assert_eq!(
+            Torrent::from_file_torrent(&lenient)?.info_hash(),
+            Torrent::from_file_torrent(&strict)?.info_hash()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_from_url() -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read("sample.torrent")?;
+        let files = HashMap::from([(String::from("/t/sample.torrent"), content)]);
+        let base = MockWebSeed::new(files).spawn().await;
+
+        let url = base.join("t/sample.torrent")?;
+        let from_url = TorrentFile::load(Path::new(url.as_str()), false).await?;
+        let from_file = TorrentFile::load(Path::new("sample.torrent"), false).await?;
+        assert_eq!(from_url, from_file);
+
+        let missing = base.join("missing.torrent")?;
+        assert!(TorrentFile::load(Path::new(missing.as_str()), false)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_creation_date() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tf = TorrentFile::parse_from_file(&PathBuf::from("sample.torrent"), false)?;
+        tf.creation_date = None;
+        let bencoded = serde_bencode::to_bytes(&tf)?;
+        let with_creation_date = |value: &str| {
+            let mut b = bencoded[..bencoded.len() - 1].to_vec();
+            b.extend_from_slice(format!("13:creation date{}e", value).as_bytes());
+            b
+        };
+
+        let tf = TorrentFile::parse(with_creation_date("i1700000000e"))?;
+        assert_eq!(tf.creation_date, Some(1700000000));
+        let torrent = Torrent::from_file_torrent(&tf)?;
+        assert!(torrent
+            .to_string()
+            .contains("Created: 2023-11-14 22:13:20 UTC\n"));
+
+        let tf = TorrentFile::parse(with_creation_date("10:yesterday!"))?;
+        assert_eq!(tf.creation_date, None);
+        let tf = TorrentFile::parse(bencoded)?;
+        assert!(!Torrent::from_file_torrent(&tf)?
+            .to_string()
+            .contains("Created"));
+
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_utc(-1), "1969-12-31 23:59:59 UTC");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_hash() -> Result<(), Box<dyn std::error::Error>> {
+        let torrent_file = TorrentFile::parse_from_file(&PathBuf::from("sample.torrent"), false)?;
+        let pieces = Torrent::from_file_torrent(&torrent_file)?
+            .to_download_request()?
+            .pieces;
+
+        let hash = info_hash(
+            "sample.txt",
+            32768,
+            &pieces,
+            InfoContent::File { length: 92063 },
+        )?;
+        assert_eq!(hash.to_hex(), "d69f91e6b2ae4c542468d1073a71d4ea13879a7f");
+
+        let files = [FileEntry {
+            length: 92063,
+            path: vec![String::from("sample.txt")],
+        }];
+        let multi = info_hash("sample", 32768, &pieces, InfoContent::Files(&files))?;
+        assert!(multi != hash);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_info() -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read("sample.torrent")?;
+        let info = raw_info(&content)?;
+        assert!(info.starts_with(b"d6:length"));
+        let torrent = Torrent::from_file_torrent(&TorrentFile::parse(content.clone())?)?;
+        assert_eq!(Hash::hash(info), *torrent.info_hash());
+
+        assert!(raw_info(b"d8:announce1:xe").is_err());
+        assert!(raw_info(b"i5e").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_info_non_canonical() -> Result<(), Box<dyn std::error::Error>> {
+        // The keys of the info dict aren't sorted.
+        let info = [
+            &b"d4:name1:x6:lengthi11e12:piece lengthi16384e6:pieces20:"[..],
+            &[7; 20],
+            b"e",
+        ]
+        .concat();
+        let content = [
+            &b"d8:announce17:http://t.example/10:created by1:y4:info"[..],
+            &info,
+            b"e",
+        ]
+        .concat();
+
+        assert_eq!(raw_info(&content)?, info);
+        let torrent = Torrent::from_file_torrent(&TorrentFile::parse(content)?)?;
+        assert_ne!(Hash::hash(&info), *torrent.info_hash());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint() -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read("sample.torrent")?;
+        for check in lint(&content) {
+            assert!(check.result.is_ok(), "{}: {:?}", check.name, check.result);
+        }
+
+        let mut tf = TorrentFile::parse(content)?;
+        tf.info.piece_length = 30000;
+        tf.info.pieces.pop();
+        let failed: Vec<_> = lint(&serde_bencode::to_bytes(&tf)?)
+            .into_iter()
+            .filter(|check| check.result.is_err())
+            .map(|check| check.name)
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                "pieces are whole hashes",
+                "piece length is a power of two",
+                "piece count matches length",
+                "info hash matches the raw bytes",
+            ]
+        );
+
+        let checks = lint(b"d4:infod4:name1:aee");
+        assert_eq!(checks.len(), 2);
+        assert!(checks[0].result.is_ok());
+        assert!(checks[1].result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(1000), 16 * 1024);
+        // 700 MiB, 1400 pieces.
+        assert_eq!(auto_piece_length(700 << 20), 512 * 1024);
+        assert_eq!(auto_piece_length(4 << 30), 4 << 20);
+        assert_eq!(auto_piece_length(1 << 40), 16 << 20);
+        // Exactly 2000 pieces fit, one more byte doubles the length.
+        assert_eq!(auto_piece_length(2000 * 65536), 65536);
+        assert_eq!(auto_piece_length(2000 * 65536 + 1), 131072);
+    }
+
+    #[test]
+    fn test_hash_pieces() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("abcde");
+        std::fs::write(&path, b"abcde")?;
+
+        let hex: Vec<_> = hash_pieces(std::slice::from_ref(&path), 2)?
+            .iter()
+            .map(Hash::to_hex)
+            .collect();
+        assert_eq!(
+            hex,
+            vec![
+                "da23614e02469a0d7c7bd1bdab5c9c474b1904dc",
+                "034778198a045c1ed80be271cdd029b76874f6fc",
+                "58e6b3a414a1e090dfc6029add0f3555ccba127f",
+            ]
+        );
+
+        // Pieces span file boundaries.
+        let other = dir.path().join("c");
+        std::fs::write(&other, b"c")?;
+        std::fs::write(&path, b"ab")?;
+        let pieces = hash_pieces(&[path, other], 4)?;
+        assert_eq!(
+            pieces.iter().map(Hash::to_hex).collect::<Vec<_>>(),
+            vec!["a9993e364706816aba3e25717850c26c9cd0d89d"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("content");
+        std::fs::create_dir_all(source.join("sub"))?;
+        let data: Vec<u8> = (0..100).map(|_| rand::random::<u8>()).collect();
+        std::fs::write(source.join("b.txt"), &data[..30])?;
+        std::fs::write(source.join("sub").join("a.txt"), &data[30..])?;
+        let tracker = Url::parse("http://tracker.example/announce")?;
+
+        let tf = TorrentFile::create(&source, &tracker, 16)?;
+        let content = tf.to_bytes()?;
+        for check in lint(&content) {
+            assert!(check.result.is_ok(), "{}: {:?}", check.name, check.result);
+        }
+        let torrent = Torrent::from_file_torrent(&TorrentFile::parse(content)?)?;
+        assert_eq!(torrent.tracker_url(), &tracker);
+        assert_eq!(torrent.length(), 100);
+        let expected: Vec<_> = data.chunks(16).map(Hash::hash).collect();
+        assert!(torrent.pieces() == expected.as_slice());
+        let paths: Vec<_> = torrent.files()?.into_iter().map(|span| span.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("content").join("b.txt"),
+                PathBuf::from("content").join("sub").join("a.txt"),
+            ]
+        );
+
+        let single = TorrentFile::create(&source.join("b.txt"), &tracker, 16)?;
+        let torrent = Torrent::from_file_torrent(&single)?;
+        assert_eq!(torrent.summary().split('\t').next(), Some("b.txt"));
+        assert_eq!(torrent.pieces().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_rejects_bad_piece_length() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("content");
+        std::fs::write(&source, b"data")?;
+        let tracker = Url::parse("http://tracker.example/announce")?;
+
+        for piece_length in [0, 24] {
+            let Err(err) = TorrentFile::create(&source, &tracker, piece_length) else {
+                panic!("expected piece length {} to be rejected", piece_length);
+            };
+            assert_eq!(
+                err.to_string(),
+                format!("piece length must be a power of two, got {}", piece_length)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.mkv", "season 1/episode.mkv"));
+        assert!(glob_match("ep??.mkv", "ep01.mkv"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.mkv", "episode.mkv.part"));
+        assert!(!glob_match("ep?.mkv", "ep01.mkv"));
+    }
+
+    #[test]
+    fn test_url_list_string_or_list() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tf = TorrentFile::parse_from_file(&PathBuf::from("sample.torrent"), false)?;
+        tf.url_list = None;
+        let bencoded = serde_bencode::to_bytes(&tf)?;
+        let with_url_list = |url_list: &str| {
+            let mut b = bencoded[..bencoded.len() - 1].to_vec();
+            b.extend_from_slice(format!("8:url-list{}e", url_list).as_bytes());
+            b
+        };
+
+        let single = TorrentFile::parse(with_url_list("20:http://seed.example/"))?;
+        let list = TorrentFile::parse(with_url_list(
+            "l20:http://seed.example/21:http://other.example/e",
+        ))?;
+
+        let seeds = |tf: &TorrentFile| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            let torrent = Torrent::from_file_torrent(tf)?;
+            Ok(torrent.web_seeds().iter().map(|u| u.to_string()).collect())
+        };
+        assert_eq!(seeds(&single)?, vec!["http://seed.example/sample.txt"]);
+        assert_eq!(
+            seeds(&list)?,
+            vec![
+                "http://seed.example/sample.txt",
+                "http://other.example/sample.txt"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_tracker_keeps_info_hash() -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read("sample.torrent")?;
+        // Append an announce-list, keys don't need to be sorted for parsing.
+        let mut with_list = content[..content.len() - 1].to_vec();
+        with_list.extend_from_slice(b"13:announce-listll20:http://old.example/aeee");
+        let tracker = Url::parse("http://new.example/announce")?;
+
+        let before = Torrent::from_file_torrent(&TorrentFile::parse(content.clone())?)?;
+        for (input, clear) in [(&content, false), (&with_list, true)] {
+            let edited = replace_tracker(input, &tracker, clear)?;
+            let tf = TorrentFile::parse(edited.clone())?;
+            let after = Torrent::from_file_torrent(&tf)?;
+
+            assert_eq!(after.tracker_url, tracker);
+            assert_eq!(after.info.hash, before.info.hash);
+            assert!(!edited
+                .windows(b"announce-list".len())
+                .any(|w| w == b"announce-list"));
+        }
+
+        // Kept unless asked to clear it.
+        let edited = replace_tracker(&with_list, &tracker, false)?;
+        let (entries, _) = bencode::split_dict(&edited)?;
+        assert!(entries.contains(&(
+            b"announce-list".as_slice(),
+            b"ll20:http://old.example/aee".as_slice()
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_eq() {
+        let data = b"some piece data".to_vec();
+        let hash = Hash::hash(&data);
+        let mut off_by_one = *hash.get_hash();
+        off_by_one[19] ^= 1;
+
+        assert!(hash == Hash::hash(&data));
+        assert!(hash != Hash::new(off_by_one));
+        assert!(!constant_time_eq(hash.get_hash(), &hash.get_hash()[..19]));
+    }
+
+    #[test]
+    fn test_hash_algorithms() -> Result<(), Box<dyn std::error::Error>> {
+        let sha1 = Hash::from_bytes(HashAlgorithm::Sha1, &[1; 20])?;
+        let sha256 = Hash::from_bytes(HashAlgorithm::Sha256, &[1; 32])?;
+        assert!(Hash::from_bytes(HashAlgorithm::Sha256, &[1; 20]).is_err());
+
+        assert_eq!(sha256.algorithm(), HashAlgorithm::Sha256);
+        // Same wire representation, but different digests.
+        assert_eq!(sha1.get_hash(), sha256.get_hash());
+        assert!(sha1 != sha256);
+
+        let data = b"data".to_vec();
+        assert!(Hash::digest(HashAlgorithm::Sha1, &data)? == Hash::hash(&data));
+
+        assert!(Hash::from_hex(&sha1.to_hex())? == sha1);
+        assert!(Hash::from_hex(&sha256.to_hex())? == sha256);
+        assert!(Hash::from_hex("D69F91E6B2AE4C542468D1073A71D4EA13879A7F").is_ok());
+        assert!(Hash::from_hex("d69f91e6").is_err());
+        assert!(Hash::from_hex(&"zz".repeat(20)).is_err());
+        // Multi-byte characters must not split a digit pair.
+        assert!(Hash::from_hex(&format!("é{}", "0".repeat(38))).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_hex_rejects_signs() {
+        let Err(err) = Hash::from_hex(&"+f".repeat(20)) else {
+            panic!("expected signs to be rejected");
+        };
+        assert!(err.to_string().contains("not a hex encoded hash"), "{err}");
+        assert!(Hash::from_hex(&format!("-1{}", "0".repeat(38))).is_err());
+    }
+
+    #[test]
+    fn test_zero_piece_length_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tf = TorrentFile::parse(std::fs::read("sample.torrent")?)?;
+        tf.info.piece_length = 0;
+
+        let Err(err) = Torrent::from_file_torrent(&tf) else {
+            panic!("expected a piece length of 0 to be rejected");
+        };
+        assert_eq!(err.to_string(), "piece length must not be 0");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_piece_count_must_match_length() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tf = TorrentFile::parse(std::fs::read("sample.torrent")?)?;
+        // One hash more than the 3 pieces of the sample need.
+        tf.info.pieces.extend_from_slice(&[0; 20]);
+
+        let Err(err) = Torrent::from_file_torrent(&tf) else {
+            panic!("expected an extra piece hash to be rejected");
+        };
+        assert_eq!(
+            err.to_string(),
+            "92063 bytes need 3 pieces of 32768 bytes, info has 4 piece hashes"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_v2_torrent_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read("sample.torrent")?;
+        let mut tf = TorrentFile::parse(content)?;
+        tf.info.meta_version = Some(2);
+        // Re-encode, so the key is really parsed.
+        let tf = TorrentFile::parse(serde_bencode::to_bytes(&tf)?)?;
+
+        let Err(err) = Torrent::from_file_torrent(&tf) else {
+            panic!("expected v2 torrent to be rejected");
+        };
+        assert_eq!(
+            err.to_string(),
+            "BitTorrent v2 torrents are not yet supported"
+        );
+
+        // v2-only torrents have no pieces.
+        let v2_only = b"d8:announce15:http://tracker/10:created by4:test4:infod9:file treed1:ad0:d6:lengthi1e11:pieces root32:01234567890123456789012345678901eee12:meta versioni2e4:name1:a12:piece lengthi16384eee";
+        let tf = TorrentFile::parse(v2_only.to_vec())?;
+        let Err(err) = Torrent::from_file_torrent(&tf) else {
+            panic!("expected v2-only torrent to be rejected");
+        };
+        assert_eq!(
+            err.to_string(),
+            "BitTorrent v2 torrents are not yet supported"
+        );
+
+        Ok(())
+    }
+
+    /// a.txt (6 bytes) and dir/b.txt (5 bytes) in pieces of 4 bytes.
+    fn multi_file_torrent() -> TorrentFile {
+        TorrentFile {
+            tracker_url: String::from("http://localhost/announce"),
+            created_by: String::from("test"),
+            creation_date: None,
+            info: FileInfo {
+                file_tree: None,
+                files: Some(vec![
+                    FileEntry {
+                        length: 6,
+                        path: vec![String::from("a.txt")],
+                    },
+                    FileEntry {
+                        length: 5,
+                        path: vec![String::from("dir"), String::from("b.txt")],
+                    },
+                ]),
+                length: None,
+                meta_version: None,
+                name: String::from("sample"),
+                piece_length: 4,
+                pieces: vec![0; 60],
+            },
+            announce_list: Some(vec![
+                vec![
+                    String::from("http://a.example/announce"),
+                    String::from("invalid"),
+                ],
+                vec![String::from("http://b.example/announce")],
+            ]),
+            url_list: Some(vec![String::from("http://seed.example/files")]),
+        }
+    }
+
+    #[test]
+    fn test_multi_file_torrent() -> Result<(), Box<dyn std::error::Error>> {
+        let tf = multi_file_torrent();
+        let parsed = TorrentFile::parse(serde_bencode::to_bytes(&tf)?)?;
+        assert_eq!(tf, parsed);
+
+        let torrent = Torrent::from_file_torrent(&parsed)?;
+        let tiers: Vec<Vec<_>> = torrent
+            .to_peer_request()
+            .tiers()
+            .iter()
+            .map(|tier| tier.iter().map(|u| u.to_string()).collect())
+            .collect();
+        assert_eq!(
+            tiers,
+            vec![
+                vec!["http://a.example/announce"],
+                vec!["http://b.example/announce"]
+            ]
+        );
+        let req = torrent.to_download_request()?;
+        assert_eq!(req.length, 11);
+        let files = req.files.as_ref().expect("multi-file torrent");
+        // Multi-file seeds name a directory.
+        assert_eq!(req.web_seeds[0].as_str(), "http://seed.example/files/");
+        assert_eq!(
+            files[1].path,
+            PathBuf::from("sample").join("dir").join("b.txt")
+        );
+
+        // b.txt spans bytes 6..11, so it starts in the middle of piece 1.
+        assert_eq!(req.pieces_for_files(&[1])?, vec![1, 2]);
+        assert_eq!(req.pieces_for_files(&[0, 1])?, vec![0, 1, 2]);
+        assert!(req.pieces_for_files(&[2]).is_err());
+
+        let spans = torrent.files()?;
+        assert_eq!(
+            spans[0],
+            FileSpan {
+                path: PathBuf::from("sample").join("a.txt"),
+                length: 6,
+                offset: 0,
+                pieces: 0..=1,
+                first_piece_begin: 0,
+                last_piece_end: 2,
+            }
+        );
+
+        // Starts and ends mid-piece.
+        assert_eq!(spans[1].offset, 6);
+        assert_eq!(spans[1].pieces, 1..=2);
+        assert_eq!(spans[1].first_piece_begin, 2);
+        assert_eq!(spans[1].last_piece_end, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_tracker() -> Result<(), Box<dyn std::error::Error>> {
+        // Replaces the announce-list as well, not only the announce URL.
+        let torrent = Torrent::from_file_torrent(&multi_file_torrent())?
+            .with_tracker(Url::parse("http://other.example/announce")?);
+        assert_eq!(
+            torrent.to_peer_request().tiers(),
+            vec![vec![Url::parse("http://other.example/announce")?]]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_selected_files() -> Result<(), Box<dyn std::error::Error>> {
+        let torrent = Torrent::from_file_torrent(&multi_file_torrent())?;
+        assert_eq!(torrent.to_peer_request().left, 11);
+
+        // b.txt needs pieces 1 and 2, the last one is 3 bytes short.
+        let torrent = torrent.with_selected_files(&[1])?;
+        assert_eq!(torrent.to_peer_request().left, 4 + 3);
+        assert!(torrent.with_selected_files(&[2]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_files() -> Result<(), Box<dyn std::error::Error>> {
+        let torrent = Torrent::from_file_torrent(&multi_file_torrent())?;
+        assert_eq!(torrent.select_files("1")?, vec![1]);
+        assert_eq!(torrent.select_files("*.txt")?, vec![0, 1]);
+        assert_eq!(torrent.select_files("dir/*, 1")?, vec![1]);
+        assert_eq!(torrent.select_files("?.txt")?, vec![0]);
+        assert!(torrent.select_files("*.mkv").is_err());
+        assert!(torrent.select_files("2").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_beyond_4gib() -> Result<(), Box<dyn std::error::Error>> {
+        let length = 5_000_000_000u64;
+        let piece_length = 1 << 22;
+        let tf = TorrentFile {
+            tracker_url: String::from("http://localhost/announce"),
+            created_by: String::from("test"),
+            creation_date: None,
+            info: FileInfo {
+                file_tree: None,
+                files: None,
+                length: Some(length),
+                meta_version: None,
+                name: String::from("big.iso"),
+                piece_length,
+                pieces: vec![0; length.div_ceil(u64::from(piece_length)) as usize * 20],
+            },
+            announce_list: None,
+            url_list: None,
+        };
+        let content = serde_bencode::to_bytes(&tf)?;
+        assert!(String::from_utf8_lossy(&content).contains("6:lengthi5000000000e"));
+        let parsed = TorrentFile::parse(content)?;
+        assert_eq!(parsed.info.length, Some(length));
+
+        let torrent = Torrent::from_file_torrent(&parsed)?;
+        assert_eq!(torrent.length(), length);
+        assert_eq!(torrent.pieces().len(), 1193);
+        assert_eq!(torrent.to_download_request()?.length as u64, length);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_spans_with_empty_file() {
+        let file = |length| DownloadFile {
+            path: PathBuf::from("f"),
+            length,
+        };
+        let spans = file_spans(&[file(4), file(0), file(5)], 4);
+
+        assert_eq!(spans[0].pieces, 0..=0);
+        assert_eq!(spans[0].last_piece_end, 4);
+        assert_eq!(spans[1].pieces, 1..=1);
+        assert_eq!(
+            (spans[1].first_piece_begin, spans[1].last_piece_end),
+            (0, 0)
+        );
+        assert_eq!(spans[2].pieces, 1..=2);
+        assert_eq!(spans[2].last_piece_end, 1);
+    }
+
+    #[test]
+    fn test_file_entry_nested_path() -> Result<(), Box<dyn std::error::Error>> {
+        let entry = |path: &[&str]| FileEntry {
+            length: 1,
+            path: path.iter().map(|s| s.to_string()).collect(),
+        };
+
+        let file = entry(&["dir", "subdir", "file.txt"]).to_download_file("name")?;
+        let expected: PathBuf = ["name", "dir", "subdir", "file.txt"].iter().collect();
+        assert_eq!(file.path, expected);
+
+        assert!(entry(&[]).to_download_file("name").is_err());
+        assert!(entry(&["dir", "", "file.txt"])
+            .to_download_file("name")
+            .is_err());
+        assert!(entry(&["..", "file.txt"]).to_download_file("name").is_err());
+        assert!(entry(&[".", "file.txt"]).to_download_file("name").is_err());
+        assert!(entry(&["dir/../..", "file.txt"])
+            .to_download_file("name")
+            .is_err());
+        assert!(entry(&["/etc", "passwd"]).to_download_file("name").is_err());
+        assert!(entry(&["file.txt"]).to_download_file("..").is_err());
+
         Ok(())
     }
 }
@@ -1,23 +1,32 @@
 use core::fmt;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::SeekFrom;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, Sender};
 
 use anyhow::{anyhow, bail, Context, Result};
-use log::debug;
+use log::{debug, info, warn};
 use tokio::net::TcpStream;
-use tokio::task::JoinHandle;
+use tokio::task::{JoinHandle, JoinSet};
 
+use crate::bitfield::Bitfield;
+use crate::cancel::{CancelToken, Cancelled};
 use crate::peers::{Peer, PeerID, Peers};
-use crate::torrent::{DownloadRequest, Hash};
+use crate::progress::{Eta, Progress, Throughput};
+use crate::scheduler::{BlockRequest, BlockScheduler, NextBlock};
+use crate::stats::{
+    DownloadStats, MessageCounter, MeteredStream, PeerScore, PeerScores, TransferTotals,
+};
+use crate::torrent::{DownloadFile, DownloadRequest, Hash};
+use crate::webseed::WebSeed;
 
 const HANDSHAKE_BYTE_SIZE: usize = 68;
 // PORT is for now just hardcoded.
-const BLOCK_SIZE: usize = 16 * 1024;
+pub(crate) const BLOCK_SIZE: usize = 16 * 1024;
 const MAX_PAYLOAD_LEN: usize = 1048576;
 
 const LENGTH_PREFIX_SIZE_BYTES: usize = 4;
@@ -34,19 +43,138 @@ const REQUEST_PAYLOAD_BYTES_COUNT: usize = INDEX_SIZE_BYTES + BEGIN_SIZE_BYTES +
 const REQUEST_BYTES_COUNT: usize =
     LENGTH_PREFIX_SIZE_BYTES + ID_SIZE_BYTES + REQUEST_PAYLOAD_BYTES_COUNT;
 
+const DEFAULT_MAX_OUTSTANDING_PIECES: usize = 16;
+const MIN_RESULT_CHANNEL_CAPACITY: usize = 4;
+const MAX_RESULT_CHANNEL_CAPACITY: usize = 64;
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+// Outstanding block requests per peer connection.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 5;
+const DEFAULT_RECONNECT_ATTEMPTS: usize = 3;
+const DEFAULT_RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REQUEST_TIMEOUTS: usize = 2;
+
+/// Tunables for `download_file`.
+pub struct DownloadConfig {
+    /// Upper bound on pieces that were handed out to peer workers but are not yet written to
+    /// disk. New jobs are only handed out once the writer catches up, so the piece buffers held
+    /// in memory never exceed `max_outstanding_pieces * piece_length` bytes, regardless of how
+    /// many (fast) peers are downloading.
+    ///
+    /// With `PieceOrder::Ascending` this also caps the pieces waiting for a missing
+    /// predecessor: once they fill every slot no further piece is started until the gap is
+    /// filled.
+    pub max_outstanding_pieces: usize,
+    /// Upper bound on pieces partially downloaded at once, each holding a buffer. Unlike for
+    /// `max_outstanding_pieces`, complete pieces waiting to be written don't count, so a new
+    /// piece is started as soon as one is complete. None only limits the outstanding pieces.
+    pub max_active_pieces: Option<usize>,
+    /// Indices of the files to download from a multi-file torrent, None downloads everything.
+    /// Only the pieces overlapping these files are requested.
+    pub selected_files: Option<Vec<usize>>,
+    /// Capacity of the channel passing downloaded pieces to the writer. None derives it from the
+    /// peer count, see `result_channel_capacity`.
+    pub result_channel_capacity: Option<usize>,
+    /// Receives a `Progress` event after every written piece.
+    pub progress_tx: Option<mpsc::UnboundedSender<Progress>>,
+    /// How often a worker reconnects to its peer after the connection dropped, before giving up.
+    pub reconnect_attempts: usize,
+    /// Wait before the first reconnect, doubled for every further attempt.
+    pub reconnect_backoff: Duration,
+    /// Block requests kept outstanding per peer connection. They aren't limited to a single
+    /// piece: once every block of the started pieces is requested, the next piece is started, so
+    /// a fast peer with a long round trip isn't left idle while the last blocks of a piece
+    /// arrive. Blocks are matched to their piece by the index of the Piece message.
+    pub pipeline_depth: usize,
+    /// Compare every downloaded piece against its hash. Only disable this for benchmarking
+    /// downloads from trusted peers, corrupt data is written as is otherwise.
+    pub verify: bool,
+    /// Stats file the downloaded bytes are added to, see `TransferTotals`.
+    pub stats_file: Option<PathBuf>,
+    /// Peer cache the peers that delivered a verified piece are saved to, see
+    /// `Peers::save_cached`. Saved after a failure as well, nothing is saved without `verify`.
+    pub peer_cache: Option<PathBuf>,
+    /// How long to wait for a peer to answer a block request. On timeout the outstanding
+    /// requests are cancelled and their blocks go to other peers, the connection stays open for
+    /// other pieces.
+    pub request_timeout: Duration,
+    /// Timeouts after which a peer counts as too slow and is given up, unlike a peer that
+    /// merely dropped its connection.
+    pub max_request_timeouts: usize,
+    /// Stops the download with a `Cancelled` error once triggered. The file is flushed first.
+    pub cancel: Option<CancelToken>,
+    /// Bytes of downloaded pieces `download_file` collects before writing them, so neighbouring
+    /// pieces are written sequentially. 0, the default, writes every piece as soon as it arrives.
+    pub write_buffer_size: usize,
+    /// Count the bytes and messages of every peer connection, reported as
+    /// `PeerStats::connection`. Off, the connections aren't wrapped at all.
+    pub connection_metrics: bool,
+    /// Times pieces may be handed out again in total, after a hash mismatch or a failed or too
+    /// slow peer, before the download fails with a `RetriesExhausted` error listing the missing
+    /// pieces. None retries as long as there are peers.
+    pub max_retries: Option<usize>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            max_outstanding_pieces: DEFAULT_MAX_OUTSTANDING_PIECES,
+            max_active_pieces: None,
+            selected_files: None,
+            result_channel_capacity: None,
+            progress_tx: None,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            reconnect_backoff: DEFAULT_RECONNECT_BACKOFF,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            verify: true,
+            stats_file: None,
+            peer_cache: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            max_request_timeouts: DEFAULT_MAX_REQUEST_TIMEOUTS,
+            cancel: None,
+            write_buffer_size: 0,
+            connection_metrics: false,
+            max_retries: None,
+        }
+    }
+}
+
+/// Azureus style peer id prefixes (`-qB4630-`) and the clients using them.
+const AZUREUS_CLIENTS: &[(&[u8; 2], &str)] = &[
+    (b"AZ", "Vuze"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent (rakshasa)"),
+    (b"lt", "libtorrent (rasterbar)"),
+    (b"qB", "qBittorrent"),
+    (b"TR", "Transmission"),
+    (b"UT", "\u{b5}Torrent"),
+    (b"UM", "\u{b5}Torrent for Mac"),
+    (b"WW", "WebTorrent"),
+];
+
+/// Shadow style peer id prefixes (`S58B-----`) and the clients using them.
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'M', "BitTorrent"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+];
+
 pub struct Handshake {
     info_hash: Hash,
+    reserved: [u8; 8],
     peer_id: Vec<u8>,
 }
 
 impl fmt::Display for Handshake {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let hex_representation: String = self
-            .peer_id
-            .iter()
-            .map(|byte| format!("{:02x}", byte))
-            .collect();
-        writeln!(f, "Peer ID: {}", hex_representation)
+        writeln!(f, "Peer ID: {}", self.peer_id_hex())
     }
 }
 
@@ -54,10 +182,51 @@ impl Handshake {
     fn new(info_hash: &Hash, peer_id: &PeerID) -> Handshake {
         Handshake {
             info_hash: info_hash.clone(),
+            reserved: [0; 8],
             peer_id: peer_id.as_bytes().to_vec(),
         }
     }
 
+    pub fn peer_id_bytes(&self) -> &[u8] {
+        &self.peer_id
+    }
+
+    pub fn peer_id_hex(&self) -> String {
+        self.peer_id
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// The extensions the peer supports, e.g. bit 0x10 of byte 5 for the extension protocol.
+    pub fn reserved_bytes(&self) -> [u8; 8] {
+        self.reserved
+    }
+
+    /// Name of the peer's client decoded from its peer id, "unknown" if the id doesn't follow
+    /// the Azureus (`-qB4630-...`) or Shadow (`S58B-----...`) convention.
+    pub fn client_name(&self) -> &'static str {
+        let id = &self.peer_id;
+        if id.len() >= 8 && id[0] == b'-' && id[7] == b'-' {
+            return AZUREUS_CLIENTS
+                .iter()
+                .find(|(prefix, _)| id[1..3] == prefix[..])
+                .map_or("unknown", |(_, name)| name);
+        }
+        // Version digits and dots, padded with dashes.
+        let versioned = id.len() >= 6
+            && id[1..6]
+                .iter()
+                .all(|b| b.is_ascii_alphanumeric() || *b == b'.' || *b == b'-');
+        match id.first() {
+            Some(first) if versioned => SHADOW_CLIENTS
+                .iter()
+                .find(|(prefix, _)| prefix == first)
+                .map_or("unknown", |(_, name)| name),
+            _ => "unknown",
+        }
+    }
+
     fn to_bytes(&self) -> [u8; HANDSHAKE_BYTE_SIZE] {
         /*
             length of the protocol string (BitTorrent protocol) which is 19 (1 byte)
@@ -73,7 +242,7 @@ impl Handshake {
 
         out[0] = PROTOCOL_LEN;
         out[1..20].copy_from_slice(&PROTOCOL.as_bytes());
-        // out[20..28] -> Reserved
+        out[20..28].copy_from_slice(&self.reserved);
         out[28..48].copy_from_slice(self.info_hash.get_hash());
         out[48..68].copy_from_slice(&self.peer_id);
 
@@ -87,61 +256,24 @@ impl Handshake {
 
         Ok(Handshake {
             info_hash: Hash::new(info_hash),
+            reserved: data[20..28].try_into()?,
             peer_id: data[48..68].to_vec(),
         })
     }
 }
 
-struct PeerMessageReader {
-    meta_buf: [u8; 5],
-}
-
-impl PeerMessageReader {
-    fn new() -> Self {
-        Self { meta_buf: [0; 5] }
-    }
-    fn ident_byte(&self) -> u8 {
-        self.meta_buf[4]
-    }
-
-    fn payload_len(&self) -> usize {
-        let mut pl = u32::from_be_bytes(
-            self.meta_buf[0..4]
-                .try_into()
-                .expect("[u8; 5] into [u8; 4] will always work"),
-        );
-        // Take off 1 from the length as the ident byte is already read.
-        if pl > 0 {
-            pl -= 1
-        }
-        pl as usize
-    }
-
-    async fn from_stream(&mut self, s: &mut TcpStream) -> Result<PeerMessage> {
-        s.read_exact(&mut self.meta_buf).await?;
-        let payload_len = self.payload_len();
-        if payload_len > MAX_PAYLOAD_LEN {
-            bail!(
-                "message specifies too large payload length: allowed {} bytes wants {} bytes",
-                MAX_PAYLOAD_LEN,
-                payload_len
-            );
-        }
-        let mut payload_buf = vec![0; payload_len];
-        s.read_exact(&mut payload_buf).await?;
-        let pm = PeerMessage::from_bytes(self.ident_byte(), &payload_buf)?;
-
-        Ok(pm)
-    }
-}
-
 #[derive(Debug)]
 enum PeerMessage {
-    Bitfield,
+    /// Raw payload, its size depends on the torrent, see `Bitfield::from_bytes`.
+    Bitfield(Vec<u8>),
     Interested,
     Unchoke,
     Request(RequestPayload),
     Piece(PiecePayload),
+    /// Withdraws a Request, the peer may still send the block if it was already on its way.
+    Cancel(RequestPayload),
+    /// DHT port of the peer (BEP 5). Not used until DHT is supported.
+    Port(u16),
 }
 
 impl PeerMessage {
@@ -149,7 +281,7 @@ impl PeerMessage {
         match ident {
             1 => Ok(Self::Unchoke),
             2 => Ok(Self::Interested),
-            5 => Ok(Self::Bitfield),
+            5 => Ok(Self::Bitfield(payload.to_vec())),
             6 => {
                 let msg = RequestPayload::from_bytes(payload)?;
                 Ok(Self::Request(msg))
@@ -158,45 +290,194 @@ impl PeerMessage {
                 let msg = PiecePayload::from_bytes(payload)?;
                 Ok(Self::Piece(msg))
             }
+            8 => {
+                let msg = RequestPayload::from_bytes(payload)?;
+                Ok(Self::Cancel(msg))
+            }
+            9 => {
+                let port = payload
+                    .try_into()
+                    .map(u16::from_be_bytes)
+                    .map_err(|_| anyhow!("port message must be 2 bytes, got {}", payload.len()))?;
+                Ok(Self::Port(port))
+            }
             other => bail!("unknown byte message id: {}", other),
         }
     }
 
+    /// Reads the next message, skipping keep-alives.
+    async fn read<R: AsyncRead + Unpin>(r: &mut R) -> Result<PeerMessage> {
+        let mut len = 0;
+        while len == 0 {
+            len = r.read_u32().await? as usize;
+        }
+        // The length includes the ident byte.
+        let payload_len = len - ID_SIZE_BYTES;
+        if payload_len > MAX_PAYLOAD_LEN {
+            bail!(
+                "message specifies too large payload length: allowed {} bytes wants {} bytes",
+                MAX_PAYLOAD_LEN,
+                payload_len
+            );
+        }
+        let ident = r.read_u8().await?;
+        let mut payload_buf = vec![0; payload_len];
+        r.read_exact(&mut payload_buf).await?;
+
+        PeerMessage::from_bytes(ident, &payload_buf)
+    }
+
+    /// Like `read`, but skips messages that need no reaction from us.
+    async fn read_relevant<R: AsyncRead + Unpin>(r: &mut R) -> Result<PeerMessage> {
+        loop {
+            match Self::read(r).await? {
+                PeerMessage::Port(port) => debug!("Ignoring DHT port {} of peer.", port),
+                msg => return Ok(msg),
+            }
+        }
+    }
+
+    async fn write<W: AsyncWrite + Unpin>(&self, w: &mut W) -> Result<()> {
+        w.write_all(&self.to_bytes()).await?;
+
+        Ok(())
+    }
+
     fn to_bytes(&self) -> Vec<u8> {
         match self {
             PeerMessage::Unchoke => vec![0, 0, 0, 1, 1],
             PeerMessage::Interested => vec![0, 0, 0, 1, 2],
-            PeerMessage::Bitfield => vec![0, 0, 0, 1, 5],
+            PeerMessage::Bitfield(bits) => {
+                let mut out = Vec::with_capacity(5 + bits.len());
+                out.extend_from_slice(&(bits.len() as u32 + 1).to_be_bytes());
+                out.push(5);
+                out.extend_from_slice(bits);
+                out
+            }
             PeerMessage::Request(msg) => {
                 let mut out: Vec<u8> = Vec::with_capacity(REQUEST_BYTES_COUNT);
-                out.extend_from_slice(&REQUEST_MESSAGE_LENGTH_BYTES.to_be_bytes());
+                out.extend_from_slice(&(REQUEST_MESSAGE_LENGTH_BYTES as u32).to_be_bytes());
                 out.extend_from_slice(&6u8.to_be_bytes());
                 msg.append_bytes(&mut out);
                 out
             }
-            PeerMessage::Piece(msg) => msg.to_bytes().to_vec(),
+            PeerMessage::Piece(msg) => msg.to_bytes(),
+            PeerMessage::Cancel(msg) => {
+                let mut out: Vec<u8> = Vec::with_capacity(REQUEST_BYTES_COUNT);
+                out.extend_from_slice(&(REQUEST_MESSAGE_LENGTH_BYTES as u32).to_be_bytes());
+                out.extend_from_slice(&8u8.to_be_bytes());
+                msg.append_bytes(&mut out);
+                out
+            }
+            PeerMessage::Port(port) => {
+                let mut out = vec![0, 0, 0, 3, 9];
+                out.extend_from_slice(&port.to_be_bytes());
+                out
+            }
         }
     }
 }
 
-struct FullPiece {
-    data: Vec<u8>,
-    piece: Piece,
+/// Reads peer messages through a buffer. Unlike `PeerMessage::read`, a read cancelled by a
+/// timeout loses no bytes, the next read continues with the partly received message.
+#[derive(Default)]
+struct MessageReader {
+    buf: Vec<u8>,
+}
+
+impl MessageReader {
+    /// Like `PeerMessage::read_relevant`, but cancel safe.
+    async fn read_relevant<R: AsyncRead + Unpin>(&mut self, r: &mut R) -> Result<PeerMessage> {
+        loop {
+            match self.next_message()? {
+                Some(PeerMessage::Port(port)) => debug!("Ignoring DHT port {} of peer.", port),
+                Some(msg) => return Ok(msg),
+                // A single read is cancel safe, its bytes are buffered before the next await.
+                None => {
+                    self.buf.reserve(BLOCK_SIZE);
+                    if r.read_buf(&mut self.buf).await? == 0 {
+                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the next complete message out of the buffer, skipping keep-alives.
+    fn next_message(&mut self) -> Result<Option<PeerMessage>> {
+        loop {
+            let Some(prefix) = self.buf.get(..LENGTH_PREFIX_SIZE_BYTES) else {
+                return Ok(None);
+            };
+            let len = u32::from_be_bytes(prefix.try_into()?) as usize;
+            if len == 0 {
+                self.buf.drain(..LENGTH_PREFIX_SIZE_BYTES);
+                continue;
+            }
+            // The length includes the ident byte.
+            let payload_len = len - ID_SIZE_BYTES;
+            if payload_len > MAX_PAYLOAD_LEN {
+                bail!(
+                    "message specifies too large payload length: allowed {} bytes wants {} bytes",
+                    MAX_PAYLOAD_LEN,
+                    payload_len
+                );
+            }
+            if self.buf.len() < LENGTH_PREFIX_SIZE_BYTES + len {
+                return Ok(None);
+            }
+            let frame: Vec<u8> = self.buf.drain(..LENGTH_PREFIX_SIZE_BYTES + len).collect();
+            let (ident, payload) = (
+                frame[LENGTH_PREFIX_SIZE_BYTES],
+                &frame[LENGTH_PREFIX_SIZE_BYTES + ID_SIZE_BYTES..],
+            );
+
+            return PeerMessage::from_bytes(ident, payload).map(Some);
+        }
+    }
+}
+
+/// A downloaded piece whose hash was verified.
+pub struct FullPiece {
+    pub(crate) data: Vec<u8>,
+    pub(crate) piece: Piece,
+}
+
+impl FullPiece {
+    pub fn index(&self) -> usize {
+        self.piece.idx
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
 }
 
 #[derive(Debug)]
 struct PiecePayload {
+    index: u32,
+    begin: u32,
     block: Vec<u8>,
 }
 
 impl PiecePayload {
-    fn to_bytes(&self) -> &[u8] {
-        unimplemented!()
+    fn to_bytes(&self) -> Vec<u8> {
+        let len = ID_SIZE_BYTES + INDEX_SIZE_BYTES + BEGIN_SIZE_BYTES + self.block.len();
+        let mut out = Vec::with_capacity(LENGTH_PREFIX_SIZE_BYTES + len);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+        out.push(7);
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.begin.to_be_bytes());
+        out.extend_from_slice(&self.block);
+        out
     }
 
     fn from_bytes(b: &[u8]) -> Result<PiecePayload> {
-        let _ = u32::from_be_bytes(b[..4].try_into()?);
-        let _ = u32::from_be_bytes(b[4..8].try_into()?);
+        if b.len() < INDEX_SIZE_BYTES + BEGIN_SIZE_BYTES {
+            bail!("piece message too short: {} bytes", b.len());
+        }
+        let index = u32::from_be_bytes(b[..4].try_into()?);
+        let begin = u32::from_be_bytes(b[4..8].try_into()?);
         let block_rest = &b[8..];
 
         let block = if block_rest.len() < BLOCK_SIZE {
@@ -206,47 +487,209 @@ impl PiecePayload {
         };
 
         Ok(PiecePayload {
+            index,
+            begin,
             block: block.to_vec(),
         })
     }
 }
 
+/// A region of the torrent's byte stream backed by a file on disk. Unselected files of a
+/// multi-file torrent have no backing file, bytes falling into them are dropped.
+struct FileTarget {
+    offset: u64,
+    length: u64,
+    file: Option<File>,
+    // Where the file's cursor is, writes continuing right there don't seek.
+    position: u64,
+}
+
 struct DownloadingFile {
     piece_len: usize,
-    file: File,
+    targets: Vec<FileTarget>,
+    // Pieces not written yet by index, see `with_write_buffer`.
+    buffer: BTreeMap<usize, Vec<u8>>,
+    buffered: usize,
+    buffer_size: usize,
 }
 
 impl DownloadingFile {
     async fn new(piece_len: usize, dest: PathBuf) -> Result<Self> {
+        let file = Self::open(dest).await?;
+
+        Ok(Self::with_targets(
+            piece_len,
+            vec![FileTarget {
+                offset: 0,
+                length: u64::MAX,
+                file: Some(file),
+                position: 0,
+            }],
+        ))
+    }
+
+    /// Lays out the files of a multi-file torrent under the `dest` directory. Only files whose
+    /// index is in `selected` (or all, if there is no selection) are created.
+    async fn new_multi(
+        piece_len: usize,
+        dest: PathBuf,
+        files: &[DownloadFile],
+        selected: Option<&[usize]>,
+    ) -> Result<Self> {
+        let mut targets = Vec::with_capacity(files.len());
+        let mut offset = 0;
+        for (idx, f) in files.iter().enumerate() {
+            let file = if selected.is_none_or(|s| s.contains(&idx)) {
+                Some(Self::open(dest.join(&f.path)).await?)
+            } else {
+                None
+            };
+            targets.push(FileTarget {
+                offset,
+                length: f.length as u64,
+                file,
+                position: 0,
+            });
+            offset += f.length as u64;
+        }
+
+        Ok(Self::with_targets(piece_len, targets))
+    }
+
+    fn with_targets(piece_len: usize, targets: Vec<FileTarget>) -> Self {
+        Self {
+            piece_len,
+            targets,
+            buffer: BTreeMap::new(),
+            buffered: 0,
+            buffer_size: 0,
+        }
+    }
+
+    /// Keeps up to `size` bytes of pieces in memory, so runs of neighbouring pieces are written
+    /// with a single seek, even if they arrived out of order. 0 writes every piece right away.
+    fn with_write_buffer(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Creates the file at `path`, along with any missing parent directories.
+    async fn open(path: PathBuf) -> Result<File> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("could not create directory {}", parent.display()))?;
+        }
         let file = tokio::fs::OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
-            .open(dest)
-            .await?;
+            .open(&path)
+            .await
+            .with_context(|| format!("could not open {}", path.display()))?;
+
+        Ok(file)
+    }
+
+    /// Writes or buffers `fp`. Returns the index and length of every piece that was written,
+    /// which need not include `fp`.
+    async fn write_full_piece(&mut self, fp: FullPiece) -> Result<Vec<(usize, usize)>> {
+        if self.buffer_size == 0 {
+            self.write_at(self.piece_offset(fp.piece.idx), &fp.data)
+                .await?;
+            return Ok(vec![(fp.piece.idx, fp.data.len())]);
+        }
+
+        self.buffered += fp.data.len();
+        self.buffer.insert(fp.piece.idx, fp.data);
+        if self.buffered >= self.buffer_size {
+            return self.write_buffer().await;
+        }
 
-        Ok(Self { piece_len, file })
+        Ok(Vec::new())
     }
 
-    async fn write_full_piece(&mut self, fp: FullPiece) -> Result<()> {
-        let idx = fp.piece.idx;
-        let offset = idx * self.piece_len;
+    /// Writes the buffered pieces, each run of consecutive pieces at once. A run stays buffered
+    /// until it was written, so a failed write loses nothing. Returns the pieces written.
+    async fn write_buffer(&mut self) -> Result<Vec<(usize, usize)>> {
+        let mut runs: Vec<Vec<usize>> = Vec::new();
+        for &idx in self.buffer.keys() {
+            match runs.last_mut() {
+                Some(run) if run.last().map(|last| last + 1) == Some(idx) => run.push(idx),
+                _ => runs.push(vec![idx]),
+            }
+        }
+
+        let mut written = Vec::new();
+        for run in runs {
+            let data = run
+                .iter()
+                .map(|idx| self.buffer[idx].as_slice())
+                .collect::<Vec<_>>()
+                .concat();
+            self.write_at(self.piece_offset(run[0]), &data).await?;
+            for idx in run {
+                if let Some(piece) = self.buffer.remove(&idx) {
+                    self.buffered -= piece.len();
+                    written.push((idx, piece.len()));
+                }
+            }
+        }
+
+        Ok(written)
+    }
 
-        self.file.seek(SeekFrom::Start(offset as u64)).await?;
-        self.file.write_all(&fp.data).await?;
+    /// Where piece `idx` starts in the torrent. Computed in u64, as torrents may be larger than
+    /// a 32-bit usize.
+    fn piece_offset(&self, idx: usize) -> u64 {
+        idx as u64 * self.piece_len as u64
+    }
+
+    /// Writes `data` at the torrent wide offset `data_start`.
+    async fn write_at(&mut self, data_start: u64, data: &[u8]) -> Result<()> {
+        let data_end = data_start + data.len() as u64;
+
+        // Data may straddle file boundaries, write each overlapping part into its file.
+        for target in self.targets.iter_mut() {
+            let target_end = target.offset.saturating_add(target.length);
+            let start = data_start.max(target.offset);
+            let end = data_end.min(target_end);
+            if start >= end {
+                continue;
+            }
+            let Some(file) = target.file.as_mut() else {
+                continue;
+            };
+
+            let position = start - target.offset;
+            if position != target.position {
+                file.seek(SeekFrom::Start(position)).await?;
+            }
+            file.write_all(&data[(start - data_start) as usize..(end - data_start) as usize])
+                .await?;
+            // tokio finishes writes in a background task, flushing waits for it, so a failed
+            // write is reported here rather than by a later one.
+            file.flush().await?;
+            target.position = position + (end - start);
+        }
 
         Ok(())
     }
+
+    /// Writes the buffered pieces and returns them.
+    async fn flush(&mut self) -> Result<Vec<(usize, usize)>> {
+        self.write_buffer().await
+    }
 }
 
-struct RequestPayloadGen {
+pub(crate) struct RequestPayloadGen {
     piece_len: usize,
     piece_idx: usize,
     progress: usize,
 }
 
 impl RequestPayloadGen {
-    fn new(piece_len: usize, piece_idx: usize) -> Self {
+    pub(crate) fn new(piece_len: usize, piece_idx: usize) -> Self {
         Self {
             piece_len,
             piece_idx,
@@ -254,7 +697,7 @@ impl RequestPayloadGen {
         }
     }
 
-    fn next(&mut self) -> Option<RequestPayload> {
+    pub(crate) fn next(&mut self) -> Option<RequestPayload> {
         if self.progress >= self.piece_len {
             return None;
         }
@@ -277,15 +720,27 @@ impl RequestPayloadGen {
 }
 
 #[derive(Debug)]
-struct RequestPayload {
-    index: u32,
-    begin: u32,
-    length: u32,
+pub(crate) struct RequestPayload {
+    pub(crate) index: u32,
+    pub(crate) begin: u32,
+    pub(crate) length: u32,
 }
 
 impl RequestPayload {
-    fn from_bytes(_: &[u8]) -> Result<RequestPayload> {
-        bail!("unexpected RequestPayload in PeerMessage, from_bytes is not implemented")
+    fn from_bytes(b: &[u8]) -> Result<RequestPayload> {
+        if b.len() != REQUEST_PAYLOAD_BYTES_COUNT {
+            bail!(
+                "request message must be {} bytes, got {}",
+                REQUEST_PAYLOAD_BYTES_COUNT,
+                b.len()
+            );
+        }
+
+        Ok(RequestPayload {
+            index: u32::from_be_bytes(b[0..4].try_into()?),
+            begin: u32::from_be_bytes(b[4..8].try_into()?),
+            length: u32::from_be_bytes(b[8..12].try_into()?),
+        })
     }
 
     fn append_bytes(&self, to: &mut Vec<u8>) {
@@ -295,38 +750,21 @@ impl RequestPayload {
     }
 }
 
-struct RequestQueue {
-    gen: RequestPayloadGen,
-}
-
-impl RequestQueue {
-    fn new(gen: RequestPayloadGen) -> Self {
-        Self { gen }
-    }
-
-    fn receiver(mut self) -> Receiver<Option<RequestPayload>> {
-        let (tx, rx) = mpsc::channel(5);
-        tokio::spawn(async move {
-            let mut req_cnt = 0;
-            loop {
-                let request = self.gen.next();
-                req_cnt += 1;
-                if tx.send(request).await.is_err() {
-                    debug!("Receiver channel closed, closing sender channel.");
-                    break;
-                }
-            }
-            debug!("Created {} async requests", req_cnt);
-        });
-
-        rx
+impl From<&BlockRequest> for RequestPayload {
+    fn from(req: &BlockRequest) -> Self {
+        Self {
+            index: req.piece_idx.try_into().expect("must fit into u32"),
+            begin: req.begin.try_into().expect("must fit into u32"),
+            length: req.length.try_into().expect("must fit into u32"),
+        }
     }
 }
 
-struct Piece {
-    hash: Hash,
-    idx: usize,
-    len: usize,
+#[derive(Clone)]
+pub(crate) struct Piece {
+    pub(crate) hash: Hash,
+    pub(crate) idx: usize,
+    pub(crate) len: usize,
 }
 
 impl fmt::Display for Piece {
@@ -342,298 +780,2800 @@ impl fmt::Display for Piece {
     }
 }
 
-struct PeerWorkerSetup {
-    info_hash: Arc<Hash>,
-    client_id: Arc<PeerID>,
-    result_tx: Arc<Sender<FullPiece>>,
-    job_rx: Arc<async_channel::Receiver<Piece>>,
-    peers: Peers,
+/// Peers to download from. Besides the initial peers, more can be sent at any time, e.g. from a
+/// periodic re-announce, and are added to the running download.
+pub struct PeerFeed {
+    rx: mpsc::UnboundedReceiver<Peers>,
+    // Number of initial peers, to size buffers by.
+    initial_len: usize,
+    wanted: Arc<tokio::sync::Notify>,
 }
 
-fn setup_peer_workers(pws: PeerWorkerSetup) -> Vec<JoinHandle<Result<(), anyhow::Error>>> {
-    // Spawn multiple job executors, one for each available Peer.
-    let mut handles = Vec::with_capacity(pws.peers.len());
-    for peer in pws.peers.into_iter() {
-        let handle = tokio::spawn({
-            let info_hash = Arc::clone(&pws.info_hash);
-            let job_rx = Arc::clone(&pws.job_rx);
-            let result_tx = Arc::clone(&pws.result_tx);
-            let client_id = Arc::clone(&pws.client_id);
-
-            async move {
-                let peer_info = peer.to_string();
-                let mut stream = setup_peer(&client_id, peer, &info_hash).await?;
-                while let Ok(job) = job_rx.recv().await {
-                    debug!("Executing Job {} on Peer {}", job, peer_info);
-                    let full_piece = download_piece(job, &mut stream).await?;
-                    result_tx.send(full_piece).await?;
-                }
-                debug!("Closing connection to Peer {}", peer_info);
-
-                Ok::<_, anyhow::Error>(())
-            }
-        });
-        handles.push(handle);
+impl PeerFeed {
+    /// Feed starting with `initial`, the sender adds more peers later on. Once the sender is
+    /// dropped, the download gives up as soon as no peer is left.
+    pub fn new(initial: Peers) -> (PeerSender, PeerFeed) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let initial_len = initial.len();
+        tx.send(initial).expect("receiver is alive");
+        let wanted = Arc::new(tokio::sync::Notify::new());
+
+        let sender = PeerSender {
+            tx,
+            wanted: Arc::clone(&wanted),
+        };
+        (
+            sender,
+            PeerFeed {
+                rx,
+                initial_len,
+                wanted,
+            },
+        )
     }
-    handles
 }
 
-pub async fn download_file(
-    client_id: PeerID,
-    peers: Peers,
-    download_req: DownloadRequest,
-    output_path: PathBuf,
-) -> Result<()> {
-    debug!("Have {} pieces to download.", download_req.pieces.len());
-    debug!("Piece len is {}.", download_req.piece_length);
-    debug!("Total length is {}.", download_req.length);
+/// Adds peers to a running download, see `PeerFeed`.
+pub struct PeerSender {
+    tx: mpsc::UnboundedSender<Peers>,
+    wanted: Arc<tokio::sync::Notify>,
+}
 
-    // Job channel for peer tasks to grab next job.
-    let (job_tx, job_rx) = async_channel::bounded::<Piece>(download_req.pieces.len());
-    // Result channel for tasks to pass pieces to.
-    let (result_tx, mut result_rx) = mpsc::channel::<FullPiece>(10); // Arbitrary num for now.
+impl PeerSender {
+    /// Fails once the download is over.
+    pub fn send(&self, peers: Peers) -> Result<(), mpsc::error::SendError<Peers>> {
+        self.tx.send(peers)
+    }
 
-    let piece_len = download_req.piece_length;
-    let last_piece_len = download_req.last_piece_len();
-    let pieces_cnt = download_req.pieces.len();
+    /// Resolves once the download is over.
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
 
-    // Spawn multiple job executors, one for each available Peer.
-    let handles = setup_peer_workers(PeerWorkerSetup {
-        info_hash: Arc::new(download_req.info_hash),
-        client_id: Arc::new(client_id),
-        result_tx: Arc::new(result_tx),
-        job_rx: Arc::new(job_rx),
-        peers,
-    });
+    /// Resolves once every worker of the download is gone and it waits for new peers, e.g. to
+    /// announce ahead of schedule. If the next peers sent are all known already, the download
+    /// gives up.
+    pub async fn wanted(&self) {
+        self.wanted.notified().await
+    }
+}
 
-    debug!("Filling up job channels.");
-    for (idx, hash) in download_req.pieces.into_iter().enumerate() {
-        let current_piece_len = if idx + 1 == pieces_cnt {
-            last_piece_len
-        } else {
-            piece_len
-        };
+impl From<Peers> for PeerFeed {
+    fn from(peers: Peers) -> Self {
+        Self::new(peers).1
+    }
+}
 
-        let piece = Piece {
-            hash,
-            idx,
-            len: current_piece_len,
-        };
+struct PeerWorkerSetup {
+    info_hash: Arc<Hash>,
+    // Of the whole torrent, to check the peers' bitfields.
+    pieces_cnt: usize,
+    client_id: Arc<PeerID>,
+    result_tx: Arc<Sender<FullPiece>>,
+    scheduler: Arc<BlockScheduler>,
+    scores: Arc<PeerScores>,
+    reconnect_attempts: usize,
+    reconnect_backoff: Duration,
+    pipeline_depth: usize,
+    request_timeout: Duration,
+    max_request_timeouts: usize,
+    connection_metrics: bool,
+}
 
-        debug!("Sending job {}", piece);
-        job_tx
-            .send(piece)
-            .await
-            .context("job channel closed unexpectedly")?;
+type PeerWorkers = JoinSet<(String, Result<()>)>;
+
+/// Keeps one worker per known peer running until every piece is downloaded. Peers from `feed`
+/// are added as they come in, peers whose worker failed for good are dropped and may be added
+/// again by a later announce. Web seeds download alongside the peers.
+///
+/// Once no worker is left, more peers are asked for through the feed. If none of the peers sent
+/// next is new, the feed is given up on, so a swarm of unreachable peers fails the download
+/// instead of waiting forever.
+async fn run_peer_pool(
+    pws: PeerWorkerSetup,
+    mut feed: PeerFeed,
+    web_seeds: Vec<WebSeed>,
+) -> Result<()> {
+    let mut workers = PeerWorkers::new();
+    for seed in web_seeds {
+        spawn_web_seed_worker(&pws, &mut workers, seed);
     }
-    job_tx.close();
-    debug!("Closed job channels.");
+    // Peers with a running worker, by address.
+    let mut active = HashSet::new();
+    // Every peer ever received, by address.
+    let mut known = HashSet::new();
+    // No worker is left and more peers were asked for.
+    let mut starved = false;
+    let mut feed_open = true;
+    let mut last_err = None;
+    loop {
+        let exhausted = pws.scheduler.retries_exhausted();
+        if workers.is_empty() && (pws.scheduler.is_finished() || exhausted.is_some() || !feed_open)
+        {
+            if let Some(exhausted) = exhausted {
+                return Err(exhausted.into());
+            }
+            break;
+        }
 
-    // Wait for results and gather them.
-    let mut df = DownloadingFile::new(piece_len, output_path).await?;
-    while let Some(full_piece) = result_rx.recv().await {
-        debug!(
-            "Received FullPiece {} at {}",
-            full_piece.piece,
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_micros()
-        );
-        df.write_full_piece(full_piece).await?;
+        tokio::select! {
+            peers = feed.rx.recv(), if feed_open => {
+                let Some(peers) = peers else {
+                    feed_open = false;
+                    continue;
+                };
+                if pws.scheduler.is_finished() || exhausted.is_some() {
+                    continue;
+                }
+                let mut fresh = false;
+                for peer in peers.iter() {
+                    fresh |= known.insert(peer.to_string());
+                }
+                if starved && !fresh {
+                    warn!("No new peers to download from, giving up");
+                    feed_open = false;
+                    continue;
+                }
+                for peer in peers.into_iter() {
+                    if active.insert(peer.to_string()) {
+                        spawn_peer_worker(&pws, &mut workers, peer);
+                    }
+                }
+                starved = workers.is_empty();
+                if starved {
+                    feed.wanted.notify_one();
+                }
+            }
+            Some(joined) = workers.join_next(), if !workers.is_empty() => {
+                let (peer_info, res) = joined?;
+                active.remove(&peer_info);
+                if let Err(e) = res {
+                    warn!("Dropping Peer {}: {:#}", peer_info, e);
+                    last_err = Some(e);
+                }
+                if workers.is_empty() && feed_open && !pws.scheduler.is_finished() {
+                    starved = true;
+                    feed.wanted.notify_one();
+                }
+            }
+        }
     }
 
-    // Report if any peers failed. In a real scenario, we would introduce retry mechanisms, e.g.
-    // retry with same peer, or just put the job back into the channel so another Peer worker can
-    // grab it. However, as I am developing against a specific bittorrent impl, there are no
-    // error cases.
-    for handle in handles {
-        if let Err(e) = handle.await? {
-            bail!("Task failed: {:?}", e);
-        }
+    if !pws.scheduler.is_finished() {
+        let err = last_err.unwrap_or_else(|| anyhow!("no peers to download from"));
+        bail!("Task failed: {:?}", err);
     }
 
     Ok(())
 }
 
-pub async fn perform_download_piece(
-    client_id: PeerID,
-    peer: &Peer,
-    download_req: DownloadRequest,
-    piece_idx: usize,
-) -> Result<Vec<u8>> {
-    let mut stream = setup_peer(&client_id, peer.to_owned(), &download_req.info_hash).await?;
-    let hash = download_req
-        .pieces
-        .get(piece_idx)
-        .ok_or(anyhow!("no piece at index {}", piece_idx))?
-        .to_owned();
-    let piece = Piece {
-        hash,
-        idx: piece_idx,
-        len: download_req.piece_length,
-    };
+fn spawn_web_seed_worker(pws: &PeerWorkerSetup, workers: &mut PeerWorkers, seed: WebSeed) {
+    let scheduler = Arc::clone(&pws.scheduler);
+    let result_tx = Arc::clone(&pws.result_tx);
+    let scores = Arc::clone(&pws.scores);
 
-    let full_piece = download_piece(piece, &mut stream).await?;
-    Ok(full_piece.data)
+    workers.spawn(async move {
+        let seed_info = seed.url().to_string();
+        let score = scores.connect(&seed_info);
+        let res = seed.run(&scheduler, &result_tx, &score).await;
+        debug!("Web seed {} is done", seed_info);
+
+        (seed_info, res)
+    });
 }
 
-async fn setup_peer(client_id: &PeerID, peer: Peer, info_hash: &Hash) -> Result<TcpStream> {
-    let mut stream = TcpStream::connect(peer.to_string()).await?;
+fn spawn_peer_worker(pws: &PeerWorkerSetup, workers: &mut PeerWorkers, peer: Peer) {
+    let info_hash = Arc::clone(&pws.info_hash);
+    let pieces_cnt = pws.pieces_cnt;
+    let scheduler = Arc::clone(&pws.scheduler);
+    let result_tx = Arc::clone(&pws.result_tx);
+    let client_id = Arc::clone(&pws.client_id);
+    let scores = Arc::clone(&pws.scores);
+
+    let limits = WorkerLimits {
+        pipeline_depth: pws.pipeline_depth,
+        request_timeout: pws.request_timeout,
+        max_request_timeouts: pws.max_request_timeouts,
+    };
+    let reconnect_attempts = pws.reconnect_attempts;
+    let mut backoff = pws.reconnect_backoff;
+    let connection_metrics = pws.connection_metrics;
+
+    workers.spawn(async move {
+        let peer_info = peer.to_string();
+        let mut attempt = 0;
+        loop {
+            let setup = setup_peer(&client_id, peer.clone(), &info_hash, pieces_cnt);
+            let res = match tokio::select! {
+                setup = setup => setup,
+                // A peer still connecting once the others got everything must not hold up the
+                // end of the download.
+                _ = scheduler.finished() => break,
+            } {
+                Ok((mut stream, have)) => {
+                    let score = scores.connect(&peer_info);
+                    let have = Some(&have);
+                    if connection_metrics {
+                        let mut stream = MeteredStream::new(stream);
+                        let res =
+                            run_worker(&scheduler, &mut stream, have, &result_tx, limits, &score)
+                                .await;
+                        score.add_metrics(&stream.metrics());
+                        res
+                    } else {
+                        run_worker(&scheduler, &mut stream, have, &result_tx, limits, &score).await
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let Err(e) = res else { break };
+            if scheduler.is_finished() {
+                // The other workers got everything, no need to come back.
+                break;
+            }
+            if e.is::<RequestTimeout>() {
+                warn!(
+                    "Peer {} timed out {} times, no longer requesting blocks from it",
+                    peer_info, limits.max_request_timeouts
+                );
+                return (peer_info, Err(e));
+            }
+            if attempt >= reconnect_attempts || !is_recoverable(&e) {
+                return (peer_info, Err(e));
+            }
+            attempt += 1;
+            warn!(
+                "Connection to Peer {} failed: {:#}, reconnecting in {:?} ({}/{})",
+                peer_info, e, backoff, attempt, reconnect_attempts
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = scheduler.finished() => break,
+            }
+            backoff *= 2;
+        }
+        debug!("Closing connection to Peer {}", peer_info);
 
-    handshake(client_id, info_hash, &mut stream).await?;
-    debug!("Performed Handshake for {}.", peer);
-    let mut reader = PeerMessageReader::new();
+        (peer_info, Ok(()))
+    });
+}
 
-    // Read Bitfield
-    let mut msg = reader.from_stream(&mut stream).await?;
-    match msg {
-        PeerMessage::Bitfield => {}
-        other => bail!("expected Bitfield PeerMessage, got {:?}", other),
-    }
-    debug!("Received Bitfield from {}.", peer);
+/// A peer didn't answer a block request within `DownloadConfig::request_timeout`.
+#[derive(Debug)]
+struct RequestTimeout(Duration);
 
-    // Send Interested
-    stream
-        .write_all(&PeerMessage::Interested.to_bytes())
+impl fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer did not answer block requests within {:?}", self.0)
+    }
+}
+
+impl std::error::Error for RequestTimeout {}
+
+/// Connection problems a peer may recover from, as opposed to e.g. a peer serving another torrent.
+fn is_recoverable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if cause.is::<tokio::time::error::Elapsed>() || cause.is::<RequestTimeout>() {
+            return true;
+        }
+        cause.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+                    | std::io::ErrorKind::TimedOut
+            )
+        })
+    })
+}
+
+/// Two slots per peer let every worker park a finished piece while it starts on the next one, so
+/// fast peers don't stall on a briefly busy writer. The bounds keep a single peer from being
+/// throttled and huge swarms from buffering lots of pieces.
+fn result_channel_capacity(peer_count: usize) -> usize {
+    (peer_count * 2).clamp(MIN_RESULT_CHANNEL_CAPACITY, MAX_RESULT_CHANNEL_CAPACITY)
+}
+
+/// Every piece but the last has exactly `piece_len` bytes, which is what makes
+/// `idx * piece_len` the piece's offset in the file, whatever the piece length is.
+fn build_pieces(
+    hashes: Vec<Hash>,
+    piece_len: usize,
+    last_piece_len: usize,
+    wanted: Option<&[usize]>,
+) -> Vec<Piece> {
+    let pieces_cnt = hashes.len();
+    hashes
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| wanted.is_none_or(|w| w.binary_search(idx).is_ok()))
+        .map(|(idx, hash)| {
+            let current_piece_len = if idx + 1 == pieces_cnt {
+                last_piece_len
+            } else {
+                piece_len
+            };
+
+            Piece {
+                hash,
+                idx,
+                len: current_piece_len,
+            }
+        })
+        .collect()
+}
+
+/// The order `PieceStream` returns pieces in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PieceOrder {
+    /// By index, for sinks that can only append. Pieces arriving early are held back, see
+    /// `DownloadConfig::max_outstanding_pieces`.
+    #[default]
+    Ascending,
+    /// As soon as they are verified, for sinks that can seek. Nothing is held back.
+    Arrival,
+}
+
+/// Piece indices still to be returned by a `PieceStream`, with the position of each index
+/// precomputed so pieces arriving out of order are taken out without a search.
+struct PieceQueue {
+    // Ascending.
+    order: Vec<usize>,
+    positions: HashMap<usize, usize>,
+    returned: Vec<bool>,
+    // Position of the first piece not returned yet.
+    next: usize,
+    remaining: usize,
+}
+
+impl PieceQueue {
+    fn new(order: Vec<usize>) -> Self {
+        let positions = order
+            .iter()
+            .enumerate()
+            .map(|(pos, &idx)| (idx, pos))
+            .collect();
+        Self {
+            returned: vec![false; order.len()],
+            remaining: order.len(),
+            next: 0,
+            positions,
+            order,
+        }
+    }
+
+    /// The lowest index not returned yet.
+    fn front(&self) -> Option<usize> {
+        self.order.get(self.next).copied()
+    }
+
+    fn remove(&mut self, idx: usize) {
+        let Some(&pos) = self.positions.get(&idx) else {
+            return;
+        };
+        if !std::mem::replace(&mut self.returned[pos], true) {
+            self.remaining -= 1;
+        }
+        while self.returned.get(self.next) == Some(&true) {
+            self.next += 1;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.remaining
+    }
+}
+
+/// Verified pieces as returned by `download_pieces`, in ascending index order unless changed
+/// with `with_order`.
+pub struct PieceStream {
+    result_rx: mpsc::Receiver<FullPiece>,
+    scheduler: Arc<BlockScheduler>,
+    scores: Arc<PeerScores>,
+    pool: Option<JoinHandle<Result<()>>>,
+    piece_order: PieceOrder,
+    // Indices still to be returned.
+    order: PieceQueue,
+    // Pieces that arrived before a piece with a lower index.
+    pending: BTreeMap<usize, FullPiece>,
+    pieces_total: usize,
+    bytes_total: usize,
+    done: bool,
+}
+
+impl Drop for PieceStream {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl PieceStream {
+    pub fn with_order(mut self, piece_order: PieceOrder) -> Self {
+        self.piece_order = piece_order;
+        self
+    }
+
+    /// Returns the next piece, None once all pieces were returned. After an error the stream
+    /// ends.
+    ///
+    /// A piece's slot in `DownloadConfig::max_outstanding_pieces` is freed once it is returned,
+    /// so a slow consumer slows down the download instead of piling up pieces.
+    pub async fn next(&mut self) -> Option<Result<FullPiece>> {
+        loop {
+            if self.done {
+                return None;
+            }
+            let Some(want) = self.order.front() else {
+                self.done = true;
+                return self.join_pool().await.err().map(Err);
+            };
+            if let Some(full_piece) = self.pending.remove(&want) {
+                self.order.remove(want);
+                self.scheduler.piece_written();
+                return Some(Ok(full_piece));
+            }
+
+            match self.result_rx.recv().await {
+                Some(full_piece) => {
+                    debug!(
+                        "Received FullPiece {} at {}",
+                        full_piece.piece,
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .expect("Time went backwards")
+                            .as_micros()
+                    );
+                    if self.piece_order == PieceOrder::Arrival {
+                        self.order.remove(full_piece.index());
+                        self.scheduler.piece_written();
+                        return Some(Ok(full_piece));
+                    }
+                    self.pending.insert(full_piece.piece.idx, full_piece);
+                }
+                None => {
+                    // Every worker is gone, but pieces are missing.
+                    self.done = true;
+                    let err = match self.join_pool().await {
+                        Err(e) => e,
+                        Ok(()) => {
+                            anyhow!("all peers finished before piece {} was downloaded", want)
+                        }
+                    };
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+
+    /// Like `next`, but stops the download with a `Cancelled` error once `cancel` fires.
+    pub async fn next_or_cancelled(
+        &mut self,
+        cancel: Option<&CancelToken>,
+    ) -> Option<Result<FullPiece>> {
+        let Some(cancel) = cancel else {
+            return self.next().await;
+        };
+        if self.done {
+            return None;
+        }
+
+        tokio::select! {
+            piece = self.next() => piece,
+            _ = cancel.cancelled() => {
+                self.cancel();
+                Some(Err(Cancelled {
+                    pieces_done: self.pieces_total - self.order.len(),
+                    pieces_total: self.pieces_total,
+                }
+                .into()))
+            }
+        }
+    }
+
+    /// Stops every worker, pieces not yet returned are dropped.
+    fn cancel(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            // Dropping the pool's worker set aborts the workers.
+            pool.abort();
+        }
+        self.result_rx.close();
+        self.done = true;
+    }
+
+    pub fn pieces_total(&self) -> usize {
+        self.pieces_total
+    }
+
+    pub fn bytes_total(&self) -> usize {
+        self.bytes_total
+    }
+
+    /// Per peer throughput so far.
+    pub fn stats(&self) -> DownloadStats {
+        self.scores.stats()
+    }
+
+    /// Peers that delivered blocks of a verified piece so far.
+    pub fn verified_peers(&self) -> Vec<String> {
+        self.scheduler.verified_peers()
+    }
+
+    /// Reports why the download stopped, if it did not finish.
+    async fn join_pool(&mut self) -> Result<()> {
+        // Awaited in place, so the pool can still be aborted if this is cancelled.
+        let Some(pool) = &mut self.pool else {
+            return Ok(());
+        };
+        let res = pool.await;
+        self.pool = None;
+        res?
+    }
+}
+
+/// Downloads the pieces of `download_req` (only those of `config.selected_files`, if set) from
+/// `peers` without writing them anywhere.
+pub fn download_pieces(
+    client_id: PeerID,
+    peers: impl Into<PeerFeed>,
+    download_req: DownloadRequest,
+    config: &DownloadConfig,
+) -> Result<PieceStream> {
+    debug!("Have {} pieces to download.", download_req.pieces.len());
+    debug!("Piece len is {}.", download_req.piece_length);
+    debug!("Total length is {}.", download_req.length);
+    if config.pipeline_depth == 0 {
+        bail!("pipeline depth must be at least 1");
+    }
+    // No piece could ever be handed out.
+    if config.max_outstanding_pieces == 0 {
+        bail!("max outstanding pieces must be at least 1");
+    }
+
+    let peers = peers.into();
+    // Result channel for tasks to pass pieces to.
+    let result_capacity = match config.result_channel_capacity {
+        Some(0) => bail!("result channel capacity must be at least 1"),
+        Some(capacity) => capacity,
+        None => result_channel_capacity(peers.initial_len),
+    };
+    let (result_tx, result_rx) = mpsc::channel::<FullPiece>(result_capacity);
+
+    let wanted = match &config.selected_files {
+        Some(selected) => Some(download_req.pieces_for_files(selected)?),
+        None => None,
+    };
+    let pieces_cnt = download_req.pieces.len();
+    let last_piece_len = download_req.last_piece_len();
+    let pieces = build_pieces(
+        download_req.pieces,
+        download_req.piece_length,
+        last_piece_len,
+        wanted.as_deref(),
+    );
+    let order = PieceQueue::new(pieces.iter().map(|p| p.idx).collect());
+    let pieces_total = pieces.len();
+    let bytes_total = pieces.iter().map(|p| p.len).sum();
+    // Bounds the downloaded-but-unwritten pieces, see DownloadConfig.
+    let mut scheduler = BlockScheduler::new(pieces, config.max_outstanding_pieces);
+    if !config.verify {
+        warn!("Piece hashes are NOT verified, downloaded data may be corrupt");
+        scheduler = scheduler.without_verification();
+    }
+    if let Some(max_retries) = config.max_retries {
+        scheduler = scheduler.with_max_retries(max_retries);
+    }
+    if let Some(max_active) = config.max_active_pieces {
+        scheduler = scheduler.with_max_active(max_active);
+    }
+    let scheduler = Arc::new(scheduler);
+    let scores = Arc::new(PeerScores::default());
+
+    // Runs one job executor for each available Peer.
+    let pws = PeerWorkerSetup {
+        info_hash: Arc::new(download_req.info_hash),
+        pieces_cnt,
+        client_id: Arc::new(client_id),
+        result_tx: Arc::new(result_tx),
+        scheduler: Arc::clone(&scheduler),
+        scores: Arc::clone(&scores),
+        reconnect_attempts: config.reconnect_attempts,
+        reconnect_backoff: config.reconnect_backoff,
+        pipeline_depth: config.pipeline_depth,
+        request_timeout: config.request_timeout,
+        max_request_timeouts: config.max_request_timeouts,
+        connection_metrics: config.connection_metrics,
+    };
+    let web_seeds = download_req
+        .web_seeds
+        .into_iter()
+        .map(|url| WebSeed::new(url, download_req.piece_length, download_req.files.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    let pool = tokio::spawn(run_peer_pool(pws, peers, web_seeds));
+
+    Ok(PieceStream {
+        result_rx,
+        scheduler,
+        scores,
+        pool: Some(pool),
+        piece_order: PieceOrder::Ascending,
+        order,
+        pending: BTreeMap::new(),
+        pieces_total,
+        bytes_total,
+        done: false,
+    })
+}
+
+pub async fn download_file(
+    client_id: PeerID,
+    peers: impl Into<PeerFeed>,
+    download_req: DownloadRequest,
+    output_path: PathBuf,
+    config: DownloadConfig,
+) -> Result<DownloadStats> {
+    let piece_len = download_req.piece_length;
+    let mut df = match &download_req.files {
+        Some(files) => {
+            DownloadingFile::new_multi(
+                piece_len,
+                output_path,
+                files,
+                config.selected_files.as_deref(),
+            )
+            .await?
+        }
+        None => DownloadingFile::new(piece_len, output_path).await?,
+    }
+    .with_write_buffer(config.write_buffer_size);
+
+    let info_hash = download_req.info_hash.clone();
+    let pieces_cnt = download_req.pieces.len();
+    // The file is written at each piece's offset, so pieces need not wait for their
+    // predecessors.
+    let mut stream =
+        download_pieces(client_id, peers, download_req, &config)?.with_order(PieceOrder::Arrival);
+    let mut tracker = ProgressTracker::new(&stream, pieces_cnt);
+
+    let res: Result<()> = async {
+        while let Some(full_piece) = stream.next_or_cancelled(config.cancel.as_ref()).await {
+            let full_piece = full_piece?;
+            debug!("Writing piece {}", full_piece.index());
+            for (piece, written) in df.write_full_piece(full_piece).await? {
+                tracker.piece_written(piece, written, &config);
+            }
+        }
+        Ok(())
+    }
+    .await;
+    // Also after a failure, so the pieces written so far are kept.
+    let flushed = df.flush().await.map(|pieces| {
+        for (piece, written) in pieces {
+            tracker.piece_written(piece, written, &config);
+        }
+    });
+    if res.is_err() {
+        info!(
+            "Kept {} of {} pieces written before the download stopped",
+            tracker.written.count(),
+            tracker.written.len()
+        );
+    }
+
+    tracker.save_totals(&info_hash, &config);
+    save_peer_cache(&stream, &info_hash, &config);
+    res?;
+    flushed?;
+
+    Ok(stream.stats())
+}
+
+/// Like `download_file`, but streams the content of a single-file torrent to `writer`, e.g.
+/// stdout. Pieces are written in order as soon as they are complete, so nothing is read back
+/// and a partial download can't be resumed.
+pub async fn download_to_writer<W: AsyncWrite + Unpin>(
+    client_id: PeerID,
+    peers: impl Into<PeerFeed>,
+    download_req: DownloadRequest,
+    mut writer: W,
+    config: DownloadConfig,
+) -> Result<DownloadStats> {
+    if download_req.files.is_some() {
+        bail!("only single-file torrents can be written to a stream");
+    }
+
+    let info_hash = download_req.info_hash.clone();
+    let pieces_cnt = download_req.pieces.len();
+    let mut stream = download_pieces(client_id, peers, download_req, &config)?;
+    let mut tracker = ProgressTracker::new(&stream, pieces_cnt);
+
+    let res: Result<()> = async {
+        while let Some(full_piece) = stream.next_or_cancelled(config.cancel.as_ref()).await {
+            let full_piece = full_piece?;
+            debug!("Writing piece {}", full_piece.index());
+            writer.write_all(full_piece.data()).await?;
+            tracker.piece_written(full_piece.index(), full_piece.data().len(), &config);
+        }
+        Ok(())
+    }
+    .await;
+    let flushed = writer.flush().await;
+
+    tracker.save_totals(&info_hash, &config);
+    save_peer_cache(&stream, &info_hash, &config);
+    res?;
+    flushed?;
+
+    Ok(stream.stats())
+}
+
+/// Stores the peers that delivered a verified piece in `config.peer_cache`.
+fn save_peer_cache(stream: &PieceStream, info_hash: &Hash, config: &DownloadConfig) {
+    let Some(path) = &config.peer_cache else {
+        return;
+    };
+    // Web seeds deliver pieces as well, only peer addresses parse.
+    let peers: Vec<Peer> = stream
+        .verified_peers()
+        .iter()
+        .filter_map(|p| p.parse().ok())
+        .collect();
+    if let Err(e) = Peers::from(peers).save_cached(path, info_hash) {
+        warn!("Could not save the peer cache: {:#}", e);
+    }
+}
+
+/// Reports `Progress` after every written piece.
+struct ProgressTracker {
+    progress: Progress,
+    throughput: Throughput,
+    // Over all pieces of the torrent, not only the selected ones.
+    written: Bitfield,
+}
+
+impl ProgressTracker {
+    fn new(stream: &PieceStream, pieces_cnt: usize) -> Self {
+        Self {
+            progress: Progress {
+                piece: 0,
+                pieces_done: 0,
+                pieces_total: stream.pieces_total(),
+                bytes_done: 0,
+                bytes_total: stream.bytes_total(),
+                eta: Eta::Calculating,
+            },
+            throughput: Throughput::new(THROUGHPUT_WINDOW, Instant::now()),
+            written: Bitfield::new(pieces_cnt),
+        }
+    }
+
+    fn piece_written(&mut self, piece: usize, written: usize, config: &DownloadConfig) {
+        let now = Instant::now();
+        let progress = &mut self.progress;
+        self.throughput.record(now, written);
+        self.written.set(piece);
+        progress.piece = piece;
+        progress.pieces_done += 1;
+        progress.bytes_done += written;
+        progress.eta = self
+            .throughput
+            .eta(now, progress.bytes_total - progress.bytes_done);
+        info!("Progress: {}", progress);
+        if let Some(tx) = &config.progress_tx {
+            // The consumer going away must not abort the download.
+            let _ = tx.send(progress.clone());
+        }
+    }
+
+    /// Adds the written bytes to `config.stats_file`. Pieces written before a failure count as
+    /// well.
+    fn save_totals(&self, info_hash: &Hash, config: &DownloadConfig) {
+        let Some(path) = &config.stats_file else {
+            return;
+        };
+        let mut totals = TransferTotals::load(path, info_hash);
+        totals.downloaded += self.progress.bytes_done as u64;
+        if let Err(e) = totals.save(path, info_hash) {
+            warn!("Could not update transfer totals: {:#}", e);
+        }
+    }
+}
+
+/// Downloads a single piece from the first of `peers` that delivers it. Peers that can't be
+/// reached or fail during the download are skipped.
+pub async fn perform_download_piece(
+    client_id: PeerID,
+    peers: &Peers,
+    download_req: DownloadRequest,
+    piece_idx: usize,
+    verify: bool,
+) -> Result<Vec<u8>> {
+    let hash = download_req
+        .pieces
+        .get(piece_idx)
+        .ok_or(anyhow!("no piece at index {}", piece_idx))?
+        .to_owned();
+    let piece = Piece {
+        hash,
+        idx: piece_idx,
+        len: download_req.piece_len(piece_idx),
+    };
+
+    let mut last_err = None;
+    for peer in peers.iter() {
+        let res = async {
+            let (mut stream, have) = setup_peer(
+                &client_id,
+                peer.to_owned(),
+                &download_req.info_hash,
+                download_req.pieces.len(),
+            )
+            .await?;
+            download_piece(piece.clone(), &mut stream, &have, verify).await
+        }
+        .await;
+        match res {
+            Ok(full_piece) => return Ok(full_piece.data),
+            Err(e) => {
+                warn!(
+                    "Downloading piece {} from {} failed: {:#}",
+                    piece_idx, peer, e
+                );
+                last_err = Some(e);
+            }
+        }
+    }
+
+    match last_err {
+        Some(e) => Err(e.context(format!(
+            "all {} peers failed to deliver piece {}",
+            peers.len(),
+            piece_idx
+        ))),
+        None => bail!("no peers to download piece {} from", piece_idx),
+    }
+}
+
+/// Connects to `peer` for a torrent of `pieces_cnt` pieces and waits until it unchokes us.
+/// Returns the connection along with the pieces the peer has.
+async fn setup_peer(
+    client_id: &PeerID,
+    peer: Peer,
+    info_hash: &Hash,
+    pieces_cnt: usize,
+) -> Result<(TcpStream, Bitfield)> {
+    let mut stream = peer.connect().await?;
+
+    let hs = handshake(client_id, info_hash, &mut stream).await?;
+    if hs.info_hash != *info_hash {
+        bail!(
+            "peer {} answered with info hash {}, want {}",
+            peer,
+            hs.info_hash.to_hex(),
+            info_hash.to_hex()
+        );
+    }
+    debug!(
+        "Performed Handshake for {}, running {} with reserved bytes {:02x?}.",
+        peer,
+        hs.client_name(),
+        hs.reserved_bytes()
+    );
+
+    // Read Bitfield
+    let mut msg = PeerMessage::read_relevant(&mut stream).await?;
+    let have = match msg {
+        PeerMessage::Bitfield(bits) => Bitfield::from_bytes(&bits, pieces_cnt)
+            .with_context(|| format!("invalid bitfield from {}", peer))?,
+        other => bail!("expected Bitfield PeerMessage, got {:?}", other),
+    };
+    debug!(
+        "Received Bitfield from {}, it has {} of {} pieces.",
+        peer,
+        have.count(),
+        have.len()
+    );
+
+    // Send Interested
+    PeerMessage::Interested.write(&mut stream).await?;
+    debug!("Sent Interested to {}.", peer);
+
+    // Read Unchoke
+    msg = PeerMessage::read_relevant(&mut stream).await?;
+    match msg {
+        PeerMessage::Unchoke => {}
+        other => bail!("expected Unchoke PeerMessage, got {:?}", other),
+    }
+    debug!("Read Unchoke from {}", peer);
+
+    Ok((stream, have))
+}
+
+async fn download_piece(
+    piece: Piece,
+    stream: &mut TcpStream,
+    have: &Bitfield,
+    verify: bool,
+) -> Result<FullPiece> {
+    if !have.has(piece.idx) {
+        bail!("peer does not have piece {}", piece.idx);
+    }
+    let mut scheduler = BlockScheduler::new(vec![piece], 1);
+    if !verify {
+        warn!("Piece hash is NOT verified, downloaded data may be corrupt");
+        scheduler = scheduler.without_verification();
+    }
+    let (result_tx, mut result_rx) = mpsc::channel(1);
+    let score = Arc::new(PeerScores::default()).connect("peer");
+    let limits = WorkerLimits {
+        pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+        request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        max_request_timeouts: DEFAULT_MAX_REQUEST_TIMEOUTS,
+    };
+    run_worker(&scheduler, stream, Some(have), &result_tx, limits, &score).await?;
+
+    result_rx
+        .recv()
+        .await
+        .context("peer finished without downloading the piece")
+}
+
+/// Per connection limits of a peer worker, see `DownloadConfig`.
+#[derive(Debug, Clone, Copy)]
+struct WorkerLimits {
+    pipeline_depth: usize,
+    request_timeout: Duration,
+    max_request_timeouts: usize,
+}
+
+/// Downloads blocks handed out by the scheduler over a set up connection until every piece is
+/// done, of the pieces the peer `have`s only. Up to `pipeline_depth` requests are in flight at
+/// once, which may belong to different pieces, fewer if the peer is slow compared to others.
+/// Blocks still in flight when the connection fails or the peer doesn't answer within
+/// `request_timeout` are handed back to the scheduler.
+async fn run_worker<S: AsyncRead + AsyncWrite + MessageCounter + Unpin>(
+    scheduler: &BlockScheduler,
+    stream: &mut S,
+    have: Option<&Bitfield>,
+    result_tx: &Sender<FullPiece>,
+    limits: WorkerLimits,
+    score: &PeerScore,
+) -> Result<()> {
+    let mut in_flight: Vec<BlockRequest> = Vec::with_capacity(limits.pipeline_depth);
+    let res = worker_loop(
+        scheduler,
+        stream,
+        have,
+        result_tx,
+        limits,
+        score,
+        &mut in_flight,
+    )
+    .await;
+    if res.is_err() {
+        scheduler.release(&in_flight, score.peer());
+    }
+
+    res
+}
+
+/// Requests blocks from the peer until every piece is downloaded. After a request timed out the
+/// outstanding requests are cancelled and the peer is asked for other pieces, as long as there
+/// are any. It is given up after `max_request_timeouts` timeouts.
+async fn worker_loop<S: AsyncRead + AsyncWrite + MessageCounter + Unpin>(
+    scheduler: &BlockScheduler,
+    stream: &mut S,
+    have: Option<&Bitfield>,
+    result_tx: &Sender<FullPiece>,
+    limits: WorkerLimits,
+    score: &PeerScore,
+    in_flight: &mut Vec<BlockRequest>,
+) -> Result<()> {
+    let mut timeouts = 0;
+    // Pieces the peer timed out on.
+    let mut slow: Vec<usize> = Vec::new();
+    // Requests withdrawn with a Cancel, the peer may have sent the blocks already.
+    let mut cancelled: Vec<BlockRequest> = Vec::new();
+    let mut reader = MessageReader::default();
+    loop {
+        while in_flight.len() < score.pipeline_depth(limits.pipeline_depth) {
+            match scheduler.next_block(&slow, have, score.speed()) {
+                NextBlock::Block(req) => {
+                    // Track the block first, so it is released if sending fails.
+                    in_flight.push(req);
+                    send_request(stream, &req).await?;
+                }
+                NextBlock::Wait | NextBlock::Finished => break,
+            }
+        }
+
+        if in_flight.is_empty() {
+            // Nothing to read, wait until another worker releases blocks or all are done. The
+            // pieces the peer was slow for are left to other peers for a while first.
+            let avoiding = scheduler.wait_for_block_avoiding(&slow, have, score.speed());
+            let next = match tokio::time::timeout(limits.request_timeout, avoiding).await {
+                Ok(next) => next,
+                Err(_) => {
+                    slow.clear();
+                    scheduler.wait_for_block(have, score.speed()).await
+                }
+            };
+            match next {
+                Some(req) => {
+                    in_flight.push(req);
+                    send_request(stream, &req).await?;
+                }
+                None => return Ok(()),
+            }
+        }
+
+        let read = reader.read_relevant(stream);
+        let msg = match tokio::time::timeout(limits.request_timeout, read).await {
+            Ok(msg) => {
+                let msg = msg?;
+                stream.message_read();
+                msg
+            }
+            Err(_) => {
+                timeouts += 1;
+                if timeouts >= limits.max_request_timeouts {
+                    return Err(RequestTimeout(limits.request_timeout).into());
+                }
+                debug!(
+                    "Peer timed out, cancelling {} requests ({}/{})",
+                    in_flight.len(),
+                    timeouts,
+                    limits.max_request_timeouts
+                );
+                for req in in_flight.iter() {
+                    PeerMessage::Cancel(req.into()).write(stream).await?;
+                    stream.message_written();
+                    if !slow.contains(&req.piece_idx) {
+                        slow.push(req.piece_idx);
+                    }
+                }
+                scheduler.release(in_flight, score.peer());
+                cancelled.append(in_flight);
+                continue;
+            }
+        };
+        let piece_msg = match msg {
+            PeerMessage::Piece(piece) => piece,
+            other => bail!("expected Piece PeerMessage, got {:?}", other),
+        };
+        let is_block = |r: &BlockRequest| {
+            r.piece_idx == piece_msg.index as usize && r.begin == piece_msg.begin as usize
+        };
+        let req = if let Some(pos) = in_flight.iter().position(is_block) {
+            in_flight.swap_remove(pos)
+        } else if let Some(pos) = cancelled.iter().position(is_block) {
+            // Still useful, unless another peer delivered the block in the meantime.
+            cancelled.swap_remove(pos)
+        } else {
+            bail!(
+                "peer sent more data than requested for piece {}: unrequested block at offset {}",
+                piece_msg.index,
+                piece_msg.begin
+            );
+        };
+        debug!(
+            "Received block at offset {} of piece {}.",
+            req.begin, req.piece_idx
+        );
+        score.record(piece_msg.block.len());
+
+        if let Some(full_piece) = scheduler.complete_block(&req, &piece_msg.block, score.peer())? {
+            result_tx.send(full_piece).await?;
+        }
+    }
+}
+
+async fn send_request<S: AsyncWrite + MessageCounter + Unpin>(
+    stream: &mut S,
+    req: &BlockRequest,
+) -> Result<()> {
+    debug!(
+        "Writing request for offset {} of piece {}.",
+        req.begin, req.piece_idx
+    );
+    PeerMessage::Request(req.into()).write(stream).await?;
+    stream.message_written();
+
+    Ok(())
+}
+
+pub async fn perform_handshake(
+    client_id: PeerID,
+    peer: &Peer,
+    info_hash: &Hash,
+) -> Result<Handshake> {
+    let mut stream = peer.connect().await?;
+    handshake(&client_id, info_hash, &mut stream).await
+}
+
+/// Result of test-connecting to a peer, see `check_peers`.
+pub enum PeerCheck {
+    Reachable(Duration),
+    Failed(anyhow::Error),
+    TimedOut,
+}
+
+impl fmt::Display for PeerCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerCheck::Reachable(latency) => write!(f, "ok ({} ms)", latency.as_millis()),
+            PeerCheck::Failed(e) => write!(f, "failed: {:#}", e),
+            PeerCheck::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+/// Performs a handshake with every peer, at most `concurrency` at once, and reports per peer
+/// whether it answered for our torrent within `timeout`. Results are sent as the checks finish,
+/// the channel closes after the last one.
+pub fn check_peers(
+    client_id: PeerID,
+    peers: &Peers,
+    info_hash: &Hash,
+    timeout: Duration,
+    concurrency: usize,
+) -> mpsc::UnboundedReceiver<(Peer, PeerCheck)> {
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(concurrency.max(1)));
+    let mut checks = JoinSet::new();
+    for peer in peers.iter() {
+        let semaphore = Arc::clone(&semaphore);
+        let client_id = client_id.clone();
+        let info_hash = info_hash.clone();
+        let peer = peer.clone();
+        checks.spawn(async move {
+            let _permit = semaphore
+                .acquire()
+                .await
+                .expect("semaphore is never closed");
+            let started = Instant::now();
+            let res =
+                tokio::time::timeout(timeout, perform_handshake(client_id, &peer, &info_hash))
+                    .await;
+            let check = match res {
+                Err(_) => PeerCheck::TimedOut,
+                Ok(Err(e)) => PeerCheck::Failed(e),
+                Ok(Ok(hs)) if hs.info_hash != info_hash => {
+                    PeerCheck::Failed(anyhow!("peer answered for another torrent"))
+                }
+                Ok(Ok(_)) => PeerCheck::Reachable(started.elapsed()),
+            };
+            (peer, check)
+        });
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    // Once the receiver is dropped, dropping the JoinSet aborts the checks still running.
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                joined = checks.join_next() => match joined {
+                    Some(Ok(result)) => {
+                        if tx.send(result).is_err() {
+                            return;
+                        }
+                    }
+                    Some(Err(e)) => warn!("Peer check panicked: {}", e),
+                    None => return,
+                },
+                _ = tx.closed() => return,
+            }
+        }
+    });
+
+    rx
+}
+
+/// Like `check_peers`, but only reports the connect and handshake time per peer, fastest first.
+/// Peers that failed or timed out have none and come last.
+pub async fn probe_peers(
+    client_id: PeerID,
+    peers: &Peers,
+    info_hash: &Hash,
+    timeout: Duration,
+    concurrency: usize,
+) -> Vec<(Peer, Option<Duration>)> {
+    let mut checks = check_peers(client_id, peers, info_hash, timeout, concurrency);
+    let mut probes = Vec::with_capacity(peers.len());
+    while let Some((peer, check)) = checks.recv().await {
+        match check {
+            PeerCheck::Reachable(latency) => probes.push((peer, Some(latency))),
+            other => {
+                debug!("Probing {} {}", peer, other);
+                probes.push((peer, None));
+            }
+        }
+    }
+    probes.sort_by_key(|(_, latency)| latency.unwrap_or(Duration::MAX));
+
+    probes
+}
+
+async fn handshake(
+    client_id: &PeerID,
+    info_hash: &Hash,
+    stream: &mut TcpStream,
+) -> Result<Handshake> {
+    let handshake = Handshake::new(info_hash, client_id);
+    let bytes = handshake.to_bytes();
+
+    stream.write_all(&bytes).await?;
+
+    let mut buf = [0; HANDSHAKE_BYTE_SIZE];
+    let mut total_read = 0;
+    while total_read < HANDSHAKE_BYTE_SIZE {
+        let bytes_read = stream.read(&mut buf[total_read..]).await?;
+        if bytes_read == 0 {
+            bail!("Connection closed before handshake was fully read")
+        }
+        total_read += bytes_read;
+    }
+
+    let hs = Handshake::from_bytes(buf)?;
+    // Trackers may hand out our own address. Peer ids are random per session, so an equal one
+    // means we are talking to ourselves.
+    if hs.peer_id_bytes() == client_id.as_bytes() {
+        bail!("peer answered with our own peer id, refusing to connect to ourselves");
+    }
+
+    Ok(hs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::RetriesExhausted;
+    use crate::test_util::{MockPeer, MockWebSeed};
+
+    #[test]
+    fn test_request_payload_gen_next() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 32768;
+        let mut gen = RequestPayloadGen::new(piece_len, 0);
+        let counts = 2; // BLOCK_SIZE * 2 == piece_len
+        let mut cnt = 0;
+        for _n in 0..counts {
+            let is_some = gen.next().is_some();
+            cnt += 1;
+            assert_eq!(is_some, true)
+        }
+
+        assert_eq!(gen.next().is_some(), false);
+        assert_eq!(cnt, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_payload_gen_next_smaller_piece_len() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 6241;
+        let mut gen = RequestPayloadGen::new(piece_len, 0);
+        let req = gen.next();
+        assert_eq!(req.is_some(), true);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_peer_messages_over_pipe() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut client, mut peer) = tokio::io::duplex(64 * 1024);
+
+        PeerMessage::Interested.write(&mut client).await?;
+        PeerMessage::Request(RequestPayload {
+            index: 3,
+            begin: 16384,
+            length: 100,
+        })
+        .write(&mut client)
+        .await?;
+        // Keep-alive, then a piece.
+        client.write_all(&[0, 0, 0, 0]).await?;
+        PeerMessage::Piece(PiecePayload {
+            index: 3,
+            begin: 16384,
+            block: vec![9; 100],
+        })
+        .write(&mut client)
+        .await?;
+
+        assert!(matches!(
+            PeerMessage::read(&mut peer).await?,
+            PeerMessage::Interested
+        ));
+        match PeerMessage::read(&mut peer).await? {
+            PeerMessage::Request(req) => {
+                assert_eq!((req.index, req.begin, req.length), (3, 16384, 100))
+            }
+            other => panic!("expected Request, got {:?}", other),
+        }
+        match PeerMessage::read(&mut peer).await? {
+            PeerMessage::Piece(piece) => {
+                assert_eq!((piece.index, piece.begin), (3, 16384));
+                assert_eq!(piece.block, vec![9; 100]);
+            }
+            other => panic!("expected Piece, got {:?}", other),
+        }
+
+        // Announcing a payload above the limit fails before reading it.
+        client
+            .write_all(&(MAX_PAYLOAD_LEN as u32 + 2).to_be_bytes())
+            .await?;
+        assert!(PeerMessage::read(&mut peer).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_reader_survives_timeouts() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut client, mut peer) = tokio::io::duplex(64 * 1024);
+        let mut reader = MessageReader::default();
+        let piece = PeerMessage::Piece(PiecePayload {
+            index: 3,
+            begin: 16384,
+            block: vec![9; 100],
+        })
+        .to_bytes();
+
+        // The read times out in the middle of the message, then picks up where it left off.
+        client.write_all(&[0, 0, 0, 0]).await?;
+        client.write_all(&piece[..7]).await?;
+        let read = reader.read_relevant(&mut peer);
+        assert!(tokio::time::timeout(Duration::from_millis(20), read)
+            .await
+            .is_err());
+        client.write_all(&piece[7..]).await?;
+        PeerMessage::Port(6881).write(&mut client).await?;
+        PeerMessage::Unchoke.write(&mut client).await?;
+        match reader.read_relevant(&mut peer).await? {
+            PeerMessage::Piece(piece) => {
+                assert_eq!((piece.index, piece.begin), (3, 16384));
+                assert_eq!(piece.block, vec![9; 100]);
+            }
+            other => panic!("expected Piece, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.read_relevant(&mut peer).await?,
+            PeerMessage::Unchoke
+        ));
+
+        drop(client);
+        assert!(reader.read_relevant(&mut peer).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_port_messages() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut client, mut peer) = tokio::io::duplex(64 * 1024);
+
+        // DHT ports are parsed, but skipped while waiting for other messages.
+        PeerMessage::Port(6881).write(&mut client).await?;
+        assert!(matches!(
+            PeerMessage::read(&mut peer).await?,
+            PeerMessage::Port(6881)
+        ));
+        PeerMessage::Port(6881).write(&mut client).await?;
+        PeerMessage::Unchoke.write(&mut client).await?;
+        assert!(matches!(
+            PeerMessage::read_relevant(&mut peer).await?,
+            PeerMessage::Unchoke
+        ));
+        assert!(PeerMessage::from_bytes(9, &[0x1a]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_payload_gen_last_piece() {
+        let piece_len = 2 * BLOCK_SIZE;
+        let data = vec![0; 2 * piece_len + BLOCK_SIZE + 100];
+        let req = download_request(&data, piece_len);
+        assert_eq!(req.piece_len(0), piece_len);
+        assert_eq!(req.piece_len(2), BLOCK_SIZE + 100);
+
+        let mut gen = RequestPayloadGen::new(req.piece_len(2), 2);
+        let lengths: Vec<_> = std::iter::from_fn(|| gen.next())
+            .map(|r| r.length as usize)
+            .collect();
+        assert_eq!(lengths, vec![BLOCK_SIZE, 100]);
+    }
+
+    #[test]
+    fn test_handshake_client_name() -> Result<(), Box<dyn std::error::Error>> {
+        let handshake = |reserved: [u8; 8], peer_id: &[u8; 20]| {
+            let mut data = Handshake::new(&Hash::new([1; 20]), &PeerID::new()).to_bytes();
+            data[20..28].copy_from_slice(&reserved);
+            data[48..68].copy_from_slice(peer_id);
+            Handshake::from_bytes(data)
+        };
+
+        let hs = handshake([0, 0, 0, 0, 0, 0x10, 0, 0x05], b"-qB4630-k8hj0wgej6ch")?;
+        assert_eq!(hs.client_name(), "qBittorrent");
+        assert_eq!(hs.peer_id_bytes(), b"-qB4630-k8hj0wgej6ch");
+        assert_eq!(hs.peer_id_hex(), "2d7142343633302d6b38686a307767656a366368");
+        assert_eq!(hs.reserved_bytes(), [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+
+        assert_eq!(
+            handshake([0; 8], b"-TR4040-abcdefghijkl")?.client_name(),
+            "Transmission"
+        );
+        assert_eq!(
+            handshake([0; 8], b"S58B-----abcdefghijk")?.client_name(),
+            "Shadow"
+        );
+        assert_eq!(
+            handshake([0; 8], b"-ZZ0001-abcdefghijkl")?.client_name(),
+            "unknown"
+        );
+        assert_eq!(handshake([0; 8], &[0xff; 20])?.client_name(), "unknown");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_piece_payload_from_bytes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut piece_bytes: Vec<u8> = Vec::new();
+        piece_bytes.extend_from_slice(&0u32.to_be_bytes());
+        piece_bytes.extend_from_slice(&0u32.to_be_bytes());
+        let random_data: Vec<u8> = (0..16384).map(|_| rand::random::<u8>()).collect();
+        piece_bytes.extend_from_slice(&random_data);
+        PiecePayload::from_bytes(&piece_bytes)?;
+
+        let mut smaller_piece_bytes: Vec<u8> = Vec::new();
+        smaller_piece_bytes.extend_from_slice(&0u32.to_be_bytes());
+        smaller_piece_bytes.extend_from_slice(&0u32.to_be_bytes());
+        let random_data: Vec<u8> = (0..1337).map(|_| rand::random::<u8>()).collect();
+        smaller_piece_bytes.extend_from_slice(&random_data);
+        PiecePayload::from_bytes(&smaller_piece_bytes)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_downloading_file_writes_selected_file_only(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let files = vec![
+            DownloadFile {
+                path: PathBuf::from("a.txt"),
+                length: 6,
+            },
+            DownloadFile {
+                path: PathBuf::from("b.txt"),
+                length: 5,
+            },
+        ];
+        let data = b"aaaaaabbbbb";
+        let piece_len = 4;
+        let mut df =
+            DownloadingFile::new_multi(piece_len, dir.path().to_path_buf(), &files, Some(&[1]))
+                .await?;
+
+        // Piece 1 straddles both files, only its b.txt part must be written.
+        for idx in [2, 1] {
+            let end = (idx * piece_len + piece_len).min(data.len());
+            df.write_full_piece(FullPiece {
+                data: data[idx * piece_len..end].to_vec(),
+                piece: Piece {
+                    hash: Hash::new([0; 20]),
+                    idx,
+                    len: end - idx * piece_len,
+                },
+            })
+            .await?;
+        }
+        df.flush().await?;
+
+        assert!(!dir.path().join("a.txt").exists());
+        assert_eq!(std::fs::read(dir.path().join("b.txt"))?, b"bbbbb");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_buffer_collects_pieces() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let data: Vec<u8> = (0..50).collect();
+        let piece_len = 4;
+        let mut df = DownloadingFile::new(piece_len, path.clone())
+            .await?
+            .with_write_buffer(20);
+        let full_piece = |idx: usize| {
+            let end = (idx * piece_len + piece_len).min(data.len());
+            FullPiece {
+                data: data[idx * piece_len..end].to_vec(),
+                piece: Piece {
+                    hash: Hash::new([0; 20]),
+                    idx,
+                    len: end - idx * piece_len,
+                },
+            }
+        };
+
+        // Nothing is written until 20 bytes are buffered.
+        for idx in [3, 0, 1, 7] {
+            assert!(df.write_full_piece(full_piece(idx)).await?.is_empty());
+        }
+        assert_eq!(std::fs::metadata(&path)?.len(), 0);
+        // Written as the runs 0-1, 3 and 6-7.
+        let written = df.write_full_piece(full_piece(6)).await?;
+        assert_eq!(written, vec![(0, 4), (1, 4), (3, 4), (6, 4), (7, 4)]);
+        assert_eq!(df.buffered, 0);
+
+        let mut written = Vec::new();
+        for idx in [12, 2, 5, 4, 11, 8, 10, 9] {
+            written.extend(df.write_full_piece(full_piece(idx)).await?);
+        }
+        written.extend(df.flush().await?);
+        written.sort();
+        // 12 is the short last piece.
+        let rest: Vec<_> = [2, 4, 5, 8, 9, 10, 11].map(|idx| (idx, 4)).into();
+        assert_eq!(written, [rest, vec![(12, 2)]].concat());
+        assert_eq!(std::fs::read(&path)?, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_keeps_the_buffer() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        std::fs::write(&path, b"")?;
+        // Opened read-only, so writing to it fails.
+        let read_only = File::from_std(std::fs::File::open(&path)?);
+        let mut df = DownloadingFile::with_targets(
+            4,
+            vec![FileTarget {
+                offset: 0,
+                length: u64::MAX,
+                file: Some(read_only),
+                position: 0,
+            }],
+        )
+        .with_write_buffer(8);
+        let full_piece = |idx: usize| FullPiece {
+            data: vec![idx as u8; 4],
+            piece: Piece {
+                hash: Hash::new([0; 20]),
+                idx,
+                len: 4,
+            },
+        };
+
+        df.write_full_piece(full_piece(1)).await?;
+        assert!(df.write_full_piece(full_piece(0)).await.is_err());
+        assert_eq!(df.buffered, 8);
+
+        df.targets[0].file = Some(DownloadingFile::open(path.clone()).await?);
+        assert_eq!(df.flush().await?, vec![(0, 4), (1, 4)]);
+        assert_eq!(std::fs::read(&path)?, [0, 0, 0, 0, 1, 1, 1, 1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unbuffered_writes_land_at_their_offsets() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let data: Vec<u8> = (0..50).collect();
+        let piece_len = 4;
+        let mut df = DownloadingFile::new(piece_len, path.clone()).await?;
+
+        // Runs of neighbouring pieces continue at the cursor, the others seek.
+        for idx in [0, 1, 2, 9, 10, 3, 12, 4, 5, 11, 6, 7, 8] {
+            let end = (idx * piece_len + piece_len).min(data.len());
+            df.write_full_piece(FullPiece {
+                data: data[idx * piece_len..end].to_vec(),
+                piece: Piece {
+                    hash: Hash::new([0; 20]),
+                    idx,
+                    len: end - idx * piece_len,
+                },
+            })
+            .await?;
+            assert_eq!(df.targets[0].position, end as u64);
+        }
+        df.flush().await?;
+        assert_eq!(std::fs::read(&path)?, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_beyond_4gib() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Seek};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let piece_len = 1 << 20;
+        let idx = 5000;
+        let mut df = DownloadingFile::new(piece_len, path.clone()).await?;
+        let offset = df.piece_offset(idx);
+        assert_eq!(offset, 5000 << 20);
+        assert!(offset > u32::MAX as u64);
+
+        // The file stays sparse, only the short last piece is written.
+        df.write_full_piece(FullPiece {
+            data: b"tail".to_vec(),
+            piece: Piece {
+                hash: Hash::new([0; 20]),
+                idx,
+                len: 4,
+            },
+        })
+        .await?;
+        df.flush().await?;
+
+        let mut file = std::fs::File::open(&path)?;
+        assert_eq!(file.metadata()?.len(), offset + 4);
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        assert_eq!(tail, b"tail");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_odd_piece_length_reassembles_file() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 6241;
+        let data: Vec<u8> = (0..3 * piece_len + 1000)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let download_req = DownloadRequest {
+            length: data.len(),
+            piece_length: piece_len,
+            pieces: data.chunks(piece_len).map(Hash::hash).collect(),
+            info_hash: Hash::new([0; 20]),
+            files: None,
+            web_seeds: Vec::new(),
+        };
+        let last_piece_len = download_req.last_piece_len();
+        assert_eq!(last_piece_len, 1000);
+
+        let pieces = build_pieces(download_req.pieces, piece_len, last_piece_len, None);
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let mut df = DownloadingFile::new(piece_len, path.clone()).await?;
+        // Write out of order, with the short last piece first.
+        for piece in pieces.into_iter().rev() {
+            let start = piece.idx * piece_len;
+            let mut gen = RequestPayloadGen::new(piece.len, piece.idx);
+            let mut piece_data = Vec::new();
+            while let Some(req) = gen.next() {
+                assert_eq!(req.begin as usize, piece_data.len());
+                let begin = start + req.begin as usize;
+                piece_data.extend_from_slice(&data[begin..begin + req.length as usize]);
+            }
+            assert!(Hash::hash(&piece_data) == piece.hash);
+            df.write_full_piece(FullPiece {
+                data: piece_data,
+                piece,
+            })
+            .await?;
+        }
+        df.flush().await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+
+        Ok(())
+    }
+
+    fn download_request(data: &[u8], piece_len: usize) -> DownloadRequest {
+        DownloadRequest {
+            length: data.len(),
+            piece_length: piece_len,
+            pieces: data.chunks(piece_len).map(Hash::hash).collect(),
+            info_hash: Hash::new([7; 20]),
+            files: None,
+            web_seeds: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_file_shorter_than_a_piece() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 4 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let download_req = download_request(&data, piece_len);
+        assert_eq!(download_req.pieces.len(), 1);
+        assert_eq!(download_req.last_piece_len(), data.len());
+        assert_eq!(download_req.piece_len(0), data.len());
+        let peer = MockPeer::new(data.clone(), piece_len).spawn().await;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        download_file(
+            PeerID::new(),
+            Peers::from(vec![peer]),
+            download_req,
+            path.clone(),
+            DownloadConfig::default(),
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_file_from_mock_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 2 * BLOCK_SIZE + 7;
+        let data: Vec<u8> = (0..5 * piece_len + 333)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            peers.push(MockPeer::new(data.clone(), piece_len).spawn().await);
+        }
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let stats_file = dir.path().join("stats.json");
+        let download_req = download_request(&data, piece_len);
+        let info_hash = download_req.info_hash.clone();
+        let earlier = TransferTotals {
+            uploaded: 1,
+            downloaded: 100,
+        };
+        earlier.save(&stats_file, &info_hash)?;
+        download_file(
+            PeerID::new(),
+            Peers::from(peers),
+            download_req,
+            path.clone(),
+            DownloadConfig {
+                stats_file: Some(stats_file.clone()),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+        let totals = TransferTotals::load(&stats_file, &info_hash);
+        assert_eq!(totals.uploaded, 1);
+        assert_eq!(totals.downloaded, 100 + data.len() as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_into_missing_directory() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * piece_len + 5)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let peer = MockPeer::new(data.clone(), piece_len).spawn().await;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("not").join("there").join("out");
+        download_file(
+            PeerID::new(),
+            Peers::from(vec![peer]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig::default(),
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_to_writer() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE + 3;
+        let data: Vec<u8> = (0..4 * piece_len + 10)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let peers = vec![
+            MockPeer::new(data.clone(), piece_len).spawn().await,
+            MockPeer::new(data.clone(), piece_len).spawn().await,
+        ];
+
+        let mut out = Vec::new();
+        download_to_writer(
+            PeerID::new(),
+            Peers::from(peers),
+            download_request(&data, piece_len),
+            &mut out,
+            DownloadConfig::default(),
+        )
+        .await?;
+
+        assert_eq!(out, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_pieces_in_order() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..6 * piece_len + 10)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            peers.push(MockPeer::new(data.clone(), piece_len).spawn().await);
+        }
+
+        let mut stream = download_pieces(
+            PeerID::new(),
+            Peers::from(peers),
+            download_request(&data, piece_len),
+            &DownloadConfig::default(),
+        )?;
+        assert!(stream.stats().peers.is_empty());
+        assert_eq!(stream.pieces_total(), 7);
+
+        let mut received = Vec::new();
+        let mut indices = Vec::new();
+        while let Some(full_piece) = stream.next().await {
+            let full_piece = full_piece?;
+            indices.push(full_piece.index());
+            received.extend_from_slice(full_piece.data());
+        }
+        assert_eq!(indices, (0..7).collect::<Vec<_>>());
+        assert_eq!(received, data);
+
+        let stats = stream.stats();
+        let bytes: usize = stats.peers.iter().map(|p| p.bytes).sum();
+        assert_eq!(bytes, data.len());
+
+        Ok(())
+    }
+
+    /// Piece 1 of three arrives while piece 0 is still missing, with room for two pieces. The
+    /// sender keeps the channel open, as a running worker would.
+    fn stream_with_gap(
+        piece_order: PieceOrder,
+    ) -> (PieceStream, Arc<BlockScheduler>, Sender<FullPiece>) {
+        let data = vec![7; 10];
+        let pieces: Vec<Piece> = (0..3)
+            .map(|idx| Piece {
+                hash: Hash::hash(&data),
+                idx,
+                len: data.len(),
+            })
+            .collect();
+        let scheduler = Arc::new(BlockScheduler::new(pieces.clone(), 2));
+        let (result_tx, result_rx) = mpsc::channel(4);
+        for _ in 0..2 {
+            assert!(matches!(
+                scheduler.next_block(&[], None, 0.0),
+                NextBlock::Block(_)
+            ));
+        }
+        result_tx
+            .try_send(FullPiece {
+                data,
+                piece: pieces[1].clone(),
+            })
+            .unwrap();
+
+        let stream = PieceStream {
+            result_rx,
+            scheduler: Arc::clone(&scheduler),
+            scores: Arc::new(PeerScores::default()),
+            pool: None,
+            piece_order: PieceOrder::Ascending,
+            order: PieceQueue::new((0..3).collect()),
+            pending: BTreeMap::new(),
+            pieces_total: 3,
+            bytes_total: 30,
+            done: false,
+        }
+        .with_order(piece_order);
+        (stream, scheduler, result_tx)
+    }
+
+    #[test]
+    fn test_piece_queue() {
+        let mut queue = PieceQueue::new(vec![2, 5, 7]);
+        assert_eq!(queue.front(), Some(2));
+        queue.remove(5);
+        queue.remove(5);
+        queue.remove(4);
+        assert_eq!((queue.front(), queue.len()), (Some(2), 2));
+        queue.remove(2);
+        assert_eq!((queue.front(), queue.len()), (Some(7), 1));
+        queue.remove(7);
+        assert_eq!((queue.front(), queue.len()), (None, 0));
+    }
+
+    #[tokio::test]
+    async fn test_piece_order() -> Result<(), Box<dyn std::error::Error>> {
+        // Piece 1 waits for piece 0 and keeps its slot, so piece 2 isn't started.
+        let (mut stream, scheduler, _tx) = stream_with_gap(PieceOrder::Ascending);
+        let next = tokio::time::timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(next.is_err(), "piece 1 returned before piece 0");
+        assert!(matches!(
+            scheduler.next_block(&[], None, 0.0),
+            NextBlock::Wait
+        ));
+
+        let (mut stream, scheduler, _tx) = stream_with_gap(PieceOrder::Arrival);
+        let piece = stream.next().await.expect("a piece")?;
+        assert_eq!(piece.index(), 1);
+        match scheduler.next_block(&[], None, 0.0) {
+            NextBlock::Block(req) => assert_eq!(req.piece_idx, 2),
+            _ => panic!("expected piece 2 to be started"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_probe_peers_sorts_by_latency() -> Result<(), Box<dyn std::error::Error>> {
+        let dead: Peer = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            listener.local_addr()?.to_string().parse()?
+        };
+        let alive = MockPeer::new(vec![0; 10], 10).spawn().await;
+
+        let probes = probe_peers(
+            PeerID::new(),
+            &Peers::from(vec![dead.clone(), alive.clone()]),
+            &Hash::new([0; 20]),
+            Duration::from_secs(5),
+            2,
+        )
+        .await;
+        assert_eq!(probes.len(), 2);
+        assert_eq!(probes[0].0.to_string(), alive.to_string());
+        assert!(probes[0].1.is_some());
+        assert_eq!(probes[1].0.to_string(), dead.to_string());
+        assert_eq!(probes[1].1, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_peers_added_during_download() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..4 * piece_len).map(|_| rand::random::<u8>()).collect();
+        // Nothing listens on the port of a dropped listener.
+        let dead: Peer = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            listener.local_addr()?.to_string().parse()?
+        };
+        let (tx, feed) = PeerFeed::new(Peers::from(vec![dead]));
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let download = tokio::spawn(download_file(
+            PeerID::new(),
+            feed,
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                reconnect_attempts: 0,
+                ..Default::default()
+            },
+        ));
+
+        // The dead peer is dropped, the download waits for the next announce.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!download.is_finished());
+        let peer = MockPeer::new(data.clone(), piece_len).spawn().await;
+        tx.send(Peers::from(vec![peer]))?;
+
+        download.await??;
+        assert_eq!(std::fs::read(path)?, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_new_peers_fails_the_download() -> Result<(), Box<dyn std::error::Error>> {
+        let data = vec![0; BLOCK_SIZE];
+        let dead: Peer = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            listener.local_addr()?.to_string().parse()?
+        };
+        let (tx, feed) = PeerFeed::new(Peers::from(vec![dead.clone()]));
+        // Like a tracker knowing no other peers, the sender stays alive.
+        let announcer = tokio::spawn(async move {
+            tx.wanted().await;
+            let _ = tx.send(Peers::from(vec![dead]));
+            tx.closed().await;
+        });
+
+        let dir = tempfile::tempdir()?;
+        let res = tokio::time::timeout(
+            Duration::from_secs(10),
+            download_file(
+                PeerID::new(),
+                feed,
+                download_request(&data, BLOCK_SIZE),
+                dir.path().join("out"),
+                DownloadConfig {
+                    reconnect_attempts: 0,
+                    ..Default::default()
+                },
+            ),
+        )
         .await?;
-    debug!("Sent Interested to {}.", peer);
+        assert!(res.is_err());
+        announcer.await?;
 
-    // Read Unchoke
-    msg = reader.from_stream(&mut stream).await?;
-    match msg {
-        PeerMessage::Unchoke => {}
-        other => bail!("expected Unchoke PeerMessage, got {:?}", other),
+        Ok(())
     }
-    debug!("Read Unchoke from {}", peer);
 
-    Ok(stream)
-}
-
-async fn download_piece(piece: Piece, stream: &mut TcpStream) -> Result<FullPiece> {
-    // Download Piece by requesting blocks of data until all data is read.
-    let mut piece_data: Vec<u8> = Vec::with_capacity(piece.len);
-    let req_gen = RequestPayloadGen::new(piece.len, piece.idx);
-    let req_q = RequestQueue::new(req_gen);
-    let mut rx = req_q.receiver();
-    let mut reader = PeerMessageReader::new();
-    while let Some(Some(req)) = rx.recv().await {
-        debug!("Writing request for offset: {}.", req.begin);
-        let peer_msg = PeerMessage::Request(req);
-        let payload = peer_msg.to_bytes();
-        stream.write_all(&payload).await?;
-        debug!("Written Request.");
-
-        let msg = reader.from_stream(stream).await?;
-        debug!("Read Message from stream.");
-        let piece_msg = match msg {
-            PeerMessage::Piece(piece) => piece,
-            other => bail!("expected Piece PeerMessage, got {:?}", other),
+    #[tokio::test]
+    async fn test_peer_cache_saved_after_failure() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * piece_len).map(|_| rand::random::<u8>()).collect();
+        // Delivers piece 0 only, the other peer never gets past the handshake.
+        let stalling = MockPeer::new(data.clone(), piece_len)
+            .stall_piece(1)
+            .spawn()
+            .await;
+        let ourselves = MockPeer::new(data.clone(), piece_len)
+            .echo_peer_id()
+            .spawn()
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let cache = dir.path().join("peers.json");
+        let download_req = download_request(&data, piece_len);
+        let info_hash = download_req.info_hash.clone();
+        let res = tokio::time::timeout(
+            Duration::from_secs(10),
+            download_file(
+                PeerID::new(),
+                Peers::from(vec![stalling.clone(), ourselves]),
+                download_req,
+                dir.path().join("out"),
+                DownloadConfig {
+                    peer_cache: Some(cache.clone()),
+                    reconnect_attempts: 0,
+                    request_timeout: Duration::from_millis(100),
+                    max_request_timeouts: 2,
+                    ..Default::default()
+                },
+            ),
+        )
+        .await?;
+        assert!(res.is_err());
+
+        let cached = Peers::load_cached(&cache, &info_hash);
+        assert_eq!(cached.to_string(), format!("{}\n", stalling));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dropping_the_stream_stops_the_download() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let data = vec![0; BLOCK_SIZE];
+        let peer = MockPeer::new(data.clone(), BLOCK_SIZE)
+            .never_answer()
+            .spawn()
+            .await;
+        let (tx, feed) = PeerFeed::new(Peers::from(vec![peer]));
+
+        let stream = download_pieces(
+            PeerID::new(),
+            feed,
+            download_request(&data, BLOCK_SIZE),
+            &DownloadConfig::default(),
+        )?;
+        drop(stream);
+        // The feed is closed once the peer pool is gone.
+        tokio::time::timeout(Duration::from_secs(5), tx.closed()).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_from_web_seed_only() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE + 10;
+        let data: Vec<u8> = (0..3 * piece_len + 5)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let files = [(String::from("/file.bin"), data.clone())]
+            .into_iter()
+            .collect();
+        let base = MockWebSeed::new(files).spawn().await;
+
+        let mut download_req = download_request(&data, piece_len);
+        download_req.web_seeds = vec![base.join("file.bin")?];
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let stats = download_file(
+            PeerID::new(),
+            Peers::from(Vec::new()),
+            download_req,
+            path.clone(),
+            DownloadConfig::default(),
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+        assert_eq!(stats.peers.len(), 1);
+        assert_eq!(stats.peers[0].bytes, data.len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_self_connection_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let ourselves = MockPeer::new(data.clone(), piece_len)
+            .echo_peer_id()
+            .spawn()
+            .await;
+        let other = MockPeer::new(data.clone(), piece_len).spawn().await;
+
+        let client_id = PeerID::new();
+        let Err(err) = setup_peer(&client_id, ourselves.clone(), &Hash::new([7; 20]), 2).await
+        else {
+            panic!("expected the self-connection to be rejected");
         };
-        debug!("Received Piece data.");
-        piece_data.append(&mut piece_msg.block.to_vec());
+        assert!(err.to_string().contains("ourselves"), "{:#}", err);
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let stats = download_file(
+            client_id,
+            Peers::from(vec![ourselves, other.clone()]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig::default(),
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+        let peers: Vec<_> = stats.peers.iter().map(|p| p.peer.clone()).collect();
+        assert_eq!(peers, vec![other.to_string()]);
+
+        Ok(())
     }
 
-    debug!("Closing receiver channel.");
-    rx.close();
+    #[tokio::test]
+    async fn test_peer_stuck_in_setup_does_not_block_completion(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..4 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let peer = MockPeer::new(data.clone(), piece_len).spawn().await;
+        // Accepts connections, but never answers the handshake.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let stuck: Peer = listener.local_addr()?.to_string().parse()?;
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                open.push(stream);
+            }
+        });
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let download = download_file(
+            PeerID::new(),
+            Peers::from(vec![stuck, peer]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig::default(),
+        );
+        tokio::time::timeout(Duration::from_secs(10), download).await??;
+        assert_eq!(std::fs::read(path)?, data);
 
-    // Checksums with sha1.
-    let downloaded_piece_hash = Hash::hash(&piece_data);
-    if downloaded_piece_hash != piece.hash {
-        bail!(
-            "hash not matching of downloaded piece have: {} want: {}",
-            downloaded_piece_hash.to_hex(),
-            piece.hash.to_hex()
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_worker_reconnects_after_dropped_connection(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 1024;
+        let data: Vec<u8> = (0..8 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let peer = MockPeer::new(data.clone(), piece_len)
+            .drop_first_connection_after(2)
+            .spawn()
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        download_file(
+            PeerID::new(),
+            Peers::from(vec![peer]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                reconnect_backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
         )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+
+        Ok(())
     }
 
-    debug!("Download of piece with idx {} was successful", piece.idx);
+    #[tokio::test]
+    async fn test_download_piece_tries_next_peer() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE + 5;
+        let data: Vec<u8> = (0..2 * piece_len).map(|_| rand::random::<u8>()).collect();
+        // Nothing listens on a just released port.
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+        let peers = vec![
+            dead.to_string().parse()?,
+            MockPeer::new(data.clone(), piece_len).spawn().await,
+        ];
+
+        let piece = perform_download_piece(
+            PeerID::new(),
+            &Peers::from(peers),
+            download_request(&data, piece_len),
+            1,
+            true,
+        )
+        .await?;
+        assert_eq!(piece, data[piece_len..]);
+
+        let err = perform_download_piece(
+            PeerID::new(),
+            &Peers::from(vec![dead.to_string().parse()?]),
+            download_request(&data, piece_len),
+            1,
+            true,
+        )
+        .await;
+        assert!(err.is_err());
 
-    Ok(FullPiece {
-        data: piece_data,
-        piece,
-    })
-}
+        Ok(())
+    }
 
-pub async fn perform_handshake(
-    client_id: PeerID,
-    peer: &Peer,
-    info_hash: &Hash,
-) -> Result<Handshake> {
-    let mut stream = TcpStream::connect(peer.to_string()).await?;
-    handshake(&client_id, info_hash, &mut stream).await
-}
+    #[tokio::test]
+    async fn test_silent_peer_times_out() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..4 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let silent = MockPeer::new(data.clone(), piece_len)
+            .never_answer()
+            .spawn()
+            .await;
+        let other = MockPeer::new(data.clone(), piece_len).spawn().await;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let stats = download_file(
+            PeerID::new(),
+            Peers::from(vec![silent, other.clone()]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                request_timeout: Duration::from_millis(200),
+                reconnect_backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
+        )
+        .await?;
 
-async fn handshake(
-    client_id: &PeerID,
-    info_hash: &Hash,
-    stream: &mut TcpStream,
-) -> Result<Handshake> {
-    let handshake = Handshake::new(info_hash, client_id);
-    let bytes = handshake.to_bytes();
+        assert_eq!(std::fs::read(path)?, data);
+        let peers: Vec<_> = stats.peers.iter().map(|p| p.peer.clone()).collect();
+        assert_eq!(peers, vec![other.to_string()]);
 
-    stream.write_all(&bytes).await?;
+        Ok(())
+    }
 
-    let mut buf = [0; HANDSHAKE_BYTE_SIZE];
-    let mut total_read = 0;
-    while total_read < HANDSHAKE_BYTE_SIZE {
-        let bytes_read = stream.read(&mut buf[total_read..]).await?;
-        if bytes_read == 0 {
-            bail!("Connection closed before handshake was fully read")
+    #[tokio::test]
+    async fn test_pieces_are_requested_from_peers_having_them(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let first = MockPeer::new(data.clone(), piece_len).without_piece(0);
+        let second = MockPeer::new(data.clone(), piece_len).without_piece(1);
+        let connections = [first.connections(), second.connections()];
+        let peers = vec![first.spawn().await, second.spawn().await];
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let stats = download_file(
+            PeerID::new(),
+            Peers::from(peers.clone()),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig::default(),
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+        // Neither peer was asked for its missing piece, which would have closed the connection.
+        for (peer, connections) in peers.iter().zip(connections) {
+            assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+            let peer_stats = stats.peers.iter().find(|p| p.peer == peer.to_string());
+            assert_eq!(peer_stats.map(|p| p.bytes), Some(piece_len));
         }
-        total_read += bytes_read;
+
+        Ok(())
     }
 
-    Handshake::from_bytes(buf)
-}
+    #[tokio::test]
+    async fn test_slow_piece_goes_to_another_peer() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..4 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let slow = MockPeer::new(data.clone(), piece_len).stall_piece(0);
+        let (connections, cancels) = (slow.connections(), slow.cancels());
+        let slow = slow.spawn().await;
+        let other = MockPeer::new(data.clone(), piece_len).spawn().await;
+
+        // The other peer only shows up once the slow one timed out on piece 0.
+        let (peer_tx, feed) = PeerFeed::new(Peers::from(vec![slow.clone()]));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            let _ = peer_tx.send(Peers::from(vec![other]));
+            // Keeps the feed open until the download is done.
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let stats = download_file(
+            PeerID::new(),
+            feed,
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                request_timeout: Duration::from_millis(100),
+                max_request_timeouts: 20,
+                ..Default::default()
+            },
+        )
+        .await?;
 
-    #[test]
-    fn test_request_payload_gen_next() -> Result<(), Box<dyn std::error::Error>> {
-        let piece_len = 32768;
-        let mut gen = RequestPayloadGen::new(piece_len, 0);
-        let counts = 2; // BLOCK_SIZE * 2 == piece_len
-        let mut cnt = 0;
-        for _n in 0..counts {
-            let is_some = gen.next().is_some();
-            cnt += 1;
-            assert_eq!(is_some, true)
+        assert_eq!(std::fs::read(path)?, data);
+        assert!(cancels.load(std::sync::atomic::Ordering::SeqCst) > 0);
+        // Served the other pieces on its first connection.
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let slow_stats = stats.peers.iter().find(|p| p.peer == slow.to_string());
+        assert_eq!(slow_stats.map(|p| p.bytes), Some(3 * piece_len));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget_exhausted() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..4 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let peer = MockPeer::new(data.clone(), piece_len)
+            .stall_piece(2)
+            .spawn()
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let res = download_file(
+            PeerID::new(),
+            Peers::from(vec![peer]),
+            download_request(&data, piece_len),
+            dir.path().join("out"),
+            DownloadConfig {
+                request_timeout: Duration::from_millis(50),
+                max_request_timeouts: 100,
+                max_retries: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let Err(err) = res else {
+            panic!("expected the retries to run out");
+        };
+        let exhausted = err
+            .downcast_ref::<RetriesExhausted>()
+            .unwrap_or_else(|| panic!("unexpected error {:#}", err));
+        assert_eq!(exhausted.pieces, vec![(2, 1)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_metrics() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..4 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let peer = MockPeer::new(data.clone(), piece_len).spawn().await;
+
+        let dir = tempfile::tempdir()?;
+        let stats = download_file(
+            PeerID::new(),
+            Peers::from(vec![peer]),
+            download_request(&data, piece_len),
+            dir.path().join("out"),
+            DownloadConfig {
+                connection_metrics: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // A request and a piece message per block.
+        let metrics = stats.peers[0].connection.expect("metrics are collected");
+        assert_eq!(metrics.messages_read, 4);
+        assert_eq!(metrics.messages_written, 4);
+        assert_eq!(metrics.bytes_read, 4 * (13 + piece_len as u64));
+        assert_eq!(metrics.bytes_written, 4 * REQUEST_BYTES_COUNT as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fast_peer_downloads_most_pieces() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..16 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let fast = MockPeer::new(data.clone(), piece_len).spawn().await;
+        let slow = MockPeer::new(data.clone(), piece_len)
+            .delay_answers(Duration::from_millis(50))
+            .spawn()
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let stats = download_file(
+            PeerID::new(),
+            Peers::from(vec![slow.clone(), fast.clone()]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                pipeline_depth: 2,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+        let bytes = |peer: &Peer| {
+            stats
+                .peers
+                .iter()
+                .find(|p| p.peer == peer.to_string())
+                .map_or(0, |p| p.bytes)
+        };
+        assert!(bytes(&fast) > bytes(&slow), "{}", stats);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_download() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..4 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let silent = MockPeer::new(data.clone(), piece_len)
+            .never_answer()
+            .spawn()
+            .await;
+
+        let cancel = CancelToken::new();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                cancel.cancel();
+            }
+        });
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let res = download_file(
+            PeerID::new(),
+            Peers::from(vec![silent]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                cancel: Some(cancel),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let Err(err) = res else {
+            panic!("expected the download to be cancelled");
+        };
+        assert_eq!(
+            err.downcast_ref::<Cancelled>(),
+            Some(&Cancelled {
+                pieces_done: 0,
+                pieces_total: 4
+            })
+        );
+        assert!(path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_piece_rejects_unrequested_blocks(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|_| rand::random::<u8>()).collect();
+        let peer = MockPeer::new(data.clone(), data.len())
+            .send_blocks_twice()
+            .spawn()
+            .await;
+        let info_hash = Hash::new([7; 20]);
+        let (mut stream, have) = setup_peer(&PeerID::new(), peer, &info_hash, 1).await?;
+
+        let piece = Piece {
+            hash: Hash::hash(&data),
+            idx: 0,
+            len: data.len(),
+        };
+        let Err(err) = download_piece(piece, &mut stream, &have, true).await else {
+            panic!("expected an error for the unrequested block");
+        };
+        assert!(err
+            .to_string()
+            .contains("peer sent more data than requested for piece 0"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_depth_is_respected() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 4 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..3 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let mock = MockPeer::new(data.clone(), piece_len).answer_in_batches();
+        let max_pipelined = mock.max_pipelined();
+        let peer = mock.spawn().await;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        download_file(
+            PeerID::new(),
+            Peers::from(vec![peer]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                pipeline_depth: 3,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+        assert_eq!(max_pipelined.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_several_pieces_in_flight_per_peer() -> Result<(), Box<dyn std::error::Error>> {
+        // Single block pieces, so every pipelined request belongs to another piece.
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..6 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let mock = MockPeer::new(data.clone(), piece_len).answer_in_batches();
+        let max_pipelined = mock.max_pipelined();
+        let peer = mock.spawn().await;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        download_file(
+            PeerID::new(),
+            Peers::from(vec![peer]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                pipeline_depth: 3,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+        assert_eq!(max_pipelined.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_active_pieces() -> Result<(), Box<dyn std::error::Error>> {
+        // Single block pieces, so only as many requests as active pieces can be pipelined.
+        let piece_len = BLOCK_SIZE;
+        let data: Vec<u8> = (0..6 * piece_len).map(|_| rand::random::<u8>()).collect();
+        let mock = MockPeer::new(data.clone(), piece_len).answer_in_batches();
+        let max_pipelined = mock.max_pipelined();
+        let peer = mock.spawn().await;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        download_file(
+            PeerID::new(),
+            Peers::from(vec![peer]),
+            download_request(&data, piece_len),
+            path.clone(),
+            DownloadConfig {
+                pipeline_depth: 3,
+                max_active_pieces: Some(2),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        assert_eq!(std::fs::read(path)?, data);
+        assert_eq!(max_pipelined.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_zero_outstanding_pieces_is_rejected() {
+        let data = vec![0; 10];
+        let res = download_pieces(
+            PeerID::new(),
+            Peers::from(vec![]),
+            download_request(&data, 10),
+            &DownloadConfig {
+                max_outstanding_pieces: 0,
+                ..Default::default()
+            },
+        );
+        let Err(err) = res else {
+            panic!("expected 0 outstanding pieces to be rejected");
+        };
+        assert!(
+            err.to_string().contains("max outstanding pieces"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zero_result_channel_capacity_is_rejected() {
+        let data = vec![0; 10];
+        let res = download_pieces(
+            PeerID::new(),
+            Peers::from(vec![]),
+            download_request(&data, 10),
+            &DownloadConfig {
+                result_channel_capacity: Some(0),
+                ..Default::default()
+            },
+        );
+        let Err(err) = res else {
+            panic!("expected a result channel capacity of 0 to be rejected");
+        };
+        assert!(
+            err.to_string().contains("result channel capacity"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let reachable = MockPeer::new(vec![0; 10], 10).spawn().await;
+        let dead: Peer = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            listener.local_addr()?.to_string().parse()?
+        };
+        // Accepts connections but never answers the handshake.
+        let silent_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let silent: Peer = silent_listener.local_addr()?.to_string().parse()?;
+
+        let peers = Peers::from(vec![reachable.clone(), dead.clone(), silent.clone()]);
+        let mut checks = check_peers(
+            PeerID::new(),
+            &peers,
+            &Hash::new([7; 20]),
+            Duration::from_millis(200),
+            2,
+        );
+        let mut results = BTreeMap::new();
+        while let Some((peer, check)) = checks.recv().await {
+            results.insert(peer.to_string(), check);
         }
 
-        assert_eq!(gen.next().is_some(), false);
-        assert_eq!(cnt, 2);
+        assert_eq!(results.len(), 3);
+        assert!(matches!(
+            results[&reachable.to_string()],
+            PeerCheck::Reachable(_)
+        ));
+        assert!(matches!(results[&dead.to_string()], PeerCheck::Failed(_)));
+        assert!(matches!(results[&silent.to_string()], PeerCheck::TimedOut));
+        drop(silent_listener);
+
         Ok(())
     }
 
-    #[test]
-    fn test_request_payload_gen_next_smaller_piece_len() -> Result<(), Box<dyn std::error::Error>> {
-        let piece_len = 6241;
-        let mut gen = RequestPayloadGen::new(piece_len, 0);
-        let req = gen.next();
-        assert_eq!(req.is_some(), true);
+    #[tokio::test]
+    async fn test_check_peers_concurrency() -> Result<(), Box<dyn std::error::Error>> {
+        // Peers holding each connection until another one is open, then closing it. A check
+        // only ends once its connection is closed, so the count is exact.
+        let open = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let peak = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let pair = Arc::new(tokio::sync::Barrier::new(2));
+        let mut closing = Vec::new();
+        for _ in 0..6 {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            closing.push(listener.local_addr()?.to_string().parse()?);
+            let (open, peak, pair) = (Arc::clone(&open), Arc::clone(&peak), Arc::clone(&pair));
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (open, peak, pair) =
+                        (Arc::clone(&open), Arc::clone(&peak), Arc::clone(&pair));
+                    tokio::spawn(async move {
+                        let now = open.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, std::sync::atomic::Ordering::SeqCst);
+                        pair.wait().await;
+                        open.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                        drop(stream);
+                    });
+                }
+            });
+        }
+
+        let mut checks = check_peers(
+            PeerID::new(),
+            &Peers::from(closing),
+            &Hash::new([7; 20]),
+            Duration::from_secs(30),
+            2,
+        );
+        let mut count = 0;
+        while let Some((_, check)) = checks.recv().await {
+            assert!(matches!(check, PeerCheck::Failed(_)), "{}", check);
+            count += 1;
+        }
+        assert_eq!(count, 6);
+        assert_eq!(peak.load(std::sync::atomic::Ordering::SeqCst), 2);
 
         Ok(())
     }
 
-    #[test]
-    fn test_piece_payload_from_bytes() -> Result<(), Box<dyn std::error::Error>> {
-        let mut piece_bytes: Vec<u8> = Vec::new();
-        piece_bytes.extend_from_slice(&0u32.to_be_bytes());
-        piece_bytes.extend_from_slice(&0u32.to_be_bytes());
-        let random_data: Vec<u8> = (0..16384).map(|_| rand::random::<u8>()).collect();
-        piece_bytes.extend_from_slice(&random_data);
-        PiecePayload::from_bytes(&piece_bytes)?;
+    #[tokio::test]
+    async fn test_dropping_check_results_stops_the_checks() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let silent: Peer = listener.local_addr()?.to_string().parse()?;
+        let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let closed_tx = closed_tx.clone();
+                tokio::spawn(async move {
+                    // Never answers, reads until the check gives up on the connection.
+                    let _ = stream.read_to_end(&mut Vec::new()).await;
+                    let _ = closed_tx.send(());
+                });
+            }
+        });
 
-        let mut smaller_piece_bytes: Vec<u8> = Vec::new();
-        smaller_piece_bytes.extend_from_slice(&0u32.to_be_bytes());
-        smaller_piece_bytes.extend_from_slice(&0u32.to_be_bytes());
-        let random_data: Vec<u8> = (0..1337).map(|_| rand::random::<u8>()).collect();
-        smaller_piece_bytes.extend_from_slice(&random_data);
-        PiecePayload::from_bytes(&smaller_piece_bytes)?;
+        let checks = check_peers(
+            PeerID::new(),
+            &Peers::from(vec![silent]),
+            &Hash::new([7; 20]),
+            Duration::from_secs(30),
+            1,
+        );
+        drop(checks);
+        // The check is aborted long before its timeout.
+        tokio::time::timeout(Duration::from_secs(10), closed_rx.recv()).await?;
 
         Ok(())
     }
+
+    #[test]
+    fn test_is_recoverable() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(is_recoverable(
+            &anyhow::Error::new(reset).context("reading")
+        ));
+        assert!(!is_recoverable(&anyhow!("peer answered with info hash")));
+    }
+
+    #[test]
+    fn test_result_channel_capacity_scales_with_peers() {
+        assert_eq!(result_channel_capacity(1), MIN_RESULT_CHANNEL_CAPACITY);
+        assert_eq!(result_channel_capacity(5), 10);
+        assert_eq!(result_channel_capacity(20), 40);
+        assert_eq!(result_channel_capacity(1000), MAX_RESULT_CHANNEL_CAPACITY);
+    }
 }
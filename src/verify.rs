@@ -0,0 +1,464 @@
+//! Checks downloaded data against the piece hashes of its torrent.
+
+use core::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinSet;
+
+use crate::bitfield::Bitfield;
+use crate::torrent::{DownloadRequest, Hash, PieceHash, Sha1Hasher};
+
+/// Bytes read at once by each verifying thread.
+const STREAM_BUFFER_SIZE: usize = 1 << 20;
+
+/// Hashes every piece of the download at `path` (the file of a single-file torrent, the
+/// directory containing the torrent's directory otherwise) with up to `threads` pieces hashed at
+/// once. Returns the pieces that match, missing data counts as a mismatch. The number of pieces
+/// checked so far is sent to `progress_tx` after every piece.
+///
+/// The pieces are split into `threads` runs of neighbouring pieces, each read front to back in
+/// fixed size buffers, so neither seeks per piece nor whole pieces in memory are needed. A single
+/// thread reads the files exactly once, best for huge torrents on spinning disks.
+pub async fn verify(
+    download_req: &DownloadRequest,
+    path: &Path,
+    threads: usize,
+    progress_tx: Option<UnboundedSender<usize>>,
+) -> Result<Bitfield> {
+    let files: Arc<Vec<(PathBuf, usize)>> = Arc::new(match &download_req.files {
+        Some(files) => files
+            .iter()
+            .map(|f| (path.join(&f.path), f.length))
+            .collect(),
+        None => vec![(path.to_path_buf(), download_req.length)],
+    });
+
+    let pieces_cnt = download_req.pieces.len();
+    let run_len = pieces_cnt.div_ceil(threads.max(1)).max(1);
+    let checked = Arc::new(AtomicUsize::new(0));
+    let mut tasks = JoinSet::new();
+    for first in (0..pieces_cnt).step_by(run_len) {
+        let pieces = first..(first + run_len).min(pieces_cnt);
+        let progress = progress_tx.clone().map(|tx| (tx, Arc::clone(&checked)));
+        let mut stream = PieceStream::new(download_req, pieces, progress);
+        let files = Arc::clone(&files);
+        tasks.spawn_blocking(move || {
+            stream.read_files(&files);
+            stream.matched
+        });
+    }
+
+    let mut matched = Bitfield::new(pieces_cnt);
+    while let Some(res) = tasks.join_next().await {
+        for idx in res?.ones() {
+            matched.set(idx);
+        }
+    }
+
+    Ok(matched)
+}
+
+/// Checks that the downloaded `data` of a piece hashes to `expected`.
+pub fn verify_piece(data: &[u8], expected: &PieceHash) -> Result<()> {
+    let have = Hash::digest(expected.algorithm(), data)?;
+    if have != *expected {
+        anyhow::bail!(
+            "hash not matching of downloaded piece have: {} want: {}",
+            have.to_hex(),
+            expected.to_hex()
+        );
+    }
+
+    Ok(())
+}
+
+/// How much of a download is present and verified, see `completeness`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletenessReport {
+    pub verified: Bitfield,
+    /// Bytes of the verified pieces.
+    pub bytes_present: u64,
+    /// Share of the torrent's bytes that are present, 0 to 100.
+    pub percent: f64,
+}
+
+impl CompletenessReport {
+    fn new(download_req: &DownloadRequest, verified: Bitfield) -> Self {
+        let bytes_present: u64 = verified
+            .ones()
+            .map(|idx| download_req.piece_len(idx) as u64)
+            .sum();
+        let percent = if download_req.length == 0 {
+            100.0
+        } else {
+            bytes_present as f64 * 100.0 / download_req.length as f64
+        };
+
+        Self {
+            verified,
+            bytes_present,
+            percent,
+        }
+    }
+}
+
+impl fmt::Display for CompletenessReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}% complete ({}/{} pieces, {} bytes)",
+            self.percent,
+            self.verified.count(),
+            self.verified.len(),
+            self.bytes_present
+        )
+    }
+}
+
+/// Reports which pieces of the download at `path` are present and verified, e.g. to show how far
+/// an interrupted download got without downloading anything. Like `verify`, a file shorter than
+/// expected or a missing file only lacks the pieces it would contain.
+pub async fn completeness(
+    download_req: &DownloadRequest,
+    path: &Path,
+) -> Result<CompletenessReport> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let verified = verify(download_req, path, threads, None).await?;
+
+    Ok(CompletenessReport::new(download_req, verified))
+}
+
+/// Hashes a run of neighbouring pieces as the torrent's data is read front to back, one piece
+/// after the other.
+struct PieceStream {
+    pieces: Range<usize>,
+    hashes: Vec<PieceHash>,
+    piece_length: usize,
+    length: usize,
+    // Where to send the number of pieces checked by all streams of a `verify`.
+    progress: Option<(UnboundedSender<usize>, Arc<AtomicUsize>)>,
+    // The piece currently hashed, how much of it was hashed and whether some of it is missing.
+    idx: usize,
+    filled: usize,
+    damaged: bool,
+    hasher: Sha1Hasher,
+    matched: Bitfield,
+}
+
+impl PieceStream {
+    fn new(
+        download_req: &DownloadRequest,
+        pieces: Range<usize>,
+        progress: Option<(UnboundedSender<usize>, Arc<AtomicUsize>)>,
+    ) -> Self {
+        Self {
+            hashes: download_req.pieces[pieces.clone()].to_vec(),
+            idx: pieces.start,
+            pieces,
+            piece_length: download_req.piece_length,
+            length: download_req.length,
+            progress,
+            filled: 0,
+            damaged: false,
+            hasher: Sha1Hasher::default(),
+            matched: Bitfield::new(download_req.pieces.len()),
+        }
+    }
+
+    /// Hashes the stream's pieces from `files`, laid out back to back with their lengths.
+    fn read_files(&mut self, files: &[(PathBuf, usize)]) {
+        let start = self.pieces.start * self.piece_length;
+        let end = (self.pieces.end * self.piece_length).min(self.length);
+        let mut file_start = 0;
+        for (path, length) in files {
+            let file_end = file_start + length;
+            let (from, to) = (start.max(file_start), end.min(file_end));
+            if from < to {
+                self.read_file(path, from - file_start, to - from);
+            }
+            file_start = file_end;
+        }
+    }
+
+    /// Hashes `length` bytes of the file at `path`, starting at `offset`. Missing bytes, the
+    /// whole range if the file can't be opened, damage the pieces they belong to.
+    fn read_file(&mut self, path: &Path, offset: usize, length: usize) {
+        let mut remaining = length;
+        let opened = File::open(path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset as u64))?;
+            Ok(file)
+        });
+        match opened {
+            Ok(mut file) => {
+                let mut buf = vec![0; STREAM_BUFFER_SIZE.min(length)];
+                while remaining > 0 {
+                    let want = remaining.min(buf.len());
+                    let n = match file.read(&mut buf[..want]) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            log::debug!("Could not read {}: {}", path.display(), e);
+                            break;
+                        }
+                    };
+                    self.advance(Some(&buf[..n]), n);
+                    remaining -= n;
+                }
+            }
+            Err(e) => log::debug!("Could not open {}: {}", path.display(), e),
+        }
+        self.advance(None, remaining);
+    }
+
+    /// Moves `count` bytes further, hashing `data` or marking the bytes as missing if there is
+    /// none.
+    fn advance(&mut self, data: Option<&[u8]>, count: usize) {
+        let mut done = 0;
+        while done < count && self.idx < self.pieces.end {
+            let piece_len = self
+                .piece_length
+                .min(self.length.saturating_sub(self.idx * self.piece_length));
+            let take = (count - done).min(piece_len - self.filled);
+            match data {
+                Some(data) => self.hasher.update(&data[done..done + take]),
+                None => self.damaged = true,
+            }
+            done += take;
+            self.filled += take;
+
+            if self.filled == piece_len {
+                let hash = self.hasher.finalize_reset();
+                if !self.damaged && hash == self.hashes[self.idx - self.pieces.start] {
+                    self.matched.set(self.idx);
+                }
+                self.idx += 1;
+                self.filled = 0;
+                self.damaged = false;
+                if let Some((tx, checked)) = &self.progress {
+                    let _ = tx.send(checked.fetch_add(1, Ordering::SeqCst) + 1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{DownloadFile, Hash};
+
+    #[tokio::test]
+    async fn test_parallel_and_sequential_verify_agree() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 7;
+        let data: Vec<u8> = (0..100).map(|_| rand::random::<u8>()).collect();
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir_all(dir.path().join("name"))?;
+        let mut corrupt = data.clone();
+        corrupt[8] ^= 1;
+        corrupt[99] ^= 1;
+        std::fs::write(dir.path().join("name").join("a"), &corrupt[..30])?;
+        std::fs::write(dir.path().join("name").join("b"), &corrupt[30..])?;
+
+        let download_req = DownloadRequest {
+            length: data.len(),
+            piece_length: piece_len,
+            pieces: data.chunks(piece_len).map(Hash::hash).collect(),
+            info_hash: Hash::new([7; 20]),
+            files: Some(vec![
+                DownloadFile {
+                    path: PathBuf::from("name").join("a"),
+                    length: 30,
+                },
+                DownloadFile {
+                    path: PathBuf::from("name").join("b"),
+                    length: 70,
+                },
+            ]),
+            web_seeds: Vec::new(),
+        };
+
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+        let sequential = verify(&download_req, dir.path(), 1, Some(progress_tx.clone())).await?;
+        assert_eq!(sequential.zeros().collect::<Vec<_>>(), vec![1, 14]);
+        assert_eq!(sequential.count(), 13);
+        let mut checked = Vec::new();
+        while let Ok(n) = progress_rx.try_recv() {
+            checked.push(n);
+        }
+        assert_eq!(checked, (1..=15).collect::<Vec<_>>());
+
+        // Runs of 2 pieces, piece 4 starts in a and ends in b.
+        let parallel = verify(&download_req, dir.path(), 8, Some(progress_tx)).await?;
+        assert_eq!(parallel, sequential);
+        let mut checked = Vec::new();
+        while let Ok(n) = progress_rx.try_recv() {
+            checked.push(n);
+        }
+        checked.sort();
+        assert_eq!(checked, (1..=15).collect::<Vec<_>>());
+
+        // Cut short in the middle of piece 6.
+        std::fs::write(dir.path().join("name").join("b"), &data[30..45])?;
+        for threads in [1, 3] {
+            let short = verify(&download_req, dir.path(), threads, None).await?;
+            assert_eq!(
+                short.zeros().collect::<Vec<_>>(),
+                [vec![1], (6..15).collect()].concat()
+            );
+        }
+
+        std::fs::remove_file(dir.path().join("name").join("b"))?;
+        for threads in [1, 8] {
+            let missing = verify(&download_req, dir.path(), threads, None).await?;
+            assert_eq!(
+                missing.zeros().collect::<Vec<_>>(),
+                [vec![1], (4..15).collect()].concat()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_piece() {
+        let data = b"piece data";
+        let hash = Hash::hash(data);
+
+        assert!(verify_piece(data, &hash).is_ok());
+        let Err(err) = verify_piece(b"other data", &hash) else {
+            panic!("expected a hash mismatch");
+        };
+        assert!(err.to_string().contains(&hash.to_hex()));
+        let sha256 = Hash::Sha256([0; 32]);
+        assert!(verify_piece(data, &sha256).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_extra_piece_hashes_do_not_panic() -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<u8> = (0..25).map(|_| rand::random::<u8>()).collect();
+        let mut pieces: Vec<_> = data.chunks(10).map(Hash::hash).collect();
+        pieces.push(Hash::new([0; 20]));
+        let download_req = DownloadRequest {
+            length: data.len(),
+            piece_length: 10,
+            pieces,
+            info_hash: Hash::new([7; 20]),
+            files: None,
+            web_seeds: Vec::new(),
+        };
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        std::fs::write(&path, &data)?;
+
+        for threads in [1, 4] {
+            let matched = verify(&download_req, &path, threads, None).await?;
+            // The hash past the end is of no data.
+            assert_eq!(
+                matched.ones().collect::<Vec<_>>(),
+                vec![0, 1, 2],
+                "{threads}"
+            );
+        }
+        completeness(&download_req, &path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_completeness_of_a_short_file() -> Result<(), Box<dyn std::error::Error>> {
+        let piece_len = 10;
+        let data: Vec<u8> = (0..95).map(|_| rand::random::<u8>()).collect();
+        let download_req = DownloadRequest {
+            length: data.len(),
+            piece_length: piece_len,
+            pieces: data.chunks(piece_len).map(Hash::hash).collect(),
+            info_hash: Hash::new([7; 20]),
+            files: None,
+            web_seeds: Vec::new(),
+        };
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+
+        let report = completeness(&download_req, &path).await?;
+        assert_eq!((report.verified.count(), report.bytes_present), (0, 0));
+
+        // Interrupted in the middle of piece 7, with piece 2 damaged.
+        let mut partial = data[..75].to_vec();
+        partial[25] ^= 1;
+        std::fs::write(&path, &partial)?;
+        let report = completeness(&download_req, &path).await?;
+        assert_eq!(
+            report.verified.ones().collect::<Vec<_>>(),
+            vec![0, 1, 3, 4, 5, 6]
+        );
+        assert_eq!(report.bytes_present, 60);
+        assert_eq!(report.to_string(), "63.2% complete (6/10 pieces, 60 bytes)");
+
+        // The short last piece counts with its own length.
+        std::fs::write(&path, &data)?;
+        let report = completeness(&download_req, &path).await?;
+        assert_eq!(report.bytes_present, 95);
+        assert_eq!(report.percent, 100.0);
+
+        Ok(())
+    }
+
+    /// Benchmark of `verify` over a multi-gigabyte file, `BENCH_VERIFY_MB` (default 2048) MiB.
+    /// Compare the SHA1 backends with
+    /// `cargo test --release [--features sha1-smol] bench_verify -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_verify() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Write;
+
+        let piece_len = 1 << 20;
+        let pieces_cnt: usize =
+            std::env::var("BENCH_VERIFY_MB").map_or(Ok(2048), |mb| mb.parse())?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out");
+        let mut file = std::io::BufWriter::new(File::create(&path)?);
+        let mut pieces = Vec::with_capacity(pieces_cnt);
+        let mut piece = vec![0; piece_len];
+        for idx in 0..pieces_cnt {
+            piece
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b = (i ^ idx) as u8);
+            pieces.push(Hash::hash(&piece));
+            file.write_all(&piece)?;
+        }
+        drop(file);
+        let download_req = DownloadRequest {
+            length: pieces_cnt * piece_len,
+            piece_length: piece_len,
+            pieces,
+            info_hash: Hash::new([7; 20]),
+            files: None,
+            web_seeds: Vec::new(),
+        };
+
+        for threads in [1, 4] {
+            let start = std::time::Instant::now();
+            let matched = verify(&download_req, &path, threads, None).await?;
+            let elapsed = start.elapsed();
+            assert_eq!(matched.count(), pieces_cnt);
+            println!(
+                "verified {} MiB with {} threads in {:.2?} ({:.0} MiB/s)",
+                pieces_cnt,
+                threads,
+                elapsed,
+                pieces_cnt as f64 / elapsed.as_secs_f64()
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+//! Downloading from HTTP web seeds (BEP 19).
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use reqwest::{header, StatusCode};
+use tokio::sync::mpsc::Sender;
+use url::Url;
+
+use crate::scheduler::{BlockRequest, BlockScheduler};
+use crate::stats::PeerScore;
+use crate::torrent::DownloadFile;
+use crate::tracker::FullPiece;
+
+/// A web seed serving the torrent's content, blocks are fetched with HTTP range requests.
+pub(crate) struct WebSeed {
+    client: reqwest::Client,
+    url: Url,
+    piece_len: usize,
+    /// None for single-file torrents, where `url` points at the file itself.
+    files: Option<Vec<DownloadFile>>,
+}
+
+impl WebSeed {
+    pub(crate) fn new(
+        url: Url,
+        piece_len: usize,
+        files: Option<Vec<DownloadFile>>,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(Self {
+            client,
+            url,
+            piece_len,
+            files,
+        })
+    }
+
+    pub(crate) fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Downloads blocks handed out by the scheduler until every piece is done, like a peer
+    /// worker. A failed block is handed back to the scheduler.
+    pub(crate) async fn run(
+        &self,
+        scheduler: &BlockScheduler,
+        result_tx: &Sender<FullPiece>,
+        score: &PeerScore,
+    ) -> Result<()> {
+        while let Some(req) = scheduler.wait_for_block(None, score.speed()).await {
+            let block = match self.fetch_block(&req).await {
+                Ok(block) => block,
+                Err(e) => {
+                    scheduler.release(&[req], score.peer());
+                    return Err(e);
+                }
+            };
+            score.record(block.len());
+            // Hashes are verified by the scheduler, like for blocks from peers.
+            if let Some(full_piece) = scheduler.complete_block(&req, &block, score.peer())? {
+                result_tx.send(full_piece).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_block(&self, req: &BlockRequest) -> Result<Vec<u8>> {
+        let offset = req.piece_idx * self.piece_len + req.begin;
+        let mut block = Vec::with_capacity(req.length);
+        for (url, start, end) in self.ranges(offset, req.length)? {
+            debug!("Fetching bytes {}-{} from web seed {}", start, end, url);
+            let resp = self
+                .client
+                .get(url.clone())
+                .header(header::RANGE, format!("bytes={}-{}", start, end))
+                .send()
+                .await
+                .with_context(|| format!("could not reach web seed {}", url))?;
+            if resp.status() != StatusCode::PARTIAL_CONTENT {
+                bail!(
+                    "web seed {} answered a range request with status {}",
+                    url,
+                    resp.status()
+                );
+            }
+            let body = resp.bytes().await?;
+            if body.len() != end - start + 1 {
+                bail!(
+                    "web seed {} sent {} bytes for range {}-{}",
+                    url,
+                    body.len(),
+                    start,
+                    end
+                );
+            }
+            block.extend_from_slice(&body);
+        }
+
+        Ok(block)
+    }
+
+    /// Splits the torrent wide byte range into inclusive ranges of the files it covers.
+    fn ranges(&self, offset: usize, length: usize) -> Result<Vec<(Url, usize, usize)>> {
+        let Some(files) = &self.files else {
+            return Ok(vec![(self.url.clone(), offset, offset + length - 1)]);
+        };
+
+        let end = offset + length;
+        let mut ranges = Vec::new();
+        let mut file_start = 0;
+        for file in files {
+            let file_end = file_start + file.length;
+            let start = offset.max(file_start);
+            let stop = end.min(file_end);
+            if start < stop {
+                ranges.push((
+                    self.file_url(file)?,
+                    start - file_start,
+                    stop - file_start - 1,
+                ));
+            }
+            file_start = file_end;
+        }
+
+        Ok(ranges)
+    }
+
+    fn file_url(&self, file: &DownloadFile) -> Result<Url> {
+        let mut url = self.url.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| anyhow::anyhow!("web seed {} can't have a path", self.url))?;
+            segments.pop_if_empty();
+            for component in file.path.iter() {
+                segments.push(&component.to_string_lossy());
+            }
+        }
+
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_ranges_span_files() -> Result<(), Box<dyn std::error::Error>> {
+        let files = vec![
+            DownloadFile {
+                path: PathBuf::from("name").join("a.txt"),
+                length: 6,
+            },
+            DownloadFile {
+                path: PathBuf::from("name").join("dir").join("b c.txt"),
+                length: 5,
+            },
+        ];
+        let seed = WebSeed::new(Url::parse("http://seed.example/files/")?, 4, Some(files))?;
+
+        let ranges = seed.ranges(4, 4)?;
+        let ranges: Vec<_> = ranges
+            .iter()
+            .map(|(url, start, end)| (url.as_str(), *start, *end))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                ("http://seed.example/files/name/a.txt", 4, 5),
+                ("http://seed.example/files/name/dir/b%20c.txt", 0, 1),
+            ]
+        );
+
+        Ok(())
+    }
+}